@@ -0,0 +1,13 @@
+use std::fs;
+
+/// Expose the version of qrlew resolved in `Cargo.lock` as `QRLEW_VERSION`
+fn main() {
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    let lock = fs::read_to_string("Cargo.lock").unwrap_or_default();
+    let version = lock.split("[[package]]").find_map(|package| {
+        let mut lines = package.lines().map(str::trim);
+        lines.find(|line| *line == r#"name = "qrlew""#)?;
+        lines.find_map(|line| line.strip_prefix("version = ")).map(|version| version.trim_matches('"').to_string())
+    }).unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=QRLEW_VERSION={version}");
+}
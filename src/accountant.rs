@@ -0,0 +1,79 @@
+use std::{collections::HashMap, sync::Mutex};
+
+/// Accumulates the (epsilon, delta) spent per key under basic composition, rejecting spendings beyond a total budget
+pub struct BudgetAccountant {
+    total_epsilon: f64,
+    total_delta: f64,
+    spent: Mutex<HashMap<String, (f64, f64)>>,
+}
+
+/// Tolerance on the total budget, for budgets split in shares not summing up exactly
+const TOLERANCE: f64 = 1e-9;
+
+impl BudgetAccountant {
+    pub fn new(total_epsilon: f64, total_delta: f64) -> Self {
+        BudgetAccountant {
+            total_epsilon,
+            total_delta,
+            spent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether a budget can be spent for every key without exceeding the total budget of any of them
+    pub fn can_spend<K: AsRef<str>>(&self, keys: &[K], epsilon: f64, delta: f64) -> bool {
+        self.fits(&self.spent.lock().unwrap(), keys, epsilon, delta)
+    }
+
+    fn fits<K: AsRef<str>>(&self, spent: &HashMap<String, (f64, f64)>, keys: &[K], epsilon: f64, delta: f64) -> bool {
+        keys.iter()
+            .map(|key| spent.get(key.as_ref()).copied().unwrap_or((0., 0.)))
+            .all(|(spent_epsilon, spent_delta)| spent_epsilon + epsilon <= self.total_epsilon * (1. + TOLERANCE) && spent_delta + delta <= self.total_delta * (1. + TOLERANCE))
+    }
+
+    /// Spend a budget for every key, return false and spend nothing when it would exceed the total budget of any of them
+    pub fn try_spend<K: AsRef<str>>(&self, keys: &[K], epsilon: f64, delta: f64) -> bool {
+        let mut spent = self.spent.lock().unwrap();
+        if !self.fits(&spent, keys, epsilon, delta) {
+            return false;
+        }
+        for key in keys {
+            let (spent_epsilon, spent_delta) = spent.entry(key.as_ref().to_string()).or_insert((0., 0.));
+            *spent_epsilon += epsilon;
+            *spent_delta += delta;
+        }
+        true
+    }
+
+    /// The (epsilon, delta) left to spend for a key
+    pub fn remaining(&self, key: &str) -> (f64, f64) {
+        let (spent_epsilon, spent_delta) = self.spent.lock().unwrap().get(key).copied().unwrap_or((0., 0.));
+        ((self.total_epsilon - spent_epsilon).max(0.), (self.total_delta - spent_delta).max(0.))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget_accountant() {
+        let accountant = BudgetAccountant::new(1., 1e-5);
+        assert!(accountant.try_spend(&["a"], 0.5, 0.5e-5));
+        assert!(accountant.try_spend(&["a"], 0.5, 0.5e-5));
+        println!("{:?}", accountant.remaining("a"));
+        assert_eq!(accountant.remaining("a"), (0., 0.));
+        // Nothing is spent when the budget would be exceeded
+        assert!(!accountant.try_spend(&["a"], 0.1, 0.));
+        assert!(accountant.try_spend(&["a"], 0., 0.));
+        // Other keys have their own budget
+        assert!(!accountant.try_spend(&["b"], 0.5, 2e-5));
+        assert_eq!(accountant.remaining("b"), (1., 1e-5));
+        // Spending for several keys spends nothing when one of them is exhausted
+        assert!(!accountant.can_spend(&["b", "a"], 0.5, 0.));
+        assert!(!accountant.try_spend(&["b", "a"], 0.5, 0.));
+        assert!(accountant.can_spend(&["b"], 0.5, 0.));
+        assert_eq!(accountant.remaining("b"), (1., 1e-5));
+        assert!(accountant.try_spend(&["b", "c"], 1., 1e-5));
+        assert_eq!(accountant.remaining("c"), (0., 0.));
+    }
+}
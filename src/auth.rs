@@ -1,76 +1,370 @@
 use super::{Error, Result};
+use std::{fmt, fs::File, str::FromStr};
 use tracing::info;
-use rand;
+use fs2::FileExt;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
 use base64::{Engine as _, engine::general_purpose};
 use rsa::{
-    RsaPrivateKey,
-    pkcs1v15::{SigningKey, VerifyingKey, Signature},
-    signature::{Keypair, RandomizedSigner, SignatureEncoding, Verifier},
-    sha2::Sha256,
-    pkcs8::{EncodePrivateKey, DecodePrivateKey, spki::der::pem::LineEnding},
+    RsaPrivateKey, RsaPublicKey,
+    pkcs1v15::{self, Signature},
+    signature::{Keypair, RandomizedSigner, Signer as _, SignatureEncoding, Verifier},
+    sha2::{Digest, Sha256},
+    traits::PublicKeyParts,
+    pkcs8::{EncodePrivateKey, DecodePrivateKey, DecodePublicKey, spki::{EncodePublicKey, der::pem::LineEnding}},
 };
 
-const SIZE: usize = 2048;
+/// The size of RSA keys, unless configured otherwise
+pub const DEFAULT_RSA_KEY_BITS: usize = 2048;
+/// The accepted sizes of RSA keys
+pub const RSA_KEY_BITS: [usize; 3] = [2048, 3072, 4096];
 
+/// The signature scheme of a key
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Algorithm {
+    /// RSA PKCS#1 v1.5 with SHA-256
+    #[default]
+    Rsa,
+    /// Ed25519, with much shorter signatures
+    Ed25519,
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Algorithm::Rsa => write!(f, "rsa"),
+            Algorithm::Ed25519 => write!(f, "ed25519"),
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "rsa" => Ok(Algorithm::Rsa),
+            "ed25519" => Ok(Algorithm::Ed25519),
+            _ => Err(Error::other(format!("unknown key algorithm `{s}`, expected `rsa` or `ed25519`"))),
+        }
+    }
+}
+
+/// A private key of one of the supported algorithms
+#[derive(Clone, Debug)]
+pub enum SigningKey {
+    Rsa(pkcs1v15::SigningKey<Sha256>),
+    Ed25519(ed25519_dalek::SigningKey),
+}
+
+impl SigningKey {
+    /// A fresh key, `rsa_bits` (one of `RSA_KEY_BITS`) is the size of RSA keys
+    pub fn random(algorithm: Algorithm, rsa_bits: usize) -> Result<Self> {
+        let mut rng = rand::thread_rng();
+        Ok(match algorithm {
+            Algorithm::Rsa if !RSA_KEY_BITS.contains(&rsa_bits) => return Err(Error::other(format!("unsupported RSA key size {rsa_bits}, expected one of {RSA_KEY_BITS:?}"))),
+            Algorithm::Rsa => SigningKey::Rsa(pkcs1v15::SigningKey::new(RsaPrivateKey::new(&mut rng, rsa_bits)?)),
+            Algorithm::Ed25519 => SigningKey::Ed25519(ed25519_dalek::SigningKey::generate(&mut rng)),
+        })
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        match self {
+            SigningKey::Rsa(_) => Algorithm::Rsa,
+            SigningKey::Ed25519(_) => Algorithm::Ed25519,
+        }
+    }
+
+    pub fn verifying_key(&self) -> VerifyingKey {
+        match self {
+            SigningKey::Rsa(signing_key) => VerifyingKey::Rsa(signing_key.verifying_key()),
+            SigningKey::Ed25519(signing_key) => VerifyingKey::Ed25519(signing_key.verifying_key()),
+        }
+    }
+
+    fn sign(&self, message: &[u8]) -> Vec<u8> {
+        match self {
+            SigningKey::Rsa(signing_key) => signing_key.sign_with_rng(&mut rand::thread_rng(), message).to_vec(),
+            SigningKey::Ed25519(signing_key) => signing_key.sign(message).to_vec(),
+        }
+    }
+
+    /// Read a PKCS#8 PEM file, of either algorithm
+    fn read_pem_file(path: &str) -> Result<Self> {
+        let pem = std::fs::read_to_string(path)?;
+        match RsaPrivateKey::from_pkcs8_pem(&pem) {
+            Ok(private_key) => Ok(SigningKey::Rsa(pkcs1v15::SigningKey::new(private_key))),
+            Err(_) => Ok(SigningKey::Ed25519(ed25519_dalek::SigningKey::from_pkcs8_pem(&pem)?)),
+        }
+    }
+
+    fn write_pem_file(&self, path: &str) -> Result<()> {
+        match self {
+            SigningKey::Rsa(signing_key) => signing_key.as_ref().write_pkcs8_pem_file(path, LineEnding::CRLF)?,
+            SigningKey::Ed25519(signing_key) => signing_key.write_pkcs8_pem_file(path, LineEnding::CRLF)?,
+        }
+        Ok(())
+    }
+}
+
+/// A public key of one of the supported algorithms
+#[derive(Clone, Debug)]
+pub enum VerifyingKey {
+    Rsa(pkcs1v15::VerifyingKey<Sha256>),
+    Ed25519(ed25519_dalek::VerifyingKey),
+}
+
+impl VerifyingKey {
+    fn verify(&self, message: &[u8], signature: &[u8]) -> Result<()> {
+        match self {
+            VerifyingKey::Rsa(verifying_key) => Ok(verifying_key.verify(message, &Signature::try_from(signature)?)?),
+            VerifyingKey::Ed25519(verifying_key) => Ok(verifying_key.verify(message, &ed25519_dalek::Signature::from_slice(signature)?)?),
+        }
+    }
+
+    pub fn to_jwk(&self) -> Result<Jwk> {
+        let encode = |bytes: &[u8]| general_purpose::URL_SAFE_NO_PAD.encode(bytes);
+        let kid = self.key_id()?;
+        Ok(match self {
+            VerifyingKey::Rsa(verifying_key) => {
+                let public_key: &RsaPublicKey = verifying_key.as_ref();
+                Jwk {
+                    kty: "RSA",
+                    kid,
+                    crv: None,
+                    n: Some(encode(&public_key.n().to_bytes_be())),
+                    e: Some(encode(&public_key.e().to_bytes_be())),
+                    x: None,
+                    alg: "RS256",
+                    key_use: "sig",
+                }
+            }
+            VerifyingKey::Ed25519(verifying_key) => Jwk {
+                kty: "OKP",
+                kid,
+                crv: Some("Ed25519"),
+                n: None,
+                e: None,
+                x: Some(encode(verifying_key.as_bytes())),
+                alg: "EdDSA",
+                key_use: "sig",
+            },
+        })
+    }
+
+    /// A stable fingerprint of the key: the base64url SHA-256 of its DER encoding
+    pub fn key_id(&self) -> Result<String> {
+        let der = match self {
+            VerifyingKey::Rsa(verifying_key) => verifying_key.to_public_key_der()?,
+            VerifyingKey::Ed25519(verifying_key) => verifying_key.to_public_key_der()?,
+        };
+        Ok(general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(der.as_bytes())))
+    }
+
+    /// Read a SPKI PEM public key, of either algorithm
+    pub fn from_public_key_pem(pem: &str) -> Result<Self> {
+        match RsaPublicKey::from_public_key_pem(pem) {
+            Ok(public_key) => Ok(VerifyingKey::Rsa(pkcs1v15::VerifyingKey::new(public_key))),
+            Err(_) => Ok(VerifyingKey::Ed25519(ed25519_dalek::VerifyingKey::from_public_key_pem(pem)?)),
+        }
+    }
+
+    pub fn to_public_key_pem(&self) -> Result<String> {
+        Ok(match self {
+            VerifyingKey::Rsa(verifying_key) => verifying_key.to_public_key_pem(LineEnding::CRLF)?,
+            VerifyingKey::Ed25519(verifying_key) => verifying_key.to_public_key_pem(LineEnding::CRLF)?,
+        })
+    }
+}
+
+/// A verifying key as a JSON Web Key (RFC 7517), for WebCrypto clients
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Jwk {
+    kty: &'static str,
+    kid: String,
+    /// The curve of OKP keys
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crv: Option<&'static str>,
+    /// The modulus of RSA keys
+    #[serde(skip_serializing_if = "Option::is_none")]
+    n: Option<String>,
+    /// The exponent of RSA keys
+    #[serde(skip_serializing_if = "Option::is_none")]
+    e: Option<String>,
+    /// The public key of OKP keys
+    #[serde(skip_serializing_if = "Option::is_none")]
+    x: Option<String>,
+    alg: &'static str,
+    #[serde(rename = "use")]
+    key_use: &'static str,
+}
+
+#[derive(Clone)]
 pub struct Authenticator {
-    private_key: RsaPrivateKey,
-    signing_key: SigningKey<Sha256>,
-    verifying_key: VerifyingKey<Sha256>,
+    signing_key: SigningKey,
+    verifying_key: VerifyingKey,
+    /// Verifying keys of previous signing keys with the time until which they are accepted
+    retired_verifying_keys: Vec<(VerifyingKey, DateTime<Utc>)>,
+    /// Verifying keys of historical signing keys, accepted without time limit
+    historical_verifying_keys: Vec<VerifyingKey>,
+    /// When the signing key was created
+    created_at: DateTime<Utc>,
+    /// The size of the RSA keys generated by rotations
+    rsa_bits: usize,
 }
 
 impl Authenticator {
-    pub fn new(private_key: RsaPrivateKey) -> Self {
-        let signing_key = SigningKey::<Sha256>::new(private_key.clone());
+    pub fn new(signing_key: SigningKey) -> Self {
         let verifying_key = signing_key.verifying_key();
         Authenticator {
-            private_key, signing_key, verifying_key
+            signing_key, verifying_key, retired_verifying_keys: vec![], historical_verifying_keys: vec![], created_at: Utc::now(), rsa_bits: DEFAULT_RSA_KEY_BITS
         }
     }
 
-    pub fn random(bits: usize) -> Result<Self> {
-        let mut rng = rand::thread_rng();
-        Ok(Authenticator::new(RsaPrivateKey::new(&mut rng, bits)?))
+    pub fn random(algorithm: Algorithm, rsa_bits: usize) -> Result<Self> {
+        Ok(Authenticator { rsa_bits, ..Authenticator::new(SigningKey::random(algorithm, rsa_bits)?) })
     }
 
+    /// Load the key at `path`, or generate an RSA key there
     pub fn get(path: &str) -> Result<Self> {
-        Authenticator::try_load(path).or_else(|err| {
-            let auth = Authenticator::random(SIZE)?;
-            info!("Cannot load private key: {}", err);
-            auth.save(path)?;
-            Ok(auth)
-        })
+        Authenticator::get_or_generate(path, Algorithm::default(), DEFAULT_RSA_KEY_BITS)
+    }
+
+    /// Load the key at `path` whatever its algorithm, or generate a key of `algorithm` there (of `rsa_bits` for RSA, also used by rotations)
+    pub fn get_or_generate(path: &str, algorithm: Algorithm, rsa_bits: usize) -> Result<Self> {
+        let auth = Authenticator::try_load(path).or_else(|_| -> Result<Self> {
+            // Instances sharing the key path generate it under an exclusive lock, the others wait and load it
+            let lock = File::create(format!("{path}.lock"))?;
+            lock.lock_exclusive()?;
+            let auth = Authenticator::try_load(path).or_else(|err| {
+                let auth = Authenticator::random(algorithm, rsa_bits)?;
+                info!("Cannot load private key: {}", err);
+                auth.save(path)?;
+                Ok(auth)
+            });
+            lock.unlock()?;
+            auth
+        })?;
+        Ok(Authenticator { rsa_bits, ..auth })
     }
 
     pub fn try_load(path: &str) -> Result<Self> {
-        let private_key = DecodePrivateKey::read_pkcs8_pem_file(path)?;
-        info!("Loading private key from {path}");
-        Ok(Authenticator::new(private_key))
+        let signing_key = SigningKey::read_pem_file(path)?;
+        info!("Loading {} private key from {path}", signing_key.algorithm());
+        let auth = Authenticator::new(signing_key);
+        // The key was created when its file was last written
+        let created_at = std::fs::metadata(path).and_then(|metadata| metadata.modified()).map(DateTime::<Utc>::from).unwrap_or(auth.created_at);
+        Ok(Authenticator { created_at, ..auth })
     }
 
     pub fn save(&self, path: &str) -> Result<()> {
-        Ok(self.private_key.write_pkcs8_pem_file(path, LineEnding::CRLF)?)
+        self.signing_key.write_pem_file(path)
     }
 
-    // Accessors
-    pub fn private_key(&self) -> &RsaPrivateKey {
-        &self.private_key
+    /// Start signing with a fresh key of the same algorithm, the previous verifying key is still accepted during `grace_period`
+    pub fn rotate(&mut self, grace_period: Duration) -> Result<()> {
+        *self = self.rotated(grace_period)?;
+        Ok(())
     }
 
-    pub fn signing_key(&self) -> &SigningKey<Sha256> {
+    /// A copy signing with a fresh key of the same algorithm, accepting the current verifying key during `grace_period`
+    pub fn rotated(&self, grace_period: Duration) -> Result<Self> {
+        let now = Utc::now();
+        let fresh = Authenticator::random(self.algorithm(), self.rsa_bits)?;
+        let mut retired_verifying_keys: Vec<(VerifyingKey, DateTime<Utc>)> = self.retired_verifying_keys.iter().filter(|(_, expiry)| *expiry > now).cloned().collect();
+        retired_verifying_keys.push((self.verifying_key.clone(), now + grace_period));
+        Ok(Authenticator {
+            signing_key: fresh.signing_key,
+            verifying_key: fresh.verifying_key,
+            retired_verifying_keys,
+            historical_verifying_keys: self.historical_verifying_keys.clone(),
+            created_at: now,
+            rsa_bits: self.rsa_bits,
+        })
+    }
+
+    /// Accept the signatures of a historical signing key, e.g. of another instance or from before a restart
+    pub fn add_verifying_key(&mut self, verifying_key: VerifyingKey) {
+        self.historical_verifying_keys.push(verifying_key);
+    }
+
+    // Accessors
+    pub fn signing_key(&self) -> &SigningKey {
         &self.signing_key
     }
 
-    pub fn verifying_key(&self) -> &VerifyingKey<Sha256> {
+    pub fn verifying_key(&self) -> &VerifyingKey {
         &self.verifying_key
     }
 
+    pub fn algorithm(&self) -> Algorithm {
+        self.signing_key.algorithm()
+    }
+
+    pub fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+
+    /// The current, still accepted retired and historical verifying keys
+    pub fn verifying_keys(&self) -> impl Iterator<Item = &VerifyingKey> {
+        let now = Utc::now();
+        std::iter::once(&self.verifying_key)
+            .chain(self.retired_verifying_keys.iter().filter(move |(_, expiry)| *expiry > now).map(|(key, _)| key))
+            .chain(self.historical_verifying_keys.iter())
+    }
+
+    pub fn public_key_pem(&self) -> Result<String> {
+        self.verifying_key.to_public_key_pem()
+    }
+
+    pub fn verifying_key_jwk(&self) -> Result<Jwk> {
+        self.verifying_key.to_jwk()
+    }
+
+    /// The fingerprint of the current verifying key, changing with rotations
+    pub fn key_id(&self) -> Result<String> {
+        self.verifying_key.key_id()
+    }
+
     pub fn sign(&self, text: &str) -> String {
-        let mut rng = rand::thread_rng();
-        general_purpose::STANDARD_NO_PAD.encode(self.signing_key.sign_with_rng(&mut rng, text.as_bytes()).to_bytes())
+        general_purpose::STANDARD_NO_PAD.encode(self.signing_key.sign(text.as_bytes()))
     }
 
     pub fn verify(&self, text: &str, signature: &str) -> Result<()> {
-        Ok(self.verifying_key.verify(text.as_bytes(), &Signature::try_from(general_purpose::STANDARD_NO_PAD.decode(signature)?.as_slice())?)?)
+        self.verify_with_key_id(text, signature, None)
+    }
+
+    /// Verify with the key of id `key_id` when set, with any accepted key otherwise
+    pub fn verify_with_key_id(&self, text: &str, signature: &str, key_id: Option<&str>) -> Result<()> {
+        let signature = general_purpose::STANDARD_NO_PAD.decode(signature)?;
+        let mut verifying_keys = self.verifying_keys()
+            .filter(|verifying_key| key_id.is_none_or(|key_id| verifying_key.key_id().is_ok_and(|id| id == key_id)))
+            .peekable();
+        if verifying_keys.peek().is_none() {
+            return Err(Error::other(format!("No accepted verifying key has the id `{}`", key_id.unwrap_or_default())));
+        }
+        verifying_keys
+            .find_map(|verifying_key| verifying_key.verify(text.as_bytes(), &signature).ok())
+            .ok_or_else(|| Error::other("The signature does not match any verifying key"))
+    }
+}
+
+/// The result of a key rotation
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct KeyRotation {
+    old_public_key: String,
+    new_public_key: String,
+    /// The id of the new key, set in the `key_id` of the responses it signs
+    key_id: String,
+}
+
+impl KeyRotation {
+    pub fn new(old_public_key: String, new_public_key: String, key_id: String) -> KeyRotation {
+        KeyRotation {
+            old_public_key,
+            new_public_key,
+            key_id,
+        }
     }
 }
 
@@ -78,7 +372,6 @@ impl Authenticator {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use serde_json;
 
     #[test]
     fn test_signature() {
@@ -87,4 +380,148 @@ mod tests {
         println!("{signature}");
         auth.verify("Hello Sarus !", &signature).expect("OK");
     }
+
+    #[test]
+    fn test_concurrent_get() {
+        let path = std::env::temp_dir().join(format!("qrlew_server_test_concurrent_get_{}.pem", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let public_keys: Vec<String> = (0..4)
+            .map(|_| { let path = path.clone(); std::thread::spawn(move || Authenticator::get(&path).unwrap().public_key_pem().unwrap()) })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect();
+        // All instances end up with the same key
+        assert!(public_keys.iter().all(|public_key| *public_key == public_keys[0]));
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(format!("{path}.lock")).unwrap();
+    }
+
+    #[test]
+    fn test_rotation() {
+        let path = std::env::temp_dir().join("qrlew_server_test_rotation.pem");
+        let path = path.to_str().unwrap();
+        let mut auth = Authenticator::random(Algorithm::Rsa, DEFAULT_RSA_KEY_BITS).unwrap();
+        let old_public_key = auth.public_key_pem().unwrap();
+        let old_signature = auth.sign("Hello Sarus !");
+        auth.rotate(Duration::hours(1)).unwrap();
+        auth.save(path).unwrap();
+        let new_public_key = auth.public_key_pem().unwrap();
+        let new_signature = auth.sign("Hello Sarus !");
+        assert_ne!(old_public_key, new_public_key);
+        // Both keys validate during the grace period
+        auth.verify("Hello Sarus !", &old_signature).expect("OK");
+        auth.verify("Hello Sarus !", &new_signature).expect("OK");
+        // The new key was persisted
+        let loaded = Authenticator::try_load(path).unwrap();
+        assert_eq!(loaded.public_key_pem().unwrap(), new_public_key);
+        loaded.verify("Hello Sarus !", &new_signature).expect("OK");
+        assert!(loaded.verify("Hello Sarus !", &old_signature).is_err());
+        // The old key is dropped once the grace period is over
+        auth.rotate(Duration::zero()).unwrap();
+        assert!(auth.verify("Hello Sarus !", &new_signature).is_err());
+    }
+
+    #[test]
+    fn test_historical_verifying_keys() {
+        let mut auth = Authenticator::random(Algorithm::Ed25519, DEFAULT_RSA_KEY_BITS).unwrap();
+        let rsa = Authenticator::get("secret_key.pem").unwrap();
+        let rsa_signature = rsa.sign("Hello Sarus !");
+        assert!(auth.verify("Hello Sarus !", &rsa_signature).is_err());
+        let verifying_key = VerifyingKey::from_public_key_pem(&rsa.public_key_pem().unwrap()).unwrap();
+        assert_eq!(verifying_key.key_id().unwrap(), rsa.key_id().unwrap());
+        auth.add_verifying_key(verifying_key);
+        auth.verify("Hello Sarus !", &rsa_signature).expect("OK");
+        // Historical keys outlive rotations
+        auth.rotate(Duration::zero()).unwrap();
+        auth.rotate(Duration::zero()).unwrap();
+        auth.verify("Hello Sarus !", &rsa_signature).expect("OK");
+        // The key id selects the key
+        auth.verify_with_key_id("Hello Sarus !", &rsa_signature, Some(&rsa.key_id().unwrap())).expect("OK");
+        assert!(auth.verify_with_key_id("Hello Sarus !", &rsa_signature, Some(&auth.key_id().unwrap())).is_err());
+        let err = auth.verify_with_key_id("Hello Sarus !", &rsa_signature, Some("unknown")).unwrap_err();
+        println!("{err}");
+        assert!(err.to_string().contains("unknown"));
+        let ed25519 = Authenticator::random(Algorithm::Ed25519, DEFAULT_RSA_KEY_BITS).unwrap();
+        assert!(VerifyingKey::from_public_key_pem(&ed25519.public_key_pem().unwrap()).is_ok());
+        assert!(VerifyingKey::from_public_key_pem("not a key").is_err());
+    }
+
+    #[test]
+    fn test_jwk() {
+        let auth = Authenticator::get("secret_key.pem").unwrap();
+        let jwk = serde_json::to_value(auth.verifying_key_jwk().unwrap()).unwrap();
+        println!("{jwk}");
+        assert_eq!(jwk["kty"], "RSA");
+        assert_eq!(jwk["e"], "AQAB");
+        assert_eq!(jwk["alg"], "RS256");
+        assert_eq!(jwk["use"], "sig");
+        let n = general_purpose::URL_SAFE_NO_PAD.decode(jwk["n"].as_str().unwrap()).unwrap();
+        let SigningKey::Rsa(signing_key) = auth.signing_key() else { panic!("RSA key expected") };
+        assert_eq!(n, signing_key.as_ref().n().to_bytes_be());
+        assert!(jwk.get("x").is_none());
+        assert_eq!(jwk["kid"], auth.key_id().unwrap());
+        let jwk = serde_json::to_value(Authenticator::random(Algorithm::Ed25519, DEFAULT_RSA_KEY_BITS).unwrap().verifying_key_jwk().unwrap()).unwrap();
+        println!("{jwk}");
+        assert_eq!(jwk["kty"], "OKP");
+        assert_eq!(jwk["crv"], "Ed25519");
+        assert_eq!(general_purpose::URL_SAFE_NO_PAD.decode(jwk["x"].as_str().unwrap()).unwrap().len(), 32);
+    }
+
+    #[test]
+    fn test_key_id() {
+        let mut auth = Authenticator::get("secret_key.pem").unwrap();
+        let key_id = auth.key_id().unwrap();
+        println!("{key_id}");
+        // 32 bytes in unpadded base64url
+        assert_eq!(key_id.len(), 43);
+        assert_eq!(Authenticator::try_load("secret_key.pem").unwrap().key_id().unwrap(), key_id);
+        auth.rotate(Duration::hours(1)).unwrap();
+        assert_ne!(auth.key_id().unwrap(), key_id);
+    }
+
+    #[test]
+    fn test_rsa_key_bits() {
+        let mut auth = Authenticator::random(Algorithm::Rsa, 3072).unwrap();
+        let SigningKey::Rsa(signing_key) = auth.signing_key() else { panic!("RSA key expected") };
+        assert_eq!(signing_key.as_ref().size(), 384);
+        let signature = auth.sign("Hello Sarus !");
+        println!("{signature}");
+        auth.verify("Hello Sarus !", &signature).expect("OK");
+        // Rotations keep the size
+        auth.rotate(Duration::hours(1)).unwrap();
+        let SigningKey::Rsa(signing_key) = auth.signing_key() else { panic!("RSA key expected") };
+        assert_eq!(signing_key.as_ref().size(), 384);
+        assert!(Authenticator::random(Algorithm::Rsa, 1024).is_err());
+        assert!(Authenticator::random(Algorithm::Ed25519, 1024).is_ok());
+    }
+
+    #[test]
+    fn test_ed25519() {
+        let path = std::env::temp_dir().join(format!("qrlew_server_test_ed25519_{}.pem", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut auth = Authenticator::get_or_generate(path, Algorithm::Ed25519, DEFAULT_RSA_KEY_BITS).unwrap();
+        assert_eq!(auth.algorithm(), Algorithm::Ed25519);
+        let signature = auth.sign("Hello Sarus !");
+        println!("{signature}\n{}", auth.public_key_pem().unwrap());
+        // 64 bytes signatures
+        assert_eq!(signature.len(), 86);
+        auth.verify("Hello Sarus !", &signature).expect("OK");
+        assert!(auth.verify("Hello Sarus?", &signature).is_err());
+        // The algorithm of the key file prevails
+        let loaded = Authenticator::get_or_generate(path, Algorithm::Rsa, DEFAULT_RSA_KEY_BITS).unwrap();
+        assert_eq!(loaded.public_key_pem().unwrap(), auth.public_key_pem().unwrap());
+        loaded.verify("Hello Sarus !", &signature).expect("OK");
+        // Rotations keep the algorithm
+        auth.rotate(Duration::hours(1)).unwrap();
+        assert_eq!(auth.algorithm(), Algorithm::Ed25519);
+        auth.verify("Hello Sarus !", &signature).expect("OK");
+        // RSA signatures are rejected
+        let rsa = Authenticator::get("secret_key.pem").unwrap();
+        assert!(auth.verify("Hello Sarus !", &rsa.sign("Hello Sarus !")).is_err());
+        assert_eq!("Ed25519".parse::<Algorithm>().unwrap(), Algorithm::Ed25519);
+        assert!("dsa".parse::<Algorithm>().is_err());
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(format!("{path}.lock")).unwrap();
+    }
 }
\ No newline at end of file
@@ -0,0 +1,71 @@
+use std::{collections::HashMap, hash::Hash, sync::{Mutex, atomic::{AtomicU64, Ordering}}};
+
+/// An in-memory cache of bounded size, evicting the least recently used values
+pub struct LruCache<K, V> {
+    capacity: usize,
+    /// Incremented at each access, to date the last use of values
+    tick: AtomicU64,
+    entries: Mutex<HashMap<K, (V, u64)>>,
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> LruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        LruCache {
+            capacity,
+            tick: AtomicU64::new(0),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get a value, marking it as recently used
+    pub fn get(&self, key: &K) -> Option<V> {
+        let mut entries = self.entries.lock().unwrap();
+        let (value, last_use) = entries.get_mut(key)?;
+        *last_use = self.tick.fetch_add(1, Ordering::Relaxed);
+        Some(value.clone())
+    }
+
+    /// Store a value, evicting the least recently used one when full
+    pub fn insert(&self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(&key) && entries.len() >= self.capacity {
+            if let Some(least_recently_used) = entries.iter().min_by_key(|(_, (_, last_use))| *last_use).map(|(key, _)| key.clone()) {
+                entries.remove(&least_recently_used);
+            }
+        }
+        entries.insert(key, (value, self.tick.fetch_add(1, Ordering::Relaxed)));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru_cache() {
+        let cache = LruCache::new(2);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        assert_eq!(cache.get(&"a"), Some(1));
+        // b is the least recently used
+        cache.insert("c", 3);
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"a"), Some(1));
+        assert_eq!(cache.get(&"c"), Some(3));
+        assert_eq!(cache.len(), 2);
+        let disabled = LruCache::new(0);
+        disabled.insert("a", 1);
+        assert_eq!(disabled.get(&"a"), None);
+    }
+}
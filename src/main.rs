@@ -1,25 +1,39 @@
+pub mod accountant;
 pub mod auth;
+pub mod cache;
+pub mod prometheus;
+pub mod rate_limit;
+pub mod registry;
 pub mod request;
 pub mod response;
+pub mod stats;
 // Reexport
-pub use auth::Authenticator;
-pub use request::{Dot, RewriteAsPrivacyUnitPreserving, RewriteWithDifferentialPrivacy};
+pub use accountant::BudgetAccountant;
+pub use auth::{Algorithm, Authenticator, KeyRotation};
+pub use cache::LruCache;
+pub use rate_limit::RateLimiter;
+pub use registry::Registry;
+pub use request::{Dot, JsonRequest, RewriteAsPrivacyUnitPreserving, RewriteWithDifferentialPrivacy};
 pub use response::Response;
+pub use stats::Stats;
 
-use std::{error, result, fmt, io, string, sync::OnceLock};
-use rsa;
-use rsa::pkcs8::spki::{EncodePublicKey, der::pem::LineEnding};
+use std::{collections::BTreeMap, env, error, result, fmt, future::{Future, IntoFuture}, io, net::SocketAddr, process, string, sync::{Arc, OnceLock, RwLock}, time::Duration};
 use axum::{
-    extract,
+    body::{self, Body},
+    extract::{rejection::BytesRejection, DefaultBodyLimit, FromRequest as _, MatchedPath, Path, Request, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::{self, Next},
+    response::IntoResponse as _,
     routing::{get, post},
-    Router,
+    Json, Router,
 };
 use tower_http::{
+    compression::{predicate::DefaultPredicate, CompressionLayer},
     trace::{self, TraceLayer},
-    cors::CorsLayer,
+    cors::{AllowOrigin, CorsLayer},
 };
+use serde::Serialize;
 use tracing::Level;
-use serde_json;
 use qrlew::{differential_privacy, rewriting};
 
 
@@ -27,7 +41,12 @@ use qrlew::{differential_privacy, rewriting};
 pub enum Error {
     InvalidRequest(String),
     InvalidSQL(String),
-    ImpossibleRewriting(String),
+    /// A rewriting failure with the aggregations that could not be rewritten
+    ImpossibleRewriting(String, Vec<String>),
+    Unauthorized(String),
+    TooManyRequests(String),
+    PayloadTooLarge(String),
+    Timeout(String),
     Other(String),
 }
 
@@ -39,30 +58,100 @@ impl Error {
         Error::InvalidSQL(format!("Invalid SQL: {}", sql))
     }
     pub fn impossible_rewriting(sql: impl fmt::Display) -> Error {
-        Error::InvalidSQL(format!("Impossible Rewriting: {}", sql))
+        Error::ImpossibleRewriting(format!("Impossible Rewriting: {}", sql), vec![])
+    }
+    pub fn unsupported_aggregations(rewriting: impl fmt::Display, aggregations: Vec<String>) -> Error {
+        Error::ImpossibleRewriting(format!("Impossible Rewriting: {}", rewriting), aggregations)
+    }
+    pub fn unauthorized(desc: impl fmt::Display) -> Error {
+        Error::Unauthorized(format!("Unauthorized: {}", desc))
+    }
+    pub fn too_many_requests(desc: impl fmt::Display) -> Error {
+        Error::TooManyRequests(format!("Too many requests: {}", desc))
+    }
+    pub fn payload_too_large(desc: impl fmt::Display) -> Error {
+        Error::PayloadTooLarge(format!("Payload too large: {}", desc))
+    }
+    pub fn timeout(desc: impl fmt::Display) -> Error {
+        Error::Timeout(format!("Timeout: {}", desc))
     }
     pub fn other<T: fmt::Display>(desc: T) -> Error {
         Error::Other(desc.to_string())
     }
+    /// The machine-readable kind of the error
+    pub fn tag(&self) -> &'static str {
+        match self {
+            Error::InvalidRequest(_) => "InvalidRequest",
+            Error::InvalidSQL(_) => "InvalidSQL",
+            Error::ImpossibleRewriting(_, _) => "ImpossibleRewriting",
+            Error::Unauthorized(_) => "Unauthorized",
+            Error::TooManyRequests(_) => "TooManyRequests",
+            Error::PayloadTooLarge(_) => "PayloadTooLarge",
+            Error::Timeout(_) => "Timeout",
+            Error::Other(_) => "Other",
+        }
+    }
+    /// The human-readable description of the error
+    pub fn message(&self) -> String {
+        match self {
+            Error::ImpossibleRewriting(sql, aggregations) if !aggregations.is_empty() => format!("{} (unsupported aggregations: {})", sql, aggregations.join(", ")),
+            Error::InvalidRequest(desc) | Error::InvalidSQL(desc) | Error::ImpossibleRewriting(desc, _)
+            | Error::Unauthorized(desc) | Error::TooManyRequests(desc) | Error::PayloadTooLarge(desc) | Error::Timeout(desc) | Error::Other(desc) => desc.clone(),
+        }
+    }
+    /// The HTTP status of the error
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::InvalidRequest(_) | Error::InvalidSQL(_) => StatusCode::BAD_REQUEST,
+            Error::ImpossibleRewriting(_, _) => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Error::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            Error::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Error::Other(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+    /// The aggregations preventing a rewriting, if any
+    pub fn aggregations(&self) -> &[String] {
+        match self {
+            Error::ImpossibleRewriting(_, aggregations) => aggregations,
+            _ => &[],
+        }
+    }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Error::InvalidRequest(request) => writeln!(f, "InvalidRequest: {}", request),
-            Error::InvalidSQL(sql) => writeln!(f, "InvalidSQL: {}", sql),
-            Error::ImpossibleRewriting(sql) => writeln!(f, "ImpossibleRewriting: {}", sql),
             Error::Other(err) => writeln!(f, "{}", err),
+            _ => writeln!(f, "{}: {}", self.tag(), self.message()),
         }
     }
 }
 
 impl error::Error for Error {}
 
+/// The JSON body of error responses
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
+    message: String,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    aggregations: &'a [String],
+}
+
 // Errors need to be convertible to responses
 impl axum::response::IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
-        self.to_string().into_response()
+        let body = ErrorBody { error: self.tag(), message: self.message(), aggregations: self.aggregations() };
+        let mut response = match serde_json::to_string(&body) {
+            Ok(body) => (self.status(), [(header::CONTENT_TYPE, "application/json")], body).into_response(),
+            // Fall back to the plain message rather than to an error response, which could fail the same way
+            Err(_) => (self.status(), self.to_string()).into_response(),
+        };
+        // Keep the error around for the middlewares
+        response.extensions_mut().insert(self);
+        response
     }
 }
 
@@ -90,6 +179,15 @@ impl From<io::Error> for Error {
     }
 }
 
+impl From<BytesRejection> for Error {
+    fn from(rejection: BytesRejection) -> Self {
+        match rejection.status() {
+            StatusCode::PAYLOAD_TOO_LARGE => Error::payload_too_large(format!("the request body exceeds {} bytes", max_body_bytes())),
+            _ => Error::invalid_request(rejection.body_text()),
+        }
+    }
+}
+
 impl From<differential_privacy::Error> for Error {
     fn from(err: differential_privacy::Error) -> Self {
         Error::other(err)
@@ -140,76 +238,1294 @@ impl From<chrono::ParseError> for Error {
 
 pub type Result<T> = result::Result<T, Error>;
 
-/// A global shared Authenticator
-static AUTH: OnceLock<Authenticator> = OnceLock::new();
+/// The path of the private key when `QRLEW_KEY_PATH` is not set
+const DEFAULT_KEY_PATH: &str = "secret_key.pem";
+/// The address to listen on when `QRLEW_BIND_ADDR` is not set
+const DEFAULT_BIND_ADDR: &str = "0.0.0.0:3000";
+/// The header carrying the admin token
+const ADMIN_TOKEN_HEADER: &str = "x-admin-token";
+const REQUEST_ID_HEADER: &str = "x-request-id";
+/// The longest request id accepted from clients, longer ones are replaced by a generated id
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+const KEY_ALGORITHM_HEADER: &str = "x-key-algorithm";
+const KEY_ID_HEADER: &str = "x-key-id";
+const KEY_CREATED_AT_HEADER: &str = "x-key-created-at";
+const KEY_NEXT_ROTATION_HEADER: &str = "x-key-next-rotation";
+/// How long the verifying key is still accepted after a rotation
+const KEY_ROTATION_GRACE_PERIOD_HOURS: i64 = 24;
+
+/// A global shared Authenticator, replaced as a whole by key rotations
+static AUTH: OnceLock<RwLock<Arc<Authenticator>>> = OnceLock::new();
+
+/// The path of the private key, from `QRLEW_KEY_PATH`
+fn key_path() -> String {
+    key_path_from(env::var("QRLEW_KEY_PATH").ok())
+}
+
+/// The path of the private key, `value` when set
+fn key_path_from(value: Option<String>) -> String {
+    value.unwrap_or_else(|| DEFAULT_KEY_PATH.to_string())
+}
+
+/// The algorithm of generated keys, from `QRLEW_KEY_ALGORITHM`
+fn key_algorithm() -> Result<Algorithm> {
+    env::var("QRLEW_KEY_ALGORITHM").map_or(Ok(Algorithm::default()), |algorithm| algorithm.parse())
+}
+
+/// The size of generated RSA keys, from `QRLEW_KEY_BITS`
+fn key_bits() -> Result<usize> {
+    match env::var("QRLEW_KEY_BITS") {
+        Ok(bits) => match bits.trim().parse() {
+            Ok(bits) if auth::RSA_KEY_BITS.contains(&bits) => Ok(bits),
+            _ => Err(Error::other(format!("unsupported RSA key size `{bits}`, expected one of {:?}", auth::RSA_KEY_BITS))),
+        },
+        Err(_) => Ok(auth::DEFAULT_RSA_KEY_BITS),
+    }
+}
+
+/// The historical verifying keys, read from the comma-separated public key PEM files of `QRLEW_VERIFYING_KEYS`
+fn historical_verifying_keys() -> Result<Vec<auth::VerifyingKey>> {
+    env::var("QRLEW_VERIFYING_KEYS").unwrap_or_default().split(',').map(str::trim).filter(|path| !path.is_empty())
+        .map(|path| {
+            let pem = std::fs::read_to_string(path).map_err(|err| Error::other(format!("cannot read `{path}`: {err}")))?;
+            auth::VerifyingKey::from_public_key_pem(&pem)
+        })
+        .collect()
+}
+
+fn auth_lock() -> &'static RwLock<Arc<Authenticator>> {
+    AUTH.get_or_init(|| {
+        let mut auth = Authenticator::get_or_generate(&key_path(), key_algorithm().unwrap(), key_bits().unwrap()).unwrap();
+        for verifying_key in historical_verifying_keys().unwrap() {
+            auth.add_verifying_key(verifying_key);
+        }
+        RwLock::new(Arc::new(auth))
+    })
+}
+
+/// A snapshot of the shared Authenticator, the lock is only held while cloning it
+fn auth() -> Arc<Authenticator> {
+    auth_lock().read().unwrap().clone()
+}
+
+/// How long registered objects are kept by default
+const DEFAULT_REGISTRY_TTL_SECS: u64 = 3600;
+
+/// The registered privacy units
+static PRIVACY_UNITS: OnceLock<Registry<request::PrivacyUnitSpec>> = OnceLock::new();
+
+/// Access the registered privacy units, kept for `QRLEW_REGISTRY_TTL_SECS`
+fn privacy_units() -> &'static Registry<request::PrivacyUnitSpec> {
+    PRIVACY_UNITS.get_or_init(|| {
+        let ttl = env::var("QRLEW_REGISTRY_TTL_SECS").ok().and_then(|ttl| ttl.parse().ok()).unwrap_or(DEFAULT_REGISTRY_TTL_SECS);
+        Registry::new(Duration::from_secs(ttl))
+    })
+}
+
+/// How many rendered dot graphs are cached by default
+const DEFAULT_DOT_CACHE_SIZE: usize = 128;
+
+/// The rendered dot graphs, by hash of their relation and style
+static DOT_CACHE: OnceLock<LruCache<u64, String>> = OnceLock::new();
+
+/// Access the dot graph cache, holding `QRLEW_DOT_CACHE_SIZE` graphs (0 disables it)
+fn dot_cache() -> &'static LruCache<u64, String> {
+    DOT_CACHE.get_or_init(|| {
+        let size = env::var("QRLEW_DOT_CACHE_SIZE").ok().and_then(|size| size.parse().ok()).unwrap_or(DEFAULT_DOT_CACHE_SIZE);
+        LruCache::new(size)
+    })
+}
+
+/// How many datasets converted into relations are cached by default
+const DEFAULT_DATASET_CACHE_SIZE: usize = 32;
+
+/// The relations of datasets with their conversion warnings, by hash of the dataset and conversion settings
+static DATASET_CACHE: OnceLock<LruCache<u64, request::DatasetRelations>> = OnceLock::new();
+
+/// Access the dataset cache, holding `QRLEW_DATASET_CACHE_SIZE` datasets (0 disables it)
+fn dataset_cache() -> &'static LruCache<u64, request::DatasetRelations> {
+    DATASET_CACHE.get_or_init(|| {
+        let size = env::var("QRLEW_DATASET_CACHE_SIZE").ok().and_then(|size| size.parse().ok()).unwrap_or(DEFAULT_DATASET_CACHE_SIZE);
+        LruCache::new(size)
+    })
+}
+
+/// The request counters
+static STATS: OnceLock<Stats> = OnceLock::new();
+
+fn stats() -> &'static Stats {
+    STATS.get_or_init(Stats::default)
+}
+
+/// How long a request may be handled by default, in seconds
+const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 30;
+
+/// How long a request may be handled, `QRLEW_REQUEST_TIMEOUT_SECS` or 30 seconds
+fn request_timeout() -> Duration {
+    Duration::from_secs(env::var("QRLEW_REQUEST_TIMEOUT_SECS").ok().and_then(|timeout| timeout.parse().ok()).unwrap_or(DEFAULT_REQUEST_TIMEOUT_SECS))
+}
+
+/// Answer a 504 to requests not handled within the timeout, a rewrite already running on the blocking pool still completes but its result is dropped:
+/// with budget accounting, a DP rewrite timing out after being rewritten still spends its budget, the timeout bounds the wait of clients, not the budget spent
+async fn limit_duration(State(timeout): State<Duration>, request: Request, next: Next) -> axum::response::Response {
+    match tokio::time::timeout(timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => Error::timeout(format!("the request was not handled within {}s", timeout.as_secs_f64())).into_response(),
+    }
+}
+
+/// Count the requests of each route and time them
+async fn count_requests(request: Request, next: Next) -> axum::response::Response {
+    let route = request.extensions().get::<MatchedPath>().map(|path| path.as_str().to_string());
+    let start = std::time::Instant::now();
+    let response = next.run(request).await;
+    if let Some(route) = route {
+        let success = response.status().is_success() && response.extensions().get::<Error>().is_none();
+        stats().record(&route, success);
+        prometheus::record_request(&route, success, start.elapsed());
+    }
+    response
+}
+
+/// The per protected table budget accountant, when `QRLEW_BUDGET_ACCOUNTING` is set, with a total budget of `QRLEW_TOTAL_EPSILON` and `QRLEW_TOTAL_DELTA`
+static BUDGET_ACCOUNTANT: OnceLock<Option<BudgetAccountant>> = OnceLock::new();
+
+/// The total budget of each protected table by default, when budget accounting is enabled
+const DEFAULT_TOTAL_EPSILON: f64 = 1.;
+const DEFAULT_TOTAL_DELTA: f64 = 1e-5;
 
-/// A function used to count named objects
-fn auth() -> &'static Authenticator {
-    AUTH.get_or_init(|| Authenticator::get("secret_key.pem").unwrap())
+fn budget_accountant() -> Option<&'static BudgetAccountant> {
+    BUDGET_ACCOUNTANT.get_or_init(|| {
+        env_flag("QRLEW_BUDGET_ACCOUNTING").then(|| {
+            let total = |name: &str, default: f64| env::var(name).ok().and_then(|total| total.parse().ok()).unwrap_or(default);
+            BudgetAccountant::new(total("QRLEW_TOTAL_EPSILON", DEFAULT_TOTAL_EPSILON), total("QRLEW_TOTAL_DELTA", DEFAULT_TOTAL_DELTA))
+        })
+    }).as_ref()
 }
 
-async fn verify(extract::Json(response): extract::Json<Response>) -> Result<String> {
-    auth().verify(response.value(), response.signature().ok_or(Error::invalid_request(response.value()))?).and_then(|_| Ok(format!("Verified"))).or_else(|_| Ok(format!("Not verified")))
+/// The per dataset rate limiter, when `QRLEW_DATASET_RATE_LIMIT` (requests per minute) is set
+static DATASET_RATE_LIMITER: OnceLock<Option<RateLimiter>> = OnceLock::new();
+
+fn dataset_rate_limiter() -> Option<&'static RateLimiter> {
+    DATASET_RATE_LIMITER.get_or_init(|| {
+        env::var("QRLEW_DATASET_RATE_LIMIT").ok().and_then(|limit| limit.parse().ok()).map(RateLimiter::new)
+    }).as_ref()
 }
 
-async fn public_key() -> Result<String> {
-    Ok(auth().verifying_key().to_public_key_pem(LineEnding::CRLF)?)
+/// The rate limiting key of a request body: the hash of its inline dataset (or of its dataset file)
+fn dataset_key(body: &[u8]) -> Option<String> {
+    let body: serde_json::Value = serde_json::from_slice(body).ok()?;
+    let dataset = body.get("dataset").or_else(|| body.get("dataset_file"))?;
+    Some(response::sha256_hex(dataset.to_string()))
 }
 
-async fn dot(extract::Json(dot_request): extract::Json<request::Dot>) -> Result<Response> {
+/// Reject the requests exceeding the rate limit of their dataset with a 429
+async fn limit_dataset_rate(request: Request, next: Next) -> axum::response::Response {
+    let Some(limiter) = dataset_rate_limiter() else {
+        return next.run(request).await;
+    };
+    let (parts, request_body) = request.into_parts();
+    // The body is read with the extensions of the request, holding its size limit
+    let mut body_request = Request::new(request_body);
+    *body_request.extensions_mut() = parts.extensions.clone();
+    let bytes = match body::Bytes::from_request(body_request, &()).await {
+        Ok(bytes) => bytes,
+        Err(rejection) => return Error::from(rejection).into_response(),
+    };
+    if let Some(key) = dataset_key(&bytes) {
+        if !limiter.try_acquire(&key) {
+            return Error::too_many_requests("the rate limit of this dataset is exceeded").into_response();
+        }
+    }
+    next.run(Request::from_parts(parts, Body::from(bytes))).await
+}
+
+/// The id of a request, from its `x-request-id` header
+fn request_id(headers: &HeaderMap) -> Option<&str> {
+    headers.get(REQUEST_ID_HEADER).and_then(|value| value.to_str().ok())
+}
+
+/// Set a generated UUID as `x-request-id` on requests without one, or with one longer than `MAX_REQUEST_ID_LENGTH`,
+/// before the trace span of the request is created
+async fn with_request_id(mut request: Request, next: Next) -> axum::response::Response {
+    if request_id(request.headers()).is_none_or(|request_id| request_id.len() > MAX_REQUEST_ID_LENGTH) {
+        if let Ok(request_id) = HeaderValue::from_str(&uuid::Uuid::new_v4().to_string()) {
+            request.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+        }
+    }
+    next.run(request).await
+}
+
+/// The trace span of a request, with its id for all the log lines of the request to carry it
+fn request_span(request: &Request) -> tracing::Span {
+    let request_id = request_id(request.headers()).unwrap_or_default();
+    tracing::info_span!("request", method = %request.method(), uri = %request.uri(), version = ?request.version(), request_id = %request_id)
+}
+
+/// Tag responses with the request id and the matched route, and echo the request id in the `x-request-id` header
+async fn tag_responses(request: Request, next: Next) -> axum::response::Response {
+    let request_id = request_id(request.headers()).map(str::to_string);
+    let route = request.extensions().get::<MatchedPath>().map(|path| path.as_str().to_string());
+    let mut response = next.run(request).await;
+    if let Some(body) = response.extensions_mut().remove::<Response>() {
+        let (parts, _) = response.into_parts();
+        let tagged = body.with_request_id(request_id.clone()).with_route(route);
+        response = match serde_json::to_string(&tagged) {
+            Ok(tagged_body) => axum::response::Response::from_parts(parts, tagged_body.into()),
+            Err(err) => Error::from(err).into_response(),
+        };
+    }
+    if let Some(request_id) = request_id.and_then(|request_id| HeaderValue::from_str(&request_id).ok()) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+    }
+    response
+}
+
+/// The address to listen on, `QRLEW_BIND_ADDR` when set
+fn bind_addr(value: Option<&str>) -> result::Result<SocketAddr, String> {
+    let value = value.unwrap_or(DEFAULT_BIND_ADDR);
+    value.trim().parse().map_err(|err| format!("`{value}` is not a socket address like {DEFAULT_BIND_ADDR} ({err})"))
+}
+
+/// Read a boolean flag from the environment (`1` or `true`)
+fn env_flag(name: &str) -> bool {
+    env::var(name).map(|value| matches!(value.to_lowercase().as_str(), "1" | "true")).unwrap_or(false)
+}
+
+/// Check the admin token of a request is `token` (from `QRLEW_ADMIN_TOKEN`), admin routes are disabled when it is not set
+fn check_admin_token(headers: &HeaderMap, token: Option<&str>) -> Result<()> {
+    let token = token.ok_or_else(|| Error::unauthorized("admin routes are disabled"))?;
+    match headers.get(ADMIN_TOKEN_HEADER).and_then(|value| value.to_str().ok()) {
+        // Comparing digests does not leak how much of the token a guess matches
+        Some(value) if !token.is_empty() && response::sha256_hex(value) == response::sha256_hex(token) => Ok(()),
+        _ => Err(Error::unauthorized("invalid admin token")),
+    }
+}
+
+/// The result of `/verify`
+#[derive(Serialize)]
+struct Verification {
+    verified: bool,
+}
+
+/// Check the signature of a response with the key of its `key_id` when set, responses without signature are rejected
+#[utoipa::path(post, path = "/verify", request_body = Response, responses((status = 200, description = "OK")))]
+async fn verify(JsonRequest(response): JsonRequest<Response>) -> Result<Json<Verification>> {
+    let signature = response.signature().ok_or_else(|| Error::invalid_request("the response has no signature"))?;
+    let verified = response.has_valid_digest() && auth().verify_with_key_id(&response.signed_payload(), signature, response.key_id()).is_ok();
+    Ok(Json(Verification { verified }))
+}
+
+/// The name and version of the server
+#[utoipa::path(get, path = "/", responses((status = 200, description = "OK", body = String, content_type = "text/plain")))]
+async fn index() -> String {
+    format!("This is Qrlew server {}", env!("CARGO_PKG_VERSION"))
+}
+
+/// The versions of the server and of the qrlew engine
+#[derive(Serialize)]
+struct Versions {
+    server_version: &'static str,
+    qrlew_version: &'static str,
+}
+
+/// The versions of the server and of qrlew
+#[utoipa::path(get, path = "/version", responses((status = 200, description = "OK")))]
+async fn version() -> Json<Versions> {
+    Json(Versions { server_version: env!("CARGO_PKG_VERSION"), qrlew_version: env!("QRLEW_VERSION") })
+}
+
+/// The status of the server, for liveness and readiness probes
+#[derive(Serialize)]
+struct Status {
+    status: &'static str,
+}
+
+/// Liveness probe
+#[utoipa::path(get, path = "/health", responses((status = 200, description = "OK")))]
+async fn health() -> Json<Status> {
+    Json(Status { status: "ok" })
+}
+
+/// Readiness probe, ready once the signing key is loaded
+#[utoipa::path(get, path = "/ready", responses((status = 200, description = "OK")))]
+async fn ready() -> (StatusCode, Json<Status>) {
+    readiness(AUTH.get().is_some())
+}
+
+fn readiness(ready: bool) -> (StatusCode, Json<Status>) {
+    if ready {
+        (StatusCode::OK, Json(Status { status: "ready" }))
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, Json(Status { status: "starting" }))
+    }
+}
+
+/// Request counts per route
+#[utoipa::path(get, path = "/stats", responses((status = 200, description = "OK")))]
+async fn get_stats() -> Json<BTreeMap<String, stats::CountersSnapshot>> {
+    Json(stats().snapshot())
+}
+
+/// Request counts and durations per route and signature count, in the Prometheus text format
+#[utoipa::path(get, path = "/metrics", responses((status = 200, description = "OK", body = String, content_type = "text/plain")))]
+async fn metrics() -> ([(header::HeaderName, &'static str); 1], String) {
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], prometheus::handle().render())
+}
+
+/// The interval between scheduled key rotations, set in hours by `QRLEW_KEY_ROTATION_HOURS`
+fn key_rotation_interval() -> Option<chrono::Duration> {
+    env::var("QRLEW_KEY_ROTATION_HOURS").ok()?.parse().ok().filter(|hours| *hours > 0).map(chrono::Duration::hours)
+}
+
+/// Resolve synthetic tables by naming: the synthetic table of `schema.table` is `schema.{template}` with `{table}` replaced by `table`
+fn synthetic_table_template_resolver(template: String) -> impl Fn(&[String]) -> Option<Vec<String>> + Send + Sync {
+    move |path: &[String]| {
+        let (table, schema) = path.split_last()?;
+        Some(schema.iter().cloned().chain([template.replace("{table}", table)]).collect())
+    }
+}
+
+/// The PEM public key verifying signatures, with its algorithm, id, creation time and next scheduled rotation in headers
+#[utoipa::path(get, path = "/public_key", responses((status = 200, description = "OK", body = String, content_type = "text/plain")))]
+async fn public_key() -> Result<(HeaderMap, String)> {
+    let auth = auth();
+    let mut headers = HeaderMap::new();
+    headers.insert(KEY_ID_HEADER, HeaderValue::from_str(&auth.key_id()?).map_err(Error::other)?);
+    headers.insert(KEY_ALGORITHM_HEADER, HeaderValue::from_str(&auth.algorithm().to_string()).map_err(Error::other)?);
+    headers.insert(KEY_CREATED_AT_HEADER, HeaderValue::from_str(&auth.created_at().to_rfc3339()).map_err(Error::other)?);
+    if let Some(interval) = key_rotation_interval() {
+        headers.insert(KEY_NEXT_ROTATION_HEADER, HeaderValue::from_str(&(auth.created_at() + interval).to_rfc3339()).map_err(Error::other)?);
+    }
+    Ok((headers, auth.public_key_pem()?))
+}
+
+/// The public key as a JSON Web Key
+#[utoipa::path(get, path = "/public_key.jwk", responses((status = 200, description = "OK")))]
+async fn public_key_jwk() -> Result<Json<auth::Jwk>> {
+    Ok(Json(auth().verifying_key_jwk()?))
+}
+
+/// Serializes key rotations, for each one to retire the key of the previous one
+static KEY_ROTATION: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Sign with a fresh key saved at `key_path`, keeping the previous one valid for verification during a grace period.
+/// The key is generated and saved on the blocking pool, requests keep being signed with the previous key until it is swapped in
+async fn rotate_signing_key(auth_lock: &RwLock<Arc<Authenticator>>, key_path: String) -> Result<KeyRotation> {
+    let _rotation = KEY_ROTATION.lock().await;
+    let current = auth_lock.read().unwrap().clone();
+    let old_public_key = current.public_key_pem()?;
+    let rotated = blocking(move || {
+        let rotated = current.rotated(chrono::Duration::hours(KEY_ROTATION_GRACE_PERIOD_HOURS))?;
+        // The key is persisted before signing anything, for its signatures to be verifiable after a restart
+        rotated.save(&key_path)?;
+        Ok(rotated)
+    }).await?;
+    let rotation = KeyRotation::new(old_public_key, rotated.public_key_pem()?, rotated.key_id()?);
+    *auth_lock.write().unwrap() = Arc::new(rotated);
+    tracing::info!("Signing key rotated");
+    Ok(rotation)
+}
+
+/// Rotate the signing key every `interval`
+async fn rotate_key_periodically(interval: chrono::Duration) {
+    loop {
+        let next_rotation = auth().created_at() + interval;
+        tokio::time::sleep((next_rotation - chrono::Utc::now()).to_std().unwrap_or_default()).await;
+        if let Err(err) = rotate_signing_key(auth_lock(), key_path()).await {
+            tracing::error!("Scheduled key rotation failed: {err}");
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        }
+    }
+}
+
+/// Rotate the signing key, also routed at `/admin/rotate_key`
+#[utoipa::path(post, path = "/rotate_key", params(("x-admin-token" = String, Header, description = "The admin token, `QRLEW_ADMIN_TOKEN`")), responses((status = 200, description = "OK", body = Response)))]
+async fn rotate_key(headers: HeaderMap) -> Result<Response> {
+    check_admin_token(&headers, env::var("QRLEW_ADMIN_TOKEN").ok().as_deref())?;
+    Ok(Response::new(serde_json::to_string(&rotate_signing_key(auth_lock(), key_path()).await?)?))
+}
+
+/// This document
+#[utoipa::path(get, path = "/openapi.json", responses((status = 200, description = "OK")))]
+async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(openapi_document())
+}
+
+/// The JSON Schema of the request body of a route
+#[utoipa::path(get, path = "/json_schema/{route}", params(("route" = String, Path, description = "A POST route, without its leading `/`")), responses((status = 200, description = "OK")))]
+async fn json_schema(Path(route): Path<String>) -> Result<Json<serde_json::Value>> {
+    Ok(Json(request::json_schema(&route)?))
+}
+
+/// A dot representation of the relation of a query
+#[utoipa::path(post, path = "/dot", request_body = request::Dot, responses((status = 200, description = "OK", body = Response)))]
+async fn dot(JsonRequest(dot_request): JsonRequest<request::Dot>) -> Result<Response> {
     dot_request.response()
 }
 
-async fn rewrite_as_privacy_unit_preserving(extract::Json(rewrite_as_privacy_unit_preserving_request): extract::Json<request::RewriteAsPrivacyUnitPreserving>) -> Result<Response> {
-    rewrite_as_privacy_unit_preserving_request.response()
+/// An SVG rendering of the relation of a query
+#[utoipa::path(post, path = "/dot.svg", request_body = request::Dot, responses((status = 200, description = "OK", body = String, content_type = "image/svg+xml")))]
+async fn dot_svg(JsonRequest(dot_request): JsonRequest<request::Dot>) -> Result<([(header::HeaderName, &'static str); 1], String)> {
+    Ok(([(header::CONTENT_TYPE, "image/svg+xml")], dot_request.response_svg()?))
 }
 
-async fn rewrite_with_differential_privacy(extract::Json(rewrite_with_differential_privacy_request): extract::Json<request::RewriteWithDifferentialPrivacy>) -> Result<Response> {
-    rewrite_with_differential_privacy_request.response(auth())
+/// The columns of each table read by a query
+#[utoipa::path(post, path = "/query_columns", request_body = request::QueryColumns, responses((status = 200, description = "OK", body = Response)))]
+async fn query_columns(JsonRequest(query_columns_request): JsonRequest<request::QueryColumns>) -> Result<Response> {
+    query_columns_request.response()
 }
 
-async fn rewrite_as_privacy_unit_preserving_with_dot(extract::Json(rewrite_as_privacy_unit_preserving_request_with_dot): extract::Json<request::RewriteAsPrivacyUnitPreservingWithDot>) -> Result<Response> {
-    rewrite_as_privacy_unit_preserving_request_with_dot.response()
+/// The schema of the relation of a query
+#[utoipa::path(post, path = "/parse", request_body = request::Parse, responses((status = 200, description = "OK", body = Response)))]
+async fn parse(JsonRequest(parse_request): JsonRequest<request::Parse>) -> Result<Response> {
+    parse_request.response()
 }
 
-async fn rewrite_with_differential_privacy_with_dot(extract::Json(rewrite_with_differential_privacy_request_with_dot): extract::Json<request::RewriteWithDifferentialPrivacyWithDot>) -> Result<Response> {
-    rewrite_with_differential_privacy_request_with_dot.response(auth())
+/// The schema uniting the outputs of queries
+#[utoipa::path(post, path = "/union_schema", request_body = request::UnionSchema, responses((status = 200, description = "OK", body = Response)))]
+async fn union_schema(JsonRequest(union_schema_request): JsonRequest<request::UnionSchema>) -> Result<Response> {
+    union_schema_request.response()
 }
 
-#[tokio::main]
-async fn main() {
-    // Setup tracing
-    tracing_subscriber::fmt()
-        .with_target(false)
-        .compact()
-        .init();
+/// The schema inferred from sample rows
+#[utoipa::path(post, path = "/infer_schema", request_body = request::InferSchema, responses((status = 200, description = "OK", body = Response)))]
+async fn infer_schema(JsonRequest(infer_schema_request): JsonRequest<request::InferSchema>) -> Result<Response> {
+    infer_schema_request.response()
+}
+
+/// Register a privacy unit, returning its token
+#[utoipa::path(post, path = "/register_privacy_unit", request_body = request::RegisterPrivacyUnit, responses((status = 200, description = "OK", body = Response)))]
+async fn register_privacy_unit(JsonRequest(register_privacy_unit_request): JsonRequest<request::RegisterPrivacyUnit>) -> Result<Response> {
+    register_privacy_unit_request.response()
+}
+
+/// The columns of each table gaining or losing protection between two privacy units
+#[utoipa::path(post, path = "/privacy_unit_diff", request_body = request::PrivacyUnitDiff, responses((status = 200, description = "OK", body = Response)))]
+async fn privacy_unit_diff(JsonRequest(privacy_unit_diff_request): JsonRequest<request::PrivacyUnitDiff>) -> Result<Response> {
+    privacy_unit_diff_request.response()
+}
+
+/// Check a query can be rewritten within a budget
+#[utoipa::path(post, path = "/check_budget", request_body = request::CheckBudget, responses((status = 200, description = "OK", body = Response)))]
+async fn check_budget(JsonRequest(check_budget_request): JsonRequest<request::CheckBudget>) -> Result<Response> {
+    check_budget_request.response()
+}
+
+/// Run a CPU heavy computation on the blocking thread pool, leaving the runtime free to serve other requests
+async fn blocking<T: Send + 'static>(computation: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+    tokio::task::spawn_blocking(computation).await.map_err(Error::other)?
+}
+
+/// Rewrite a query as privacy unit preserving
+#[utoipa::path(post, path = "/rewrite_as_privacy_unit_preserving", request_body = request::RewriteAsPrivacyUnitPreserving, responses((status = 200, description = "OK", body = Response)))]
+async fn rewrite_as_privacy_unit_preserving(JsonRequest(rewrite_as_privacy_unit_preserving_request): JsonRequest<request::RewriteAsPrivacyUnitPreserving>) -> Result<Response> {
+    blocking(move || rewrite_as_privacy_unit_preserving_request.response()).await
+}
+
+/// Rewrite a query with differential privacy
+#[utoipa::path(post, path = "/rewrite_with_differential_privacy", request_body = request::RewriteWithDifferentialPrivacy, responses((status = 200, description = "OK", body = Response)))]
+async fn rewrite_with_differential_privacy(JsonRequest(rewrite_with_differential_privacy_request): JsonRequest<request::RewriteWithDifferentialPrivacy>) -> Result<Response> {
+    let auth = auth();
+    blocking(move || rewrite_with_differential_privacy_request.response(&auth)).await
+}
+
+/// Rewrite queries with differential privacy
+#[utoipa::path(post, path = "/rewrite_with_differential_privacy/batch", request_body = request::RewriteWithDifferentialPrivacyBatch, responses((status = 200, description = "OK", body = Vec<request::BatchItem>)))]
+async fn rewrite_with_differential_privacy_batch(JsonRequest(rewrite_with_differential_privacy_batch_request): JsonRequest<request::RewriteWithDifferentialPrivacyBatch>) -> Result<Json<Vec<request::BatchItem>>> {
+    let auth = auth();
+    Ok(Json(blocking(move || rewrite_with_differential_privacy_batch_request.response(&auth)).await?))
+}
+
+/// Rewrite a query as privacy unit preserving and with differential privacy
+#[utoipa::path(post, path = "/rewrite", request_body = request::Rewrite, responses((status = 200, description = "OK", body = request::Rewrites)))]
+async fn rewrite(JsonRequest(rewrite_request): JsonRequest<request::Rewrite>) -> Result<Json<request::Rewrites>> {
+    let auth = auth();
+    Ok(Json(blocking(move || rewrite_request.response(&auth)).await?))
+}
+
+/// Send the query and the dot as multipart/mixed parts when the client accepts it, as JSON otherwise (the signature is only sent in JSON)
+fn with_dot_response(headers: &HeaderMap, response: Response) -> Result<axum::response::Response> {
+    let accepts_multipart = headers.get(header::ACCEPT).and_then(|accept| accept.to_str().ok()).is_some_and(|accept| accept.contains("multipart/mixed"));
+    Ok(if accepts_multipart {
+        serde_json::from_str::<request::QueryWithDot>(response.value())?.multipart()
+    } else {
+        response.into_response()
+    })
+}
+
+/// Compress responses with gzip, deflate or brotli for clients sending `Accept-Encoding`, they are streamed in chunks and signed before compression
+fn compression_layer() -> CompressionLayer<DefaultPredicate> {
+    CompressionLayer::new().no_zstd()
+}
+
+/// Rewrite a query as privacy unit preserving, with a dot representation
+#[utoipa::path(post, path = "/rewrite_as_privacy_unit_preserving_with_dot", request_body = request::RewriteAsPrivacyUnitPreservingWithDot, responses((status = 200, description = "OK", body = Response)))]
+async fn rewrite_as_privacy_unit_preserving_with_dot(headers: HeaderMap, JsonRequest(rewrite_as_privacy_unit_preserving_request_with_dot): JsonRequest<request::RewriteAsPrivacyUnitPreservingWithDot>) -> Result<axum::response::Response> {
+    with_dot_response(&headers, blocking(move || rewrite_as_privacy_unit_preserving_request_with_dot.response()).await?)
+}
+
+/// Rewrite a query with differential privacy, with a dot representation
+#[utoipa::path(post, path = "/rewrite_with_differential_privacy_with_dot", request_body = request::RewriteWithDifferentialPrivacyWithDot, responses((status = 200, description = "OK", body = Response)))]
+async fn rewrite_with_differential_privacy_with_dot(headers: HeaderMap, JsonRequest(rewrite_with_differential_privacy_request_with_dot): JsonRequest<request::RewriteWithDifferentialPrivacyWithDot>) -> Result<axum::response::Response> {
+    let auth = auth();
+    with_dot_response(&headers, blocking(move || rewrite_with_differential_privacy_request_with_dot.response(&auth)).await?)
+}
+
+/// The maximum size of request bodies by default (16 MiB)
+const DEFAULT_MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+/// The maximum size of request bodies, `QRLEW_MAX_BODY_BYTES` or 16 MiB
+fn max_body_bytes() -> usize {
+    env::var("QRLEW_MAX_BODY_BYTES").ok().and_then(|max| max.parse().ok()).unwrap_or(DEFAULT_MAX_BODY_BYTES)
+}
+
+/// The OpenAPI document of the routes, with the schemas of their request and response bodies
+#[derive(utoipa::OpenApi)]
+#[openapi(
+    info(title = "Qrlew server"),
+    paths(
+        index, version, get_stats, public_key, public_key_jwk, verify, rotate_key, json_schema, openapi,
+        dot, dot_svg, query_columns, union_schema, parse, infer_schema, register_privacy_unit, privacy_unit_diff, check_budget,
+        rewrite_as_privacy_unit_preserving, rewrite_with_differential_privacy, rewrite_with_differential_privacy_batch, rewrite,
+        rewrite_as_privacy_unit_preserving_with_dot, rewrite_with_differential_privacy_with_dot,
+        health, ready, metrics,
+    ),
+    modifiers(&ErrorResponses),
+)]
+struct ApiDoc;
+
+/// Document the error responses shared by all routes
+struct ErrorResponses;
 
-    // build our application with a single route
+impl utoipa::Modify for ErrorResponses {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let error = utoipa::openapi::Response::new("An error, as `{\"error\": <tag>, \"message\": <message>}`");
+        for path_item in openapi.paths.paths.values_mut() {
+            for operation in [&mut path_item.get, &mut path_item.post].into_iter().flatten() {
+                operation.responses.responses.insert("default".to_string(), error.clone().into());
+            }
+        }
+    }
+}
+
+/// The OpenAPI document, with `/admin/rotate_key` documented as `/rotate_key`
+fn openapi_document() -> utoipa::openapi::OpenApi {
+    let mut openapi = <ApiDoc as utoipa::OpenApi>::openapi();
+    if let Some(rotate_key) = openapi.paths.paths.get("/rotate_key").cloned() {
+        openapi.paths.paths.insert("/admin/rotate_key".to_string(), rotate_key);
+    }
+    openapi
+}
+
+/// Build the application router
+fn app() -> Router {
+    // Metrics are recorded from the first request on
+    prometheus::handle();
     let app = Router::new()
-        .route("/", get(|| async { format!("This is Qrlew server {}", env!("CARGO_PKG_VERSION"))}))
+        .route("/", get(index))
+        .route("/version", get(version))
+        .route("/stats", get(get_stats))
         .route("/public_key", get(public_key))
+        .route("/public_key.jwk", get(public_key_jwk))
         .route("/verify", post(verify))
+        .route("/rotate_key", post(rotate_key))
+        .route("/admin/rotate_key", post(rotate_key))
+        .route("/json_schema/:route", get(json_schema))
+        .route("/openapi.json", get(openapi))
         .route("/dot", post(dot))
+        .route("/dot.svg", post(dot_svg))
+        .route("/query_columns", post(query_columns))
+        .route("/union_schema", post(union_schema))
+        .route("/parse", post(parse))
+        .route("/infer_schema", post(infer_schema))
+        .route("/register_privacy_unit", post(register_privacy_unit))
+        .route("/privacy_unit_diff", post(privacy_unit_diff))
+        .route("/check_budget", post(check_budget))
         .route("/rewrite_as_privacy_unit_preserving", post(rewrite_as_privacy_unit_preserving))
         .route("/rewrite_with_differential_privacy", post(rewrite_with_differential_privacy))
+        .route("/rewrite_with_differential_privacy/batch", post(rewrite_with_differential_privacy_batch))
+        .route("/rewrite", post(rewrite))
         .route("/rewrite_as_privacy_unit_preserving_with_dot", post(rewrite_as_privacy_unit_preserving_with_dot))
         .route("/rewrite_with_differential_privacy_with_dot", post(rewrite_with_differential_privacy_with_dot))
+        .layer(middleware::from_fn(limit_dataset_rate))
+        .layer(DefaultBodyLimit::max(max_body_bytes()))
+        .layer(middleware::from_fn_with_state(request_timeout(), limit_duration))
+        .layer(middleware::from_fn(count_requests))
+        .layer(middleware::from_fn(tag_responses))
+        .layer(compression_layer())
         .layer(
             TraceLayer::new_for_http()
-                .make_span_with(trace::DefaultMakeSpan::new()
-                    .level(Level::INFO))
+                .make_span_with(request_span)
                 .on_response(trace::DefaultOnResponse::new()
                     .level(Level::INFO)),
                 )
-        .layer(
-            CorsLayer::permissive()
-        );
-    
+        .layer(middleware::from_fn(with_request_id))
+        // Probes and metrics are routed past the middlewares, not to be counted, limited or authenticated
+        .route("/health", get(health))
+        .route("/ready", get(ready))
+        .route("/metrics", get(metrics));
+    // CORS may be handled by a gateway in front of the server
+    if env_flag("QRLEW_DISABLE_CORS") {
+        tracing::info!("CORS layer disabled");
+        app
+    } else {
+        app.layer(cors_layer(env::var("QRLEW_CORS_ALLOWED_ORIGINS").ok().as_deref()))
+    }
+}
+
+/// Allow cross-origin GET and POST requests from a comma-separated list of origins, from any origin when it is empty
+fn cors_layer(allowed_origins: Option<&str>) -> CorsLayer {
+    let origins: Vec<HeaderValue> = allowed_origins.unwrap_or_default().split(',').map(str::trim).filter(|origin| !origin.is_empty())
+        .filter_map(|origin| HeaderValue::from_str(origin).map_err(|err| tracing::warn!("Ignoring the CORS origin {origin}: {err}")).ok())
+        .collect();
+    if origins.is_empty() {
+        tracing::warn!("QRLEW_CORS_ALLOWED_ORIGINS is empty, cross-origin requests are allowed from any origin");
+        return CorsLayer::permissive();
+    }
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::list(origins))
+        .allow_methods([Method::GET, Method::POST])
+        .allow_headers([header::CONTENT_TYPE])
+}
+
+/// How long in-flight requests may run after a shutdown signal by default, in seconds
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+
+/// How long in-flight requests may run after a shutdown signal, `QRLEW_SHUTDOWN_TIMEOUT_SECS` or 30 seconds
+fn shutdown_timeout() -> Duration {
+    Duration::from_secs(env::var("QRLEW_SHUTDOWN_TIMEOUT_SECS").ok().and_then(|timeout| timeout.parse().ok()).unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECS))
+}
+
+/// Resolve on SIGINT or SIGTERM
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            tracing::error!("Cannot listen to SIGINT: {err}");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => { terminate.recv().await; },
+            Err(err) => {
+                tracing::error!("Cannot listen to SIGTERM: {err}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
+    }
+}
+
+/// Serve the application until `shutdown` resolves, then stop accepting connections and let in-flight requests finish within `deadline`
+async fn serve(listener: tokio::net::TcpListener, app: Router, shutdown: impl Future<Output = ()> + Send + 'static, deadline: Duration) -> io::Result<()> {
+    let (shutting_down, mut shutdown_started) = tokio::sync::watch::channel(false);
+    let server = axum::serve(listener, app)
+        .with_graceful_shutdown(async move {
+            shutdown.await;
+            tracing::info!("shutting down");
+            shutting_down.send_replace(true);
+        })
+        .into_future();
+    let expired = async move {
+        let _ = shutdown_started.wait_for(|started| *started).await;
+        tokio::time::sleep(deadline).await;
+    };
+    tokio::select! {
+        result = server => result,
+        _ = expired => {
+            tracing::warn!("In-flight requests did not finish within {}s, exiting", deadline.as_secs_f64());
+            Ok(())
+        },
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    // Setup tracing
+    tracing_subscriber::fmt()
+        .with_target(false)
+        .compact()
+        .init();
+
+    // build our application
+    let app = app();
+
     // load authenticator
-    auth();
+    if let Err(err) = key_algorithm() {
+        tracing::error!("Invalid QRLEW_KEY_ALGORITHM: {err}");
+        process::exit(1);
+    }
+    if let Err(err) = key_bits() {
+        tracing::error!("Invalid QRLEW_KEY_BITS: {err}");
+        process::exit(1);
+    }
+    if let Err(err) = historical_verifying_keys() {
+        tracing::error!("Invalid QRLEW_VERIFYING_KEYS: {err}");
+        process::exit(1);
+    }
+    auth_lock();
+    if let Some(interval) = key_rotation_interval() {
+        tracing::info!("Rotating the signing key every {} hours", interval.num_hours());
+        tokio::spawn(rotate_key_periodically(interval));
+    }
+
+    // resolve synthetic tables
+    if let Ok(template) = env::var("QRLEW_SYNTHETIC_TABLE_TEMPLATE") {
+        tracing::info!("Resolving synthetic tables as {template}");
+        request::set_synthetic_table_resolver(synthetic_table_template_resolver(template)).unwrap();
+    }
+
+    // run it with hyper on the bind address
+    let bind_addr = match bind_addr(env::var("QRLEW_BIND_ADDR").ok().as_deref()) {
+        Ok(bind_addr) => bind_addr,
+        Err(err) => {
+            tracing::error!("Invalid QRLEW_BIND_ADDR: {err}");
+            process::exit(1);
+        }
+    };
+    let listener = match tokio::net::TcpListener::bind(bind_addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            tracing::error!("Cannot bind {bind_addr}: {err}");
+            process::exit(1);
+        }
+    };
+    tracing::info!("listening on {}", listener.local_addr().unwrap_or(bind_addr));
+    if let Err(err) = serve(listener, app, shutdown_signal(), shutdown_timeout()).await {
+        tracing::error!("Server error: {err}");
+        process::exit(1);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::{self, Body}, http::Request};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_empty_body() {
+        let response = app()
+            .oneshot(Request::post("/dot").header("content-type", "application/json").body(Body::from(" \n")).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        let body = String::from_utf8(body::to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+        println!("{body}");
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["error"], "InvalidRequest");
+        let message = body["message"].as_str().unwrap();
+        assert!(message.contains("request body is empty"));
+        assert!(message.contains("dataset, query, dark_mode"));
+        assert!(body.get("aggregations").is_none());
+    }
+
+    #[test]
+    fn test_error_status() {
+        for (error, status) in [
+            (Error::invalid_request("bad"), StatusCode::BAD_REQUEST),
+            (Error::invalid_sql("bad"), StatusCode::BAD_REQUEST),
+            (Error::unsupported_aggregations("bad", vec![]), StatusCode::UNPROCESSABLE_ENTITY),
+            (Error::unauthorized("bad"), StatusCode::UNAUTHORIZED),
+            (Error::too_many_requests("bad"), StatusCode::TOO_MANY_REQUESTS),
+            (Error::payload_too_large("bad"), StatusCode::PAYLOAD_TOO_LARGE),
+            (Error::timeout("bad"), StatusCode::GATEWAY_TIMEOUT),
+            (Error::other("bad"), StatusCode::INTERNAL_SERVER_ERROR),
+        ] {
+            let message = error.to_string();
+            let response = error.into_response();
+            println!("{} {message}", response.status());
+            assert_eq!(response.status(), status);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_openapi() {
+        let response = app().oneshot(Request::get("/openapi.json").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let openapi: serde_json::Value = serde_json::from_slice(&body::to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        println!("{}", serde_json::to_string_pretty(&openapi).unwrap());
+        assert!(openapi["openapi"].as_str().unwrap().starts_with("3."));
+        let schemas = openapi["components"]["schemas"].as_object().unwrap();
+        for name in ["Dot", "RewriteAsPrivacyUnitPreserving", "RewriteWithDifferentialPrivacy", "Response", "Dataset"] {
+            assert!(schemas.contains_key(name), "{name}");
+        }
+        let rewrite = &openapi["paths"]["/rewrite_with_differential_privacy"]["post"];
+        assert_eq!(rewrite["requestBody"]["content"]["application/json"]["schema"]["$ref"], "#/components/schemas/RewriteWithDifferentialPrivacy");
+        assert_eq!(rewrite["responses"]["200"]["content"]["application/json"]["schema"]["$ref"], "#/components/schemas/Response");
+        assert!(rewrite["responses"]["default"].is_object());
+        assert!(openapi["paths"]["/"]["get"].is_object());
+        assert!(openapi["paths"]["/admin/rotate_key"]["post"].is_object());
+        assert!(openapi["paths"]["/rotate_key"]["post"].is_object());
+        // All references resolve
+        let document = openapi.to_string();
+        for reference in document.split(r#""$ref":""#).skip(1) {
+            let name = reference.split('"').next().unwrap().trim_start_matches("#/components/schemas/");
+            assert!(schemas.contains_key(name), "{name}");
+        }
+        // All documented operations are routed
+        let paths = openapi["paths"].as_object().unwrap();
+        assert_eq!(paths.len(), 28);
+        for (path, item) in paths {
+            for method in item.as_object().unwrap().keys() {
+                let uri = path.replace("{route}", "dot");
+                let request = Request::builder().method(method.to_uppercase().as_str()).uri(&uri).body(Body::empty()).unwrap();
+                let status = app().oneshot(request).await.unwrap().status();
+                assert!(status != StatusCode::NOT_FOUND && status != StatusCode::METHOD_NOT_ALLOWED, "{method} {path}: {status}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_key_path() {
+        let path = env::temp_dir().join(format!("qrlew_server_test_key_path_{}.pem", std::process::id()));
+        let configured_path = key_path_from(Some(path.to_str().unwrap().to_string()));
+        assert_eq!(configured_path, path.to_str().unwrap());
+        assert_eq!(key_path_from(None), DEFAULT_KEY_PATH);
+        let auth = Authenticator::get(&configured_path).unwrap();
+        let loaded = Authenticator::try_load(&configured_path).unwrap();
+        assert_eq!(auth.public_key_pem().unwrap(), loaded.public_key_pem().unwrap());
+        assert!(loaded.verify("text", &auth.sign("text")).is_ok());
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(format!("{configured_path}.lock"));
+    }
+
+    #[test]
+    fn test_bind_addr() {
+        assert_eq!(bind_addr(None).unwrap(), "0.0.0.0:3000".parse::<SocketAddr>().unwrap());
+        assert_eq!(bind_addr(Some("127.0.0.1:8080")).unwrap().port(), 8080);
+        assert!(bind_addr(Some("[::1]:3001")).unwrap().is_ipv6());
+        let err = bind_addr(Some("localhost")).unwrap_err();
+        println!("{err}");
+        assert!(err.contains("`localhost`"));
+    }
+
+    #[test]
+    fn test_dataset_key() {
+        let key = dataset_key(br#"{"dataset":{"tables":[]},"query":"SELECT 1"}"#).unwrap();
+        println!("{key}");
+        assert_eq!(dataset_key(br#"{"query":"SELECT 2","dataset":{"tables":[]}}"#), Some(key));
+        assert_ne!(dataset_key(br#"{"dataset":{"tables":[{}]}}"#), dataset_key(br#"{"dataset":{"tables":[]}}"#));
+        assert_eq!(dataset_key(br#"{"value":"SELECT 1"}"#), None);
+    }
+
+    #[test]
+    fn test_synthetic_table_template_resolver() {
+        let resolver = synthetic_table_template_resolver("latest_{table}_sd".to_string());
+        assert_eq!(resolver(&["schema".to_string(), "user_table".to_string()]), Some(vec!["schema".to_string(), "latest_user_table_sd".to_string()]));
+        assert_eq!(resolver(&[]), None);
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let request = || Request::post("/verify").header("content-type", "application/json").body(Body::from("{}")).unwrap();
+        app().oneshot(request()).await.unwrap();
+        app().oneshot(request()).await.unwrap();
+        let response = app().oneshot(Request::get("/stats").body(Body::empty()).unwrap()).await.unwrap();
+        let stats: serde_json::Value = serde_json::from_slice(&body::to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        println!("{stats}");
+        assert!(stats["/verify"]["failures"].as_u64().unwrap() >= 2);
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let request_str = r#"{"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"age","data_type":"Integer","range":[0,100]}]},"size":100}]},"query":"SELECT sum(age) AS total FROM user_table","synthetic_data":[],"privacy_unit":[["user_table",[],"id"]],"epsilon":1.0,"delta":0.00001}"#;
+        let response = app()
+            .oneshot(Request::post("/rewrite_with_differential_privacy").header("content-type", "application/json").body(Body::from(request_str)).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app().oneshot(Request::get("/metrics").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/plain; version=0.0.4");
+        let body = String::from_utf8(body::to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+        println!("{body}");
+        assert!(body.contains("qrlew_requests_total{route=\"/rewrite_with_differential_privacy\"}"));
+        assert!(body.contains("qrlew_request_duration_seconds_count{route=\"/rewrite_with_differential_privacy\"}"));
+        assert!(!body.contains("qrlew_signatures_total 0\n"));
+        // Metrics are not counted
+        assert!(!body.contains("route=\"/metrics\""));
+    }
+
+    #[tokio::test]
+    async fn test_tag_responses() {
+        let request_str = r#"{"dataset":{"tables":[{"name":"table_1","path":["schema","table_1"],"schema":{"fields":[{"name":"a","data_type":"Float"}]},"size":10000}]},"query":"SELECT * FROM table_1","dark_mode":false}"#;
+        let response = app()
+            .oneshot(Request::post("/dot").header("content-type", "application/json").header(REQUEST_ID_HEADER, "request-42").body(Body::from(request_str)).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[REQUEST_ID_HEADER], "request-42");
+        let response: Response = serde_json::from_slice(&body::to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(response.request_id(), Some("request-42"));
+        assert_eq!(response.route(), Some("/dot"));
+        // An id is generated when the client sends none
+        let response = app()
+            .oneshot(Request::post("/dot").header("content-type", "application/json").body(Body::from(request_str)).unwrap())
+            .await
+            .unwrap();
+        let request_id = response.headers()[REQUEST_ID_HEADER].to_str().unwrap().to_string();
+        assert!(uuid::Uuid::parse_str(&request_id).is_ok());
+        let response: Response = serde_json::from_slice(&body::to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(response.request_id(), Some(request_id.as_str()));
+        // Overlong ids are replaced
+        let response = app()
+            .oneshot(Request::post("/dot").header("content-type", "application/json").header(REQUEST_ID_HEADER, "x".repeat(MAX_REQUEST_ID_LENGTH + 1)).body(Body::from(request_str)).unwrap())
+            .await
+            .unwrap();
+        let request_id = response.headers()[REQUEST_ID_HEADER].to_str().unwrap().to_string();
+        println!("{request_id}");
+        assert!(uuid::Uuid::parse_str(&request_id).is_ok());
+    }
+
+    /// Collects the formatted log lines
+    #[derive(Clone, Default)]
+    struct Logs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
 
-    // run it with hyper on localhost:3000
-    tracing::info!("listening on 0.0.0.0:3000");
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    #[tokio::test]
+    async fn test_request_id_in_logs() {
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::fmt().with_writer(move || writer.clone()).with_ansi(false).finish());
+        let request_str = r#"{"dataset":{"tables":[{"name":"table_1","path":["schema","table_1"],"schema":{"fields":[{"name":"a","data_type":"Float"}]},"size":10000}]},"query":"SELECT * FROM table_1","dark_mode":false}"#;
+        let response = app()
+            .oneshot(Request::post("/dot").header("content-type", "application/json").header(REQUEST_ID_HEADER, "request-43").body(Body::from(request_str)).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[REQUEST_ID_HEADER], "request-43");
+        // Generated ids are logged too
+        let response = app().oneshot(Request::post("/verify").header("content-type", "application/json").body(Body::from("{}")).unwrap()).await.unwrap();
+        let request_id = response.headers()[REQUEST_ID_HEADER].to_str().unwrap().to_string();
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        println!("{logs}");
+        assert!(logs.lines().any(|line| line.contains("request_id=request-43") && line.contains("finished processing request")));
+        assert!(logs.lines().any(|line| line.contains(&format!("request_id={request_id}"))));
+    }
+
+    #[tokio::test]
+    async fn test_version() {
+        let response = app().oneshot(Request::get("/version").body(Body::empty()).unwrap()).await.unwrap();
+        let body = String::from_utf8(body::to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+        println!("{body}");
+        let version: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(version["server_version"], env!("CARGO_PKG_VERSION"));
+        assert!(version["qrlew_version"].as_str().unwrap().starts_with("0.9."));
+    }
+
+    #[tokio::test]
+    async fn test_probes() {
+        let get = |uri: &'static str| async move {
+            let response = app().oneshot(Request::get(uri).body(Body::empty()).unwrap()).await.unwrap();
+            let status = response.status();
+            let body = String::from_utf8(body::to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+            println!("{uri} {status} {body}");
+            (status, body)
+        };
+        assert_eq!(get("/health").await, (StatusCode::OK, r#"{"status":"ok"}"#.to_string()));
+        auth_lock();
+        assert_eq!(get("/ready").await, (StatusCode::OK, r#"{"status":"ready"}"#.to_string()));
+        assert_eq!(readiness(false).0, StatusCode::SERVICE_UNAVAILABLE);
+        // Probes are not counted
+        assert!(!stats().snapshot().contains_key("/health"));
+    }
+
+    #[tokio::test]
+    async fn test_body_limit() {
+        let post = |body: Vec<u8>| async move {
+            let response = app().oneshot(Request::post("/dot").header("content-type", "application/json").body(Body::from(body)).unwrap()).await.unwrap();
+            let status = response.status();
+            let body = String::from_utf8(body::to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+            println!("{status} {body}");
+            (status, body)
+        };
+        // Oversized bodies are rejected before being deserialized, they are not even valid JSON
+        let (status, body) = post(vec![b'{'; DEFAULT_MAX_BODY_BYTES + 1]).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(body.contains("PayloadTooLarge"));
+        // Bodies within the limit are deserialized
+        let (status, body) = post(vec![b'{'; DEFAULT_MAX_BODY_BYTES]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("InvalidRequest"));
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        let app = Router::new()
+            .route("/fast", get(|| async { "fast" }))
+            .route("/slow", get(|| async { blocking(|| { std::thread::sleep(Duration::from_secs(1)); Ok("slow") }).await }))
+            .layer(middleware::from_fn_with_state(Duration::from_millis(200), limit_duration));
+        let response = app.clone().oneshot(Request::get("/fast").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let start = std::time::Instant::now();
+        let response = app.oneshot(Request::get("/slow").body(Body::empty()).unwrap()).await.unwrap();
+        let status = response.status();
+        let body = String::from_utf8(body::to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+        println!("{status} {body} after {:?}", start.elapsed());
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        assert!(body.contains("Timeout"));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(request_timeout(), Duration::from_secs(DEFAULT_REQUEST_TIMEOUT_SECS));
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (signal, shutdown) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, app(), async { let _ = shutdown.await; }, Duration::from_secs(1)));
+        signal.send(()).unwrap();
+        let result = tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap();
+        assert!(result.is_ok());
+        assert_eq!(shutdown_timeout(), Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT_SECS));
+    }
+
+    #[tokio::test]
+    async fn test_cors_layer() {
+        let allow_origin = |cors: CorsLayer, origin: &'static str| async move {
+            let app = Router::new().route("/", get(|| async { "ok" })).layer(cors);
+            let response = app.oneshot(Request::get("/").header(header::ORIGIN, origin).body(Body::empty()).unwrap()).await.unwrap();
+            let allow_origin = response.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).map(|value| value.to_str().unwrap().to_string());
+            println!("{origin} {allow_origin:?}");
+            allow_origin
+        };
+        let allowed_origins = Some("https://allowed.example, https://other.example");
+        assert_eq!(allow_origin(cors_layer(allowed_origins), "https://allowed.example").await.as_deref(), Some("https://allowed.example"));
+        assert_eq!(allow_origin(cors_layer(allowed_origins), "https://other.example").await.as_deref(), Some("https://other.example"));
+        assert_eq!(allow_origin(cors_layer(allowed_origins), "https://disallowed.example").await, None);
+        // Permissive without allowed origins
+        assert_eq!(allow_origin(cors_layer(None), "https://disallowed.example").await.as_deref(), Some("*"));
+        assert_eq!(allow_origin(cors_layer(Some(" , ")), "https://disallowed.example").await.as_deref(), Some("*"));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_blocking() {
+        let rewrite = |query: String| async move {
+            let request_str = format!(r#"{{"dataset":{{"tables":[{{"name":"user_table","path":["schema","user_table"],"schema":{{"fields":[{{"name":"id","data_type":"Integer"}},{{"name":"age","data_type":"Integer","range":[0,100]}}]}},"size":100}}]}},"query":"{query}","synthetic_data":[],"privacy_unit":[["user_table",[],"id"]],"epsilon":1.0,"delta":0.00001}}"#);
+            let response = app()
+                .oneshot(Request::post("/rewrite_with_differential_privacy").header("content-type", "application/json").body(Body::from(request_str)).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            std::time::Instant::now()
+        };
+        // Many aggregations make a slow rewrite
+        let slow_query = format!("SELECT {} FROM user_table", (0..30).map(|i| format!("sum(age + {i}) AS s{i}")).collect::<Vec<_>>().join(", "));
+        let start = std::time::Instant::now();
+        let slow = tokio::spawn(rewrite(slow_query));
+        // A slow rewrite blocking the single runtime thread would also delay this sleep
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let fast = tokio::spawn(rewrite("SELECT sum(age) AS total FROM user_table".to_string()));
+        let (slow_end, fast_end) = (slow.await.unwrap(), fast.await.unwrap());
+        println!("slow rewrite served after {:?}, fast rewrite after {:?}", slow_end - start, fast_end - start);
+        // The single runtime thread keeps serving requests during the slow rewrite
+        assert!(fast_end < slow_end);
+        // Errors are propagated
+        assert!(matches!(blocking::<()>(|| Err(Error::invalid_request("invalid"))).await, Err(Error::InvalidRequest(_))));
+    }
+
+    #[tokio::test]
+    async fn test_rotate_key_unauthorized() {
+        // QRLEW_ADMIN_TOKEN is not set in tests, disabling key rotations
+        for route in ["/rotate_key", "/admin/rotate_key"] {
+            let response = app().oneshot(Request::post(route).header(ADMIN_TOKEN_HEADER, "token").body(Body::empty()).unwrap()).await.unwrap();
+            let status = response.status();
+            let body = String::from_utf8(body::to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+            println!("{route}: {status} {body}");
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap()["error"], "Unauthorized");
+        }
+        // Nor without token
+        let response = app().oneshot(Request::post("/rotate_key").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn test_check_admin_token() {
+        let headers = |token: &'static str| HeaderMap::from_iter([(header::HeaderName::from_static(ADMIN_TOKEN_HEADER), HeaderValue::from_static(token))]);
+        assert!(check_admin_token(&headers("secret"), Some("secret")).is_ok());
+        assert!(matches!(check_admin_token(&headers("guess"), Some("secret")), Err(Error::Unauthorized(_))));
+        assert!(matches!(check_admin_token(&HeaderMap::new(), Some("secret")), Err(Error::Unauthorized(_))));
+        // Admin routes are disabled without token, or with an empty one
+        assert!(matches!(check_admin_token(&headers("secret"), None), Err(Error::Unauthorized(_))));
+        assert!(matches!(check_admin_token(&headers(""), Some("")), Err(Error::Unauthorized(_))));
+    }
+
+    #[tokio::test]
+    async fn test_rotate_signing_key() {
+        let path = env::temp_dir().join(format!("qrlew_server_test_rotate_signing_key_{}.pem", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let lock = RwLock::new(Arc::new(Authenticator::random(Algorithm::Ed25519, auth::DEFAULT_RSA_KEY_BITS).unwrap()));
+        let before = lock.read().unwrap().clone();
+        let signature = before.sign("text");
+        let rotation = rotate_signing_key(&lock, path.clone()).await.unwrap();
+        println!("{}", serde_json::to_string(&rotation).unwrap());
+        let after = lock.read().unwrap().clone();
+        assert_ne!(after.key_id().unwrap(), before.key_id().unwrap());
+        // The previous key stays accepted, and the snapshot taken before the rotation is left as is
+        assert!(after.verify("text", &signature).is_ok());
+        assert!(before.verify("text", &before.sign("text")).is_ok());
+        // The new key is on disk
+        assert_eq!(Authenticator::try_load(&path).unwrap().key_id().unwrap(), after.key_id().unwrap());
+        // Nothing is swapped in when the key cannot be saved
+        let missing_dir = env::temp_dir().join("qrlew_server_missing_dir").join("key.pem");
+        assert!(rotate_signing_key(&lock, missing_dir.to_str().unwrap().to_string()).await.is_err());
+        assert_eq!(lock.read().unwrap().key_id().unwrap(), after.key_id().unwrap());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_verify() {
+        let verify = |response: String| async move {
+            let response = app()
+                .oneshot(Request::post("/verify").header("content-type", "application/json").body(Body::from(response)).unwrap())
+                .await
+                .unwrap();
+            let status = response.status();
+            let body = String::from_utf8(body::to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+            println!("{status} {body}");
+            (status, serde_json::from_str::<serde_json::Value>(&body).unwrap())
+        };
+        let signed = Response::signed("SELECT 1".to_string(), &auth());
+        let (status, body) = verify(serde_json::to_string(&signed).unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!({"verified": true}));
+        let mut altered = serde_json::to_value(&signed).unwrap();
+        altered["value"] = "SELECT 2".into();
+        let (status, body) = verify(altered.to_string()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!({"verified": false}));
+        // The key id selects the verifying key
+        let mut unknown_key = serde_json::to_value(&signed).unwrap();
+        unknown_key["key_id"] = "unknown".into();
+        let (status, body) = verify(unknown_key.to_string()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!({"verified": false}));
+        let mut without_key_id = serde_json::to_value(&signed).unwrap();
+        without_key_id.as_object_mut().unwrap().remove("key_id");
+        let (_, body) = verify(without_key_id.to_string()).await;
+        assert_eq!(body, serde_json::json!({"verified": true}));
+        let (status, body) = verify(serde_json::to_string(&Response::new("SELECT 1".to_string())).unwrap()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "InvalidRequest");
+    }
+
+    #[tokio::test]
+    async fn test_public_key_jwk() {
+        let response = app().oneshot(Request::get("/public_key.jwk").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = String::from_utf8(body::to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+        println!("{body}");
+        let jwk: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(jwk["use"], "sig");
+        assert_eq!(jwk, serde_json::to_value(auth().verifying_key_jwk().unwrap()).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_public_key() {
+        let response = app().oneshot(Request::get("/public_key").body(Body::empty()).unwrap()).await.unwrap();
+        let created_at = response.headers()[KEY_CREATED_AT_HEADER].to_str().unwrap().to_string();
+        println!("{created_at}");
+        assert!(chrono::DateTime::parse_from_rfc3339(&created_at).is_ok());
+        assert_eq!(response.headers()[KEY_ALGORITHM_HEADER], auth().algorithm().to_string());
+        assert_eq!(response.headers()[KEY_ID_HEADER], auth().key_id().unwrap());
+        let body = String::from_utf8(body::to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+        assert!(body.starts_with("-----BEGIN PUBLIC KEY-----"));
+    }
+
+    #[tokio::test]
+    async fn test_multipart_with_dot() {
+        let request_str = r#"{"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"name","data_type":"Text"}]},"size":10000}]},"query":"SELECT * FROM user_table","synthetic_data":[],"privacy_unit":[["user_table",[],"id"]],"epsilon":1.0,"delta":0.00001,"dark_mode":false}"#;
+        let response = app()
+            .oneshot(Request::post("/rewrite_as_privacy_unit_preserving_with_dot").header("content-type", "application/json").header("accept", "multipart/mixed").body(Body::from(request_str)).unwrap())
+            .await
+            .unwrap();
+        let content_type = response.headers()[header::CONTENT_TYPE].to_str().unwrap().to_string();
+        assert!(content_type.starts_with("multipart/mixed; boundary="));
+        let body = String::from_utf8(body::to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+        println!("{body}");
+        assert!(body.contains("Content-Type: application/sql\r\n\r\nWITH"));
+        assert!(body.contains("Content-Type: text/vnd.graphviz\r\n\r\ndigraph"));
+        // JSON remains the default
+        let response = app()
+            .oneshot(Request::post("/rewrite_as_privacy_unit_preserving_with_dot").header("content-type", "application/json").body(Body::from(request_str)).unwrap())
+            .await
+            .unwrap();
+        let response: Response = serde_json::from_slice(&body::to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert!(serde_json::from_str::<request::QueryWithDot>(response.value()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_gzip_with_dot() {
+        use std::io::Read as _;
+        let request_str = r#"{"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"name","data_type":"Text"}]},"size":10000}]},"query":"SELECT count(id) FROM user_table","synthetic_data":[],"privacy_unit":[["user_table",[],"id"]],"epsilon":1.0,"delta":0.00001,"dark_mode":false}"#;
+        let response = app()
+            .oneshot(Request::post("/rewrite_with_differential_privacy_with_dot").header("content-type", "application/json").header("accept-encoding", "gzip").body(Body::from(request_str)).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert!(response.headers().get(header::CONTENT_LENGTH).is_none());
+        let compressed = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(&compressed[..]).read_to_string(&mut decompressed).unwrap();
+        println!("{} bytes compressed into {}", decompressed.len(), compressed.len());
+        // The signature is checked on the decompressed response
+        let response: Response = serde_json::from_str(&decompressed).unwrap();
+        auth().verify(&response.signed_payload(), response.signature().unwrap()).expect("OK");
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        use std::io::Read as _;
+        let fields: Vec<String> = (0..100).map(|i| format!(r#"{{"name":"column_{i}","data_type":"Float"}}"#)).collect();
+        let request_str = format!(r#"{{"dataset":{{"tables":[{{"name":"table_1","path":["schema","table_1"],"schema":{{"fields":[{}]}},"size":10000}}]}},"query":"SELECT * FROM table_1","dark_mode":false}}"#, fields.join(","));
+        let dot = |accept_encoding: Option<&'static str>| {
+            let request_str = request_str.clone();
+            async move {
+                let mut request = Request::post("/dot").header("content-type", "application/json").header(REQUEST_ID_HEADER, "request-44");
+                if let Some(accept_encoding) = accept_encoding {
+                    request = request.header(header::ACCEPT_ENCODING, accept_encoding);
+                }
+                let response = app().oneshot(request.body(Body::from(request_str)).unwrap()).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let content_encoding = response.headers().get(header::CONTENT_ENCODING).map(|encoding| encoding.to_str().unwrap().to_string());
+                (content_encoding, body::to_bytes(response.into_body(), usize::MAX).await.unwrap())
+            }
+        };
+        let (content_encoding, plain) = dot(None).await;
+        assert_eq!(content_encoding, None);
+        let (content_encoding, compressed) = dot(Some("gzip")).await;
+        assert_eq!(content_encoding.as_deref(), Some("gzip"));
+        let mut decompressed = Vec::new();
+        flate2::read::GzDecoder::new(&compressed[..]).read_to_end(&mut decompressed).unwrap();
+        println!("{} bytes compressed into {}", decompressed.len(), compressed.len());
+        assert!(compressed.len() < plain.len());
+        // The compressed body is the tagged JSON response
+        assert_eq!(decompressed, plain);
+        let response: Response = serde_json::from_slice(&decompressed).unwrap();
+        assert_eq!(response.request_id(), Some("request-44"));
+        let (content_encoding, compressed) = dot(Some("deflate")).await;
+        assert_eq!(content_encoding.as_deref(), Some("deflate"));
+        let mut decompressed = Vec::new();
+        flate2::read::ZlibDecoder::new(&compressed[..]).read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, plain);
+        let (content_encoding, _) = dot(Some("br")).await;
+        assert_eq!(content_encoding.as_deref(), Some("br"));
+    }
 }
+
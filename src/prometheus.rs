@@ -0,0 +1,82 @@
+use std::{sync::OnceLock, time::Duration};
+use metrics::{counter, describe_counter, describe_histogram, histogram, Unit};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+/// The upper bounds of the buckets of the request duration histograms, in seconds
+pub const DURATION_BUCKETS: [f64; 12] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10., 30.];
+
+const REQUESTS: &str = "qrlew_requests_total";
+const SUCCESSES: &str = "qrlew_request_successes_total";
+const FAILURES: &str = "qrlew_request_failures_total";
+const DURATIONS: &str = "qrlew_request_duration_seconds";
+const SIGNATURES: &str = "qrlew_signatures_total";
+
+/// A Prometheus recorder with the duration histograms bucketed by `DURATION_BUCKETS`
+fn builder() -> PrometheusBuilder {
+    PrometheusBuilder::new().set_buckets_for_metric(Matcher::Full(DURATIONS.to_string()), &DURATION_BUCKETS).unwrap()
+}
+
+fn describe() {
+    describe_counter!(REQUESTS, "Requests per route");
+    describe_counter!(SUCCESSES, "Successful requests per route");
+    describe_counter!(FAILURES, "Failed requests per route");
+    describe_histogram!(DURATIONS, Unit::Seconds, "Request durations per route");
+    describe_counter!(SIGNATURES, "Signed responses");
+}
+
+/// The handle of the global Prometheus recorder
+static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// Install the global Prometheus recorder, metrics recorded before are dropped
+pub fn handle() -> &'static PrometheusHandle {
+    HANDLE.get_or_init(|| {
+        let handle = builder().install_recorder().unwrap();
+        describe();
+        handle
+    })
+}
+
+/// Count a request to a route, whether it succeeded, and time it
+pub fn record_request(route: &str, success: bool, duration: Duration) {
+    let route = route.to_string();
+    counter!(REQUESTS, "route" => route.clone()).increment(1);
+    counter!(if success { SUCCESSES } else { FAILURES }, "route" => route.clone()).increment(1);
+    histogram!(DURATIONS, "route" => route).record(duration);
+}
+
+/// Count a signature
+pub fn record_signature() {
+    counter!(SIGNATURES).increment(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prometheus() {
+        let recorder = builder().build_recorder();
+        let handle = recorder.handle();
+        metrics::with_local_recorder(&recorder, || {
+            describe();
+            record_request("/dot", true, Duration::from_millis(20));
+            record_request("/dot", false, Duration::from_secs(60));
+            record_request("/\"quoted\"\\", true, Duration::from_millis(1));
+            record_signature();
+        });
+        let text = handle.render();
+        println!("{text}");
+        assert!(text.contains("# TYPE qrlew_requests_total counter\n"));
+        assert!(text.contains("qrlew_requests_total{route=\"/dot\"} 2\n"));
+        assert!(text.contains("qrlew_request_failures_total{route=\"/dot\"} 1\n"));
+        // Buckets are cumulated
+        assert!(text.contains("qrlew_request_duration_seconds_bucket{route=\"/dot\",le=\"0.01\"} 0\n"));
+        assert!(text.contains("qrlew_request_duration_seconds_bucket{route=\"/dot\",le=\"0.025\"} 1\n"));
+        assert!(text.contains("qrlew_request_duration_seconds_bucket{route=\"/dot\",le=\"30\"} 1\n"));
+        assert!(text.contains("qrlew_request_duration_seconds_bucket{route=\"/dot\",le=\"+Inf\"} 2\n"));
+        assert!(text.contains("qrlew_request_duration_seconds_count{route=\"/dot\"} 2\n"));
+        assert!(text.contains("qrlew_signatures_total 1\n"));
+        // Label values are escaped
+        assert!(text.contains("qrlew_requests_total{route=\"/\\\"quoted\\\"\\\\\"} 1\n"));
+    }
+}
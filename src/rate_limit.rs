@@ -0,0 +1,73 @@
+use std::{collections::HashMap, sync::Mutex, time::Instant};
+
+/// A token bucket rate limiter per key, idle keys are evicted once their bucket is full again
+pub struct RateLimiter {
+    requests_per_minute: f64,
+    buckets: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_minute: u32) -> Self {
+        RateLimiter {
+            requests_per_minute: requests_per_minute as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token from the bucket of a key, return false when it is empty
+    pub fn try_acquire(&self, key: &str) -> bool {
+        self.try_acquire_at(key, Instant::now())
+    }
+
+    fn try_acquire_at(&self, key: &str, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        // Refill all buckets and evict the full ones
+        let rate = self.requests_per_minute / 60.;
+        let capacity = self.requests_per_minute;
+        buckets.retain(|_, (tokens, last)| {
+            *tokens = (*tokens + now.saturating_duration_since(*last).as_secs_f64() * rate).min(capacity);
+            *last = now;
+            *tokens < capacity
+        });
+        let (tokens, _) = buckets.entry(key.to_string()).or_insert((capacity, now));
+        if *tokens >= 1. {
+            *tokens -= 1.;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The number of keys with a partially used bucket
+    pub fn len(&self) -> usize {
+        self.buckets.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(2);
+        let now = Instant::now();
+        assert!(limiter.try_acquire_at("a", now));
+        assert!(limiter.try_acquire_at("a", now));
+        assert!(!limiter.try_acquire_at("a", now));
+        // Other keys have their own bucket
+        assert!(limiter.try_acquire_at("b", now));
+        assert_eq!(limiter.len(), 2);
+        // One token every 30s
+        assert!(limiter.try_acquire_at("a", now + Duration::from_secs(30)));
+        assert!(!limiter.try_acquire_at("a", now + Duration::from_secs(31)));
+        // Full buckets are evicted
+        assert!(limiter.try_acquire_at("c", now + Duration::from_secs(120)));
+        assert_eq!(limiter.len(), 1);
+    }
+}
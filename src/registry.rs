@@ -0,0 +1,56 @@
+use std::{collections::HashMap, sync::Mutex, time::{Duration, Instant}};
+use rand::Rng;
+use base64::{Engine as _, engine::general_purpose};
+
+/// An in-memory store of values referenced by random tokens, evicted after some time to live
+pub struct Registry<T> {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, (T, Instant)>>,
+}
+
+impl<T: Clone> Registry<T> {
+    pub fn new(ttl: Duration) -> Self {
+        Registry {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Store a value and return its token
+    pub fn insert(&self, value: T) -> String {
+        let token = general_purpose::URL_SAFE_NO_PAD.encode(rand::thread_rng().gen::<[u8; 16]>());
+        let mut entries = self.entries.lock().unwrap();
+        Registry::evict(&mut entries);
+        entries.insert(token.clone(), (value, Instant::now() + self.ttl));
+        token
+    }
+
+    /// Get the value of a token, if it was not evicted
+    pub fn get(&self, token: &str) -> Option<T> {
+        let mut entries = self.entries.lock().unwrap();
+        Registry::evict(&mut entries);
+        entries.get(token).map(|(value, _)| value.clone())
+    }
+
+    fn evict(entries: &mut HashMap<String, (T, Instant)>) {
+        let now = Instant::now();
+        entries.retain(|_, (_, expiry)| *expiry > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry() {
+        let registry = Registry::new(Duration::from_secs(60));
+        let token = registry.insert("Hello Sarus !".to_string());
+        println!("{token}");
+        assert_eq!(registry.get(&token), Some("Hello Sarus !".to_string()));
+        assert_eq!(registry.get("unknown"), None);
+        let expired = Registry::new(Duration::ZERO);
+        let token = expired.insert("Hello Sarus !".to_string());
+        assert_eq!(expired.get(&token), None);
+    }
+}
@@ -1,13 +1,87 @@
-use std::{sync::Arc, convert::TryFrom};
-use serde::{Deserialize, Serialize, Deserializer};
+use std::{sync::{Arc, OnceLock}, cell::Cell, ops::{ControlFlow, Range}, hash::{Hash as _, Hasher as _}, collections::{BTreeMap, BTreeSet, HashMap}, convert::TryFrom, result};
+use serde::{Deserialize, Deserializer, Serialize, de::{self, DeserializeOwned, Visitor}, forward_to_deserialize_any};
+use axum::{async_trait, body::Bytes, extract::{FromRequest, Request}, http::header, response::IntoResponse as _};
+use base64::{Engine as _, engine::general_purpose};
+use qrlew::dialect_translation::{RelationToQueryTranslator, RelationWithTranslator, bigquery::BigQueryTranslator, mssql::MsSqlTranslator, postgresql::PostgreSqlTranslator};
+use layout::{backends::svg::SVGWriter, gv::{DotParser, GraphBuilder}};
 use serde_json::Value;
-use chrono::{NaiveDate, NaiveTime, NaiveDateTime, Duration};
-use qrlew::{self, Ready as _, Relation, With as _, ast::{Query, self}, expr::Identifier, synthetic_data::SyntheticData,
-privacy_unit_tracking::PrivacyUnit, differential_privacy::DpParameters};
+use schemars::{JsonSchema, schema_for};
+use utoipa::ToSchema;
+use chrono::{DateTime, NaiveDate, NaiveTime, NaiveDateTime, Duration};
+use qrlew::{self, Ready as _, Relation, With as _, hierarchy::Hierarchy, relation::Variant as _, data_type::DataTyped as _, namer, ast::{self, Ident, Query, SelectItem, SetExpr, Statement, TableFactor}, dialect::GenericDialect, parser::Parser, expr::{Expr, Identifier, aggregate::Aggregate, function::Function}, synthetic_data::SyntheticData,
+privacy_unit_tracking::{PrivacyUnit, Strategy}, differential_privacy::{DpEvent, DpParameters}, visitor::Acceptor as _,
+rewriting::{Property, RelationWithDpEvent, RewritingRule, rewriting_rule::{Parameters, RewriteVisitor, Rewriter, RewritingRulesEliminator, RewritingRulesSelector, RewritingRulesSetter, Score}}};
 use super::*;
 
+/// A JSON request body, rejecting empty bodies with the list of expected fields
+pub struct JsonRequest<T>(pub T);
+
+#[async_trait]
+impl<S: Send + Sync, T: DeserializeOwned + JsonSchema> FromRequest<S> for JsonRequest<T> {
+    type Rejection = Error;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self> {
+        let body = Bytes::from_request(request, state).await?;
+        if body.iter().all(u8::is_ascii_whitespace) {
+            return Err(Error::invalid_request(format!("request body is empty; expected JSON with fields {}", field_names::<T>().join(", "))));
+        }
+        match env::var("QRLEW_DATASET_DIR") {
+            Ok(dataset_dir) => Ok(JsonRequest(serde_json::from_value(with_dataset_file(serde_json::from_slice(&body)?, &dataset_dir)?)?)),
+            Err(_) => Ok(JsonRequest(serde_json::from_slice(&body)?)),
+        }
+    }
+}
+
+/// Replace a `dataset_file` by the dataset it names in `dataset_dir`, a development convenience
+fn with_dataset_file(mut body: Value, dataset_dir: &str) -> Result<Value> {
+    let Some(fields) = body.as_object_mut() else { return Ok(body) };
+    let Some(dataset_file) = fields.remove("dataset_file") else { return Ok(body) };
+    if fields.contains_key("dataset") {
+        return Err(Error::invalid_request("dataset and dataset_file cannot be both set"));
+    }
+    let dataset_file = dataset_file.as_str().ok_or_else(|| Error::invalid_request("dataset_file should be a string"))?;
+    // Only files within the dataset directory can be read
+    let dataset_dir = std::path::Path::new(dataset_dir).canonicalize()?;
+    let path = dataset_dir.join(dataset_file).canonicalize().map_err(|_| Error::invalid_request(format!("cannot find dataset_file {dataset_file}")))?;
+    if !path.starts_with(&dataset_dir) {
+        return Err(Error::invalid_request(format!("dataset_file {dataset_file} is outside the dataset directory")));
+    }
+    fields.insert("dataset".to_string(), serde_json::from_slice(&std::fs::read(path)?)?);
+    Ok(body)
+}
+
+/// The field names of a struct, as declared to serde, or the properties of its JSON Schema when serde declares none (structs with flattened fields)
+fn field_names<T: DeserializeOwned + JsonSchema>() -> Vec<String> {
+    struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de> Deserializer<'de> for FieldNames<'_> {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> result::Result<V::Value, Self::Error> {
+            Err(de::Error::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(self, _name: &'static str, fields: &'static [&'static str], _visitor: V) -> result::Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(de::Error::custom("fields collected"))
+        }
+
+        forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option unit
+            unit_struct newtype_struct seq tuple tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldNames(&mut fields));
+    if !fields.is_empty() {
+        return fields.iter().map(|field| field.to_string()).collect();
+    }
+    schema_for!(T).schema.object.map(|object| object.properties.into_keys().collect()).unwrap_or_default()
+}
+
 /// Simplified DataType
-#[derive(Copy, Clone, Debug, PartialEq, PartialOrd, Deserialize, Serialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
 enum DataType {
     Boolean,
     Integer,
@@ -19,35 +93,165 @@ enum DataType {
     DateTime,
     Duration,
     Id,
+    /// A list of values of the inner type, e.g. `{"List":"Text"}`
+    #[schema(no_recursion)]
+    List(Box<DataType>),
+    /// A record of named fields, e.g. `{"Struct":[{"name":"city","data_type":"Text"}]}`
+    #[schema(no_recursion)]
+    Struct(Vec<Field>),
 }
 
+impl DataType {
+    /// The simplified type of a qrlew data type, with whether it is nullable
+    fn simplify(data_type: &qrlew::DataType) -> Option<(DataType, bool)> {
+        Some(match data_type {
+            qrlew::DataType::Optional(optional) => (DataType::simplify(optional.data_type())?.0, true),
+            qrlew::DataType::Boolean(_) => (DataType::Boolean, false),
+            qrlew::DataType::Integer(_) => (DataType::Integer, false),
+            qrlew::DataType::Float(_) => (DataType::Float, false),
+            qrlew::DataType::Text(_) => (DataType::Text, false),
+            qrlew::DataType::Bytes(_) => (DataType::Bytes, false),
+            qrlew::DataType::Date(_) => (DataType::Date, false),
+            qrlew::DataType::Time(_) => (DataType::Time, false),
+            qrlew::DataType::DateTime(_) => (DataType::DateTime, false),
+            qrlew::DataType::Duration(_) => (DataType::Duration, false),
+            qrlew::DataType::Id(_) => (DataType::Id, false),
+            qrlew::DataType::List(list) => (DataType::List(Box::new(DataType::simplify(list.data_type())?.0)), false),
+            qrlew::DataType::Struct(fields) => (DataType::Struct(fields.fields().iter().map(|(name, data_type)| {
+                let (data_type, nullable) = DataType::simplify(data_type)?;
+                Some(Field { name: name.clone(), data_type, range: None, possible_values: None, constraint: None, description: None, optional: nullable.then_some(true), format: None })
+            }).collect::<Option<_>>()?), false),
+            _ => return None,
+        })
+    }
 
-impl From<DataType> for qrlew::DataType {
-    fn from(value: DataType) -> Self {
-        match value {
-            DataType::Boolean => qrlew::DataType::boolean(),
-            DataType::Integer => qrlew::DataType::integer(),
-            DataType::Float => qrlew::DataType::float(),
-            DataType::Text => qrlew::DataType::text(),
-            DataType::Bytes => qrlew::DataType::bytes(),
-            DataType::Date => qrlew::DataType::date(),
-            DataType::Time => qrlew::DataType::time(),
-            DataType::DateTime => qrlew::DataType::date_time(),
-            DataType::Duration => qrlew::DataType::duration(),
-            DataType::Id => qrlew::DataType::id(),
+    /// The narrowest type holding values of both types: the same type, a float for numbers, or a list of the widened items
+    fn widen(self, other: DataType) -> Option<DataType> {
+        match (self, other) {
+            (left, right) if left == right => Some(left),
+            (DataType::Integer | DataType::Float, DataType::Integer | DataType::Float) => Some(DataType::Float),
+            (DataType::List(left), DataType::List(right)) => Some(DataType::List(Box::new(left.widen(*right)?))),
+            _ => None,
         }
     }
+
+    /// Whether columns of these types can be joined: same types, numbers, or ids
+    fn is_joinable_with(&self, other: &DataType) -> bool {
+        self == other
+            || matches!((self, other), (DataType::Integer | DataType::Float, DataType::Integer | DataType::Float))
+            || *self == DataType::Id
+            || *other == DataType::Id
+    }
 }
 
-/// Convert Field into qrlew DataType
-fn data_type_from_field(value: Field) -> Option<qrlew::DataType> {
-    Some(match value {
+/// Parse a duration given as integer seconds or as a string of amounts with units, e.g. `"1h30m"` or `"90s"`
+fn duration_from_value(value: &Value) -> Option<Duration> {
+    if let Some(seconds) = value.as_i64() {
+        return Some(Duration::seconds(seconds));
+    }
+    let text = value.as_str()?.trim();
+    let mut duration = Duration::zero();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let amount: i64 = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+        let unit = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        let milliseconds_per_unit = match &rest[..unit] {
+            "d" => 86_400_000,
+            "h" => 3_600_000,
+            "m" => 60_000,
+            "s" => 1_000,
+            "ms" => 1,
+            _ => None?,
+        };
+        duration = duration.checked_add(&Duration::milliseconds(amount.checked_mul(milliseconds_per_unit)?))?;
+        rest = &rest[unit..];
+    }
+    (!text.is_empty()).then_some(duration)
+}
+
+/// Parse an integer given as a JSON number or as a string (for integers beyond the precision of JSON numbers)
+fn integer_from_value(value: &Value) -> Option<i64> {
+    value.as_i64().or_else(|| value.as_str()?.trim().parse().ok())
+}
+
+/// Parse the bound or value of a field, explaining what could not be parsed
+fn parse_value<T>(value: &Value, role: &str, type_name: &str, parse: impl Fn(&Value) -> Option<T>) -> result::Result<T, String> {
+    parse(value).ok_or_else(|| format!("could not parse {role} {value} as {type_name}"))
+}
+
+/// Parse the bounds of a range
+fn parse_range<T>((min, max): &(Value, Value), type_name: &str, parse: impl Fn(&Value) -> Option<T>) -> result::Result<(T, T), String> {
+    Ok((parse_value(min, "range min", type_name, &parse)?, parse_value(max, "range max", type_name, &parse)?))
+}
+
+/// Parse all the possible values
+fn parse_values<T>(values: &[Value], type_name: &str, parse: impl Fn(&Value) -> Option<T>) -> result::Result<Vec<T>, String> {
+    values.iter().map(|value| parse_value(value, "possible value", type_name, &parse)).collect()
+}
+
+/// Parse the possible values lying within a range, the intersection of both
+fn parse_values_in_range<T: PartialOrd>(range: &(Value, Value), values: &[Value], type_name: &str, parse: impl Fn(&Value) -> Option<T>) -> result::Result<Vec<T>, String> {
+    let (min, max) = parse_range(range, type_name, &parse)?;
+    let values: Vec<T> = parse_values(values, type_name, &parse)?.into_iter().filter(|value| min <= *value && *value <= max).collect();
+    if values.is_empty() {
+        return Err("no possible value lies within the range".to_string());
+    }
+    Ok(values)
+}
+
+fn date_from_value(value: &Value, format: Option<&str>) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(value.as_str()?, format.unwrap_or("%Y-%m-%d")).ok()
+}
+
+fn time_from_value(value: &Value, format: Option<&str>) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(value.as_str()?, format.unwrap_or("%H:%M:%S")).ok()
+}
+
+/// Parse a date time, those with a timezone offset are converted to UTC
+fn date_time_from_value(value: &Value, format: Option<&str>) -> Option<NaiveDateTime> {
+    let (text, format) = (value.as_str()?, format.unwrap_or("%Y-%m-%d %H:%M:%S"));
+    DateTime::parse_from_str(text, format).map(|date_time| date_time.naive_utc()).or_else(|_| NaiveDateTime::parse_from_str(text, format)).ok()
+}
+
+/// Convert Field into qrlew DataType, or explain why it cannot be
+fn data_type_from_field(value: Field) -> result::Result<qrlew::DataType, String> {
+    match value.data_type {
+        // The range, possible values and format of a list apply to its items
+        DataType::List(data_type) => {
+            let data_type = data_type_from_field(Field { data_type: *data_type, ..value })?;
+            return Ok(qrlew::DataType::List(qrlew::data_type::List::from_data_type(data_type)));
+        }
+        // The range and possible values of a struct are those of its fields
+        DataType::Struct(fields) => {
+            if value.range.is_some() || value.possible_values.is_some() {
+                return Err("range and possible_values cannot be set for Struct fields, set them on its fields".to_string());
+            }
+            let fields = fields.into_iter()
+                .map(|field| Ok((field.name.clone(), Arc::new(nullable_data_type_from_field(field)?))))
+                .collect::<result::Result<Vec<_>, String>>()?;
+            return Ok(qrlew::DataType::Struct(qrlew::data_type::Struct::new(fields)));
+        }
+        _ => {}
+    }
+    let format = value.format.clone();
+    let format = format.as_deref();
+    // Name the parse format in errors when it is not the default one
+    let temporal = |type_name: &str| match format {
+        Some(format) => format!("{type_name} in format {format}"),
+        None => type_name.to_string(),
+    };
+    Ok(match value {
         Field {
             name: _,
             data_type,
             range: None,
             possible_values: None,
             constraint: _,
+            description: _,
+            optional: _,
+            format: _,
         } => match data_type {
             DataType::Boolean => qrlew::DataType::boolean(),
             DataType::Integer => qrlew::DataType::integer(),
@@ -59,24 +263,60 @@ fn data_type_from_field(value: Field) -> Option<qrlew::DataType> {
             DataType::DateTime => qrlew::DataType::date_time(),
             DataType::Duration => qrlew::DataType::duration(),
             DataType::Id => qrlew::DataType::id(),
+            DataType::List(_) | DataType::Struct(_) => unreachable!("lists and structs are converted from their items and fields"),
         },
         Field {
-            name,
+            name: _,
             data_type,
-            range: Some((min, max)),
+            range: Some(range),
             possible_values: None,
             constraint: _,
+            description: _,
+            optional: _,
+            format: _,
         } => match data_type {
-            DataType::Boolean => qrlew::DataType::boolean_interval(min.as_bool()?, max.as_bool()?),
-            DataType::Integer => qrlew::DataType::integer_interval(min.as_i64()?, max.as_i64()?),
-            DataType::Float => qrlew::DataType::float_interval(min.as_f64()?, max.as_f64()?),
-            DataType::Text => qrlew::DataType::text_interval(min.as_str()?.to_string(), max.as_str()?.to_string()),
-            DataType::Date => qrlew::DataType::date_interval(NaiveDate::parse_from_str(min.as_str()?, "%Y-%m-%d").ok()?, NaiveDate::parse_from_str(max.as_str()?, "%Y-%m-%d").ok()?),
-            DataType::Time => qrlew::DataType::time_interval(NaiveTime::parse_from_str(min.as_str()?, "%H:%M:%S").ok()?, NaiveTime::parse_from_str(max.as_str()?, "%H:%M:%S").ok()?),
-            DataType::DateTime => qrlew::DataType::date_time_interval(NaiveDateTime::parse_from_str(min.as_str()?, "%Y-%m-%d %H:%M:%S").ok()?, NaiveDateTime::parse_from_str(max.as_str()?, "%Y-%m-%d %H:%M:%S").ok()?),
-            DataType::Duration => qrlew::DataType::duration_interval(Duration::seconds(min.as_i64()?), Duration::seconds(max.as_i64()?)),
+            DataType::Boolean => {
+                let (min, max) = parse_range(&range, "boolean", Value::as_bool)?;
+                qrlew::DataType::boolean_interval(min, max)
+            }
+            DataType::Integer => {
+                let (min, max) = parse_range(&range, "64 bit integer", integer_from_value)?;
+                qrlew::DataType::integer_interval(min, max)
+            }
+            DataType::Float => {
+                let (min, max) = parse_range(&range, "float", Value::as_f64)?;
+                qrlew::DataType::float_interval(min, max)
+            }
+            DataType::Text => {
+                let (min, max) = parse_range(&range, "text", |v| Some(v.as_str()?.to_string()))?;
+                qrlew::DataType::text_interval(min, max)
+            }
+            DataType::Date => {
+                let (min, max) = parse_range(&range, &temporal("date"), |v| date_from_value(v, format))?;
+                qrlew::DataType::date_interval(min, max)
+            }
+            DataType::Time => {
+                let (min, max) = parse_range(&range, &temporal("time"), |v| time_from_value(v, format))?;
+                qrlew::DataType::time_interval(min, max)
+            }
+            DataType::DateTime => {
+                let (min, max) = parse_range(&range, &temporal("date time"), |v| date_time_from_value(v, format))?;
+                qrlew::DataType::date_time_interval(min, max)
+            }
+            DataType::Duration => {
+                let (min, max) = parse_range(&range, "duration", duration_from_value)?;
+                qrlew::DataType::duration_interval(min, max)
+            }
+            // Bounds on the length of the bytes, qrlew does not bound bytes
+            DataType::Bytes => {
+                let (min, max) = parse_range(&range, "length in bytes", Value::as_u64)?;
+                if min > max {
+                    return Err(format!("range min {min} is greater than range max {max}"));
+                }
+                qrlew::DataType::bytes()
+            }
             DataType::Id => qrlew::DataType::id(),
-            _ => None?,
+            DataType::List(_) | DataType::Struct(_) => unreachable!("lists and structs are converted from their items and fields"),
         },
         Field {
             name: _,
@@ -84,76 +324,127 @@ fn data_type_from_field(value: Field) -> Option<qrlew::DataType> {
             range: None,
             possible_values: Some(possible_values),
             constraint: _,
+            description: _,
+            optional: _,
+            format: _,
         } => match data_type {
-            DataType::Boolean => qrlew::DataType::boolean_values(possible_values.into_iter().filter_map(|v| v.as_bool()).collect::<Vec<_>>()),
-            DataType::Integer => qrlew::DataType::integer_values(possible_values.into_iter().filter_map(|v| v.as_i64()).collect::<Vec<_>>()),
-            DataType::Float => qrlew::DataType::float_values(possible_values.into_iter().filter_map(|v| v.as_f64()).collect::<Vec<_>>()),
-            DataType::Text => qrlew::DataType::text_values(possible_values.into_iter().filter_map(|v| Some(v.as_str()?.to_string())).collect::<Vec<_>>()),
-            DataType::Date => qrlew::DataType::date_values(possible_values.into_iter().filter_map(|v| NaiveDate::parse_from_str(v.as_str()?, "%Y-%m-%d").ok()).collect::<Vec<_>>()),
-            DataType::Time => qrlew::DataType::time_values(possible_values.into_iter().filter_map(|v| NaiveTime::parse_from_str(v.as_str()?, "%H:%M:%S").ok()).collect::<Vec<_>>()),
-            DataType::DateTime => qrlew::DataType::date_time_values(possible_values.into_iter().filter_map(|v| NaiveDateTime::parse_from_str(v.as_str()?, "%Y-%m-%d %H:%M:%S").ok()).collect::<Vec<_>>()),
-            DataType::Duration => qrlew::DataType::duration_values(possible_values.into_iter().filter_map(|v| Some(Duration::seconds(v.as_i64()?))).collect::<Vec<_>>()),
+            DataType::Boolean => qrlew::DataType::boolean_values(parse_values(&possible_values, "boolean", Value::as_bool)?),
+            DataType::Integer => qrlew::DataType::integer_values(parse_values(&possible_values, "64 bit integer", integer_from_value)?),
+            DataType::Float => qrlew::DataType::float_values(parse_values(&possible_values, "float", Value::as_f64)?),
+            DataType::Text => qrlew::DataType::text_values(parse_values(&possible_values, "text", |v| Some(v.as_str()?.to_string()))?),
+            DataType::Date => qrlew::DataType::date_values(parse_values(&possible_values, &temporal("date"), |v| date_from_value(v, format))?),
+            DataType::Time => qrlew::DataType::time_values(parse_values(&possible_values, &temporal("time"), |v| time_from_value(v, format))?),
+            DataType::DateTime => qrlew::DataType::date_time_values(parse_values(&possible_values, &temporal("date time"), |v| date_time_from_value(v, format))?),
+            DataType::Duration => qrlew::DataType::duration_values(parse_values(&possible_values, "duration", duration_from_value)?),
+            // Values given as strings, qrlew does not enumerate bytes
+            DataType::Bytes => {
+                parse_values(&possible_values, "bytes string", |v| v.as_str().map(drop))?;
+                qrlew::DataType::bytes()
+            }
             DataType::Id => qrlew::DataType::id(),
-            _ => None?,
+            DataType::List(_) | DataType::Struct(_) => unreachable!("lists and structs are converted from their items and fields"),
+        },
+        // Only the possible values within the range are kept, for ordered types
+        Field {
+            name: _,
+            data_type,
+            range: Some(range),
+            possible_values: Some(possible_values),
+            constraint: _,
+            description: _,
+            optional: _,
+            format: _,
+        } => match data_type {
+            DataType::Integer => qrlew::DataType::integer_values(parse_values_in_range(&range, &possible_values, "64 bit integer", integer_from_value)?),
+            DataType::Float => qrlew::DataType::float_values(parse_values_in_range(&range, &possible_values, "float", Value::as_f64)?),
+            DataType::Date => qrlew::DataType::date_values(parse_values_in_range(&range, &possible_values, &temporal("date"), |v| date_from_value(v, format))?),
+            DataType::Time => qrlew::DataType::time_values(parse_values_in_range(&range, &possible_values, &temporal("time"), |v| time_from_value(v, format))?),
+            DataType::DateTime => qrlew::DataType::date_time_values(parse_values_in_range(&range, &possible_values, &temporal("date time"), |v| date_time_from_value(v, format))?),
+            DataType::Duration => qrlew::DataType::duration_values(parse_values_in_range(&range, &possible_values, "duration", duration_from_value)?),
+            data_type => return Err(format!("range and possible_values cannot both be set for {data_type:?} fields")),
         },
-        field => None?,
     })
 }
 
+/// Convert Field into qrlew DataType, optional when the field is, or explain why it cannot be naming the field
+fn nullable_data_type_from_field(value: Field) -> result::Result<qrlew::DataType, String> {
+    let name = value.name.clone();
+    let optional = value.optional == Some(true);
+    let data_type = data_type_from_field(value).map_err(|reason| format!("field `{name}`: {reason}"))?;
+    Ok(if optional { qrlew::DataType::optional(data_type) } else { data_type })
+}
+
 impl TryFrom<Field> for qrlew::DataType {
     type Error = Error;
 
     fn try_from(value: Field) -> Result<Self> {
-        let err = Error::other(value.name.clone());
-        data_type_from_field(value).ok_or(err)
+        nullable_data_type_from_field(value).map_err(Error::invalid_request)
     }
 }
 
 /// Simplified Constraint
-#[derive(Copy, Clone, Debug, PartialEq, PartialOrd, Deserialize, Serialize)]
+#[derive(Clone, Debug, PartialEq, PartialOrd, Deserialize, Serialize, JsonSchema, ToSchema)]
 enum Constraint {
     Unique,
+    PrimaryKey,
+    /// A reference to a column of another table, qrlew only keeps the constraint kind
+    ForeignKey { table: String, column: String },
 }
 
 impl From<Constraint> for qrlew::relation::Constraint {
     fn from(value: Constraint) -> Self {
         match value {
             Constraint::Unique => qrlew::relation::Constraint::Unique,
+            Constraint::PrimaryKey => qrlew::relation::Constraint::PrimaryKey,
+            Constraint::ForeignKey { .. } => qrlew::relation::Constraint::ForeignKey,
         }
     }
 }
 
 /// Field
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
 struct Field {
     name: String,
     data_type: DataType,
     range: Option<(Value, Value)>,
     possible_values: Option<Vec<Value>>,
     constraint: Option<Constraint>,
+    /// A free text description, qrlew relations carry no annotation so it is not shown in dot labels
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    /// Whether the column may be NULL, the range and possible values apply to its non-NULL values
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    optional: Option<bool>,
+    /// A chrono parse format for the range and possible values of Date, Time and DateTime fields
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    format: Option<String>,
 }
 
-impl From<Field> for qrlew::relation::Field {
-    fn from(value: Field) -> Self {
-        let data_type = value.clone().try_into().unwrap();
-        qrlew::relation::Field::new(value.name, data_type, value.constraint.map(Constraint::into))
+impl TryFrom<Field> for qrlew::relation::Field {
+    type Error = Error;
+
+    fn try_from(value: Field) -> Result<Self> {
+        let data_type = value.clone().try_into()?;
+        Ok(qrlew::relation::Field::new(value.name, data_type, value.constraint.map(Constraint::into)))
     }
 }
 
 /// Schema
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
 struct Schema {
     fields: Vec<Field>,
 }
 
-impl From<Schema> for qrlew::relation::Schema {
-    fn from(value: Schema) -> Self {
-        qrlew::relation::Schema::from_iter(value.fields.into_iter().map(|f| qrlew::relation::Field::from(f)))
+impl TryFrom<Schema> for qrlew::relation::Schema {
+    type Error = Error;
+
+    fn try_from(value: Schema) -> Result<Self> {
+        Ok(qrlew::relation::Schema::from_iter(value.fields.into_iter().map(qrlew::relation::Field::try_from).collect::<Result<Vec<_>>>()?))
     }
 }
 
 /// Table
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
 struct Table {
     name: String,
     path: Vec<String>,
@@ -161,261 +452,2940 @@ struct Table {
     size: i64,
 }
 
-impl From<Table> for qrlew::Relation {
-    fn from(value: Table) -> Self {
-        qrlew::Relation::table()
+impl TryFrom<Table> for qrlew::Relation {
+    type Error = Error;
+
+    fn try_from(value: Table) -> Result<Self> {
+        Ok(qrlew::Relation::table()
             .name(value.name)
             .path(value.path)
-            .schema(value.schema)
+            .schema(qrlew::relation::Schema::try_from(value.schema)?)
             .size(value.size)
-            .build()
+            .build())
     }
 }
 
 /// Dataset
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
 struct Dataset {
     tables: Vec<Table>,
 }
 
-impl From<Dataset> for qrlew::hierarchy::Hierarchy<Arc<qrlew::Relation>> {
-    fn from(value: Dataset) -> Self {
-        value.tables.into_iter().map(|t| (t.path.clone(), Arc::new(qrlew::Relation::from(t)))).collect()
+impl Dataset {
+    /// The table designated in a privacy unit, as qrlew does by name or by the last element of its path
+    fn privacy_unit_table(&self, name: &str) -> Option<&Table> {
+        self.tables.iter().find(|table| table.name == name || table.path.last().is_some_and(|last| last == name))
     }
-}
 
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
-pub struct Dot {
-    dataset: Dataset,
-    query: String,
-    dark_mode: bool,
+    /// The keys the budget of a privacy unit is accounted under: the sorted paths of the tables it protects,
+    /// so that the metadata of a dataset (sizes, ranges, unrelated tables) can be changed without resetting its budget
+    fn budget_keys(&self, privacy_unit: &PrivacyUnit) -> Vec<String> {
+        privacy_unit.iter()
+            .map(|(name, _)| self.privacy_unit_table(name).map_or_else(|| name.clone(), |table| table.path.join(".")))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// SHA-256 of the dataset with its tables sorted by path
+    fn hash(&self) -> Result<String> {
+        let mut tables: Vec<&Table> = self.tables.iter().collect();
+        tables.sort_by(|left, right| left.path.cmp(&right.path));
+        Ok(response::sha256_hex(serde_json::to_vec(&tables)?))
+    }
 }
 
-impl Dot {
-    pub fn response(self) -> Result<Response> {
-        let query = qrlew::sql::relation::parse(&self.query)?;
-        let mut response = Vec::new();
-        Relation::try_from(query.with(&self.dataset.into()))?.dot(&mut response, if self.dark_mode {&["dark"]} else {&[]})?;
-        Ok(Response::new(String::from_utf8(response)?))
+impl Dataset {
+    /// Check no field has more than `max` possible values, or when degrading, fall back to the plain data type of those fields with a warning
+    fn limit_possible_values(&mut self, max: Option<usize>, degrade: bool) -> Result<Vec<String>> {
+        let Some(max) = max else { return Ok(vec![]) };
+        let mut warnings = vec![];
+        for table in &mut self.tables {
+            for field in &mut table.schema.fields {
+                let count = field.possible_values.as_ref().map_or(0, Vec::len);
+                if count <= max {
+                    continue;
+                }
+                if !degrade {
+                    return Err(Error::invalid_request(format!("{}.{} has {count} possible values, more than the maximum of {max}", table.name, field.name)));
+                }
+                field.possible_values = None;
+                warnings.push(format!("the {count} possible values of {}.{} were ignored (more than {max})", table.name, field.name));
+            }
+        }
+        Ok(warnings)
+    }
+
+    /// The relations of the dataset, with the warnings of the conversion, built once for identical datasets
+    fn into_relations(mut self) -> Result<DatasetRelations> {
+        let max_possible_values: Option<usize> = env::var("QRLEW_MAX_POSSIBLE_VALUES").ok().and_then(|max| max.parse().ok());
+        let degrade = env_flag("QRLEW_DEGRADE_POSSIBLE_VALUES");
+        let mut hasher = std::hash::DefaultHasher::new();
+        (serde_json::to_string(&self)?, max_possible_values, degrade).hash(&mut hasher);
+        let key = hasher.finish();
+        if let Some(relations) = dataset_cache().get(&key) {
+            return Ok(relations);
+        }
+        let warnings = self.limit_possible_values(max_possible_values, degrade)?;
+        let relations = (self.try_into()?, warnings);
+        dataset_cache().insert(key, relations.clone());
+        Ok(relations)
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
-pub struct RewriteAsPrivacyUnitPreserving {
-    dataset: Dataset,
-    query: String,
-    synthetic_data: Vec<(String, String)>,
-    privacy_unit: Vec<(String, Vec<(String, String, String)>, String)>,
-    epsilon: f64,
-    delta: f64,
+/// The relations of a dataset, with the warnings of their conversion
+pub type DatasetRelations = (Hierarchy<Arc<Relation>>, Vec<String>);
+
+impl TryFrom<Dataset> for qrlew::hierarchy::Hierarchy<Arc<qrlew::Relation>> {
+    type Error = Error;
+
+    fn try_from(value: Dataset) -> Result<Self> {
+        value.tables.into_iter().map(|t| Ok((t.path.clone(), Arc::new(qrlew::Relation::try_from(t)?)))).collect()
+    }
 }
 
-impl RewriteAsPrivacyUnitPreserving {
-    pub fn response(self) -> Result<Response> {
-        let query = qrlew::sql::relation::parse(&self.query)?;
-        let relations = self.dataset.into();
-        let relation = Relation::try_from(query.with(&relations)).unwrap();
-        let synthetic_data = (!self.synthetic_data.is_empty()).then(|| SyntheticData::new(self.synthetic_data.into_iter().map(|(table, synthetic_table)| (Identifier::from(table), Identifier::from(synthetic_table))).collect()));
-        let borrowed_privacy_unit: Vec<(&str, Vec<(&str, &str, &str)>, &str)> = self.privacy_unit.iter().map(|(source, links, privacy_unit)| (source.as_str(), links.iter().map(|(source_col, target, target_col)| (source_col.as_str(), target.as_str(), target_col.as_str())).collect(), privacy_unit.as_str())).collect();
-        let privacy_unit = PrivacyUnit::from(borrowed_privacy_unit);
-        let dp_parameters = DpParameters::from_epsilon_delta(self.epsilon, self.delta);
-        let pup_relation = relation.rewrite_as_privacy_unit_preserving(&relations, synthetic_data, privacy_unit, dp_parameters, None)?;
-        Ok(Response::new(Query::from(pup_relation.relation()).to_string()))
+/// Privacy unit definition: (table, links as (source column, target table, target column), privacy unit column)
+pub type PrivacyUnitSpec = Vec<(String, Vec<(String, String, String)>, String)>;
+type BorrowedPrivacyUnitSpec<'a> = Vec<(&'a str, Vec<(&'a str, &'a str, &'a str)>, &'a str)>;
+
+/// Privacy unit definition with tables designated by their paths: (table, links as (source column, target table, target column), privacy unit column)
+pub type QualifiedPrivacyUnitSpec = Vec<(Vec<String>, Vec<(String, Vec<String>, String)>, String)>;
+
+/// Translate a privacy unit given with table paths into table names, a privacy unit given with names is returned as is
+fn privacy_unit_from_paths(privacy_unit: &PrivacyUnitSpec, privacy_unit_paths: &QualifiedPrivacyUnitSpec, dataset: &Dataset) -> Result<PrivacyUnitSpec> {
+    if privacy_unit_paths.is_empty() {
+        return Ok(privacy_unit.clone());
+    }
+    if !privacy_unit.is_empty() {
+        return Err(Error::invalid_request("privacy_unit and privacy_unit_paths cannot be both set"));
     }
+    let table_name = |path: &Vec<String>| -> Result<String> {
+        let table = dataset.tables.iter().find(|table| table.path == *path)
+            .ok_or_else(|| Error::invalid_request(format!("unknown table `{}` in privacy_unit_paths", path.join("."))))?;
+        if dataset.tables.iter().filter(|other| other.name == table.name).count() > 1 {
+            return Err(Error::invalid_request(format!("the table name `{}` of `{}` is not unique in the dataset", table.name, path.join("."))));
+        }
+        Ok(table.name.clone())
+    };
+    privacy_unit_paths.iter().map(|(table, links, privacy_unit_column)| Ok((
+        table_name(table)?,
+        links.iter().map(|(source_column, target, target_column)| Ok((source_column.clone(), table_name(target)?, target_column.clone()))).collect::<Result<_>>()?,
+        privacy_unit_column.clone(),
+    ))).collect()
 }
 
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
-pub struct RewriteWithDifferentialPrivacy {
-    dataset: Dataset,
-    query: String,
-    synthetic_data: Vec<(String, String)>,
-    privacy_unit: Vec<(String, Vec<(String, String, String)>, String)>,
-    epsilon: f64,
-    delta: f64,
+/// Make the relations reachable by their table names, as qrlew looks privacy unit tables up by name
+fn with_table_name_aliases(relations: &Hierarchy<Arc<Relation>>) -> Hierarchy<Arc<Relation>> {
+    let aliases: Vec<(Vec<String>, Arc<Relation>)> = relations.values()
+        .map(|relation| (vec![relation.name().to_string()], relation.clone()))
+        .filter(|(name, _)| relations.get(name).is_none())
+        .collect();
+    relations.iter().map(|(path, relation)| (path.clone(), relation.clone())).chain(aliases).collect()
 }
 
-impl RewriteWithDifferentialPrivacy {
-    pub fn response(self, auth: &Authenticator) -> Result<Response> {
-        let query = qrlew::sql::relation::parse(&self.query)?;
-        let relations = self.dataset.into();
-        let relation = Relation::try_from(query.with(&relations)).unwrap();
-        let synthetic_data = (!self.synthetic_data.is_empty()).then(|| SyntheticData::new(self.synthetic_data.into_iter().map(|(table, synthetic_table)| (Identifier::from(table), Identifier::from(synthetic_table))).collect()));
-        let borrowed_privacy_unit: Vec<(&str, Vec<(&str, &str, &str)>, &str)> = self.privacy_unit.iter().map(|(source, links, privacy_unit)| (source.as_str(), links.iter().map(|(source_col, target, target_col)| (source_col.as_str(), target.as_str(), target_col.as_str())).collect(), privacy_unit.as_str())).collect();
-        let privacy_unit = PrivacyUnit::from(borrowed_privacy_unit);
-        let dp_parameters = DpParameters::from_epsilon_delta(self.epsilon, self.delta);
-        let dp_relation = relation.rewrite_with_differential_privacy(&relations, synthetic_data, privacy_unit, dp_parameters)?;
-        Ok(Response::signed(Query::from(dp_relation.relation()).to_string(), auth))
+/// Check a privacy unit definition is well formed
+fn validate_privacy_unit(privacy_unit: &PrivacyUnitSpec) -> Result<()> {
+    if privacy_unit.is_empty() {
+        return Err(Error::invalid_request("the privacy unit is empty"));
     }
+    for (i, (table, links, privacy_unit_column)) in privacy_unit.iter().enumerate() {
+        if table.is_empty() || privacy_unit_column.is_empty() {
+            return Err(Error::invalid_request(format!("the privacy unit of table `{table}` has an empty table or column name")));
+        }
+        if privacy_unit[..i].iter().any(|(other, _, _)| other == table) {
+            return Err(Error::invalid_request(format!("the privacy unit of table `{table}` is defined more than once")));
+        }
+        if links.iter().any(|(source_column, target, target_column)| source_column.is_empty() || target.is_empty() || target_column.is_empty()) {
+            return Err(Error::invalid_request(format!("the privacy unit of table `{table}` has a link with an empty name")));
+        }
+    }
+    Ok(())
 }
 
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
-pub struct QueryWithDot {
-    query: String,
-    dot: String,
+/// Add the tables reaching a table of the privacy unit through foreign keys, explicitly defined tables are kept as is
+fn infer_privacy_unit_links(privacy_unit: &PrivacyUnitSpec, dataset: &Dataset) -> PrivacyUnitSpec {
+    let mut privacy_unit = privacy_unit.clone();
+    loop {
+        let inferred: PrivacyUnitSpec = dataset.tables.iter()
+            .filter(|table| !privacy_unit.iter().any(|(name, _, _)| *name == table.name))
+            .filter_map(|table| table.schema.fields.iter().find_map(|field| match &field.constraint {
+                Some(Constraint::ForeignKey { table: target, column }) => privacy_unit.iter()
+                    .find(|(name, _, _)| name == target)
+                    .map(|(_, links, privacy_unit_column)| {
+                        let links = std::iter::once((field.name.clone(), target.clone(), column.clone())).chain(links.iter().cloned()).collect();
+                        (table.name.clone(), links, privacy_unit_column.clone())
+                    }),
+                _ => None,
+            }))
+            .collect();
+        if inferred.is_empty() {
+            return privacy_unit;
+        }
+        privacy_unit.extend(inferred);
+    }
 }
 
-impl QueryWithDot {
-    pub fn new(query: String, dot: String) -> QueryWithDot {
-        QueryWithDot {
-            query,
-            dot,
+/// Check the tables and columns of the privacy unit exist in the dataset, the privacy unit column being in the last linked table
+fn check_privacy_unit_references(privacy_unit: &PrivacyUnitSpec, dataset: &Dataset) -> Result<()> {
+    let table = |name: &str| dataset.privacy_unit_table(name)
+        .ok_or_else(|| Error::invalid_request(format!("the privacy unit references the unknown table `{name}`")));
+    let check_column = |table: &Table, column: &str, of: &str| if table.schema.fields.iter().any(|field| field.name == column) {
+        Ok(())
+    } else {
+        Err(Error::invalid_request(format!("the privacy unit of table `{of}` references the unknown column `{}.{column}`", table.name)))
+    };
+    for (name, links, privacy_unit_column) in privacy_unit {
+        let mut current = table(name)?;
+        for (source_column, target, target_column) in links {
+            check_column(current, source_column, name)?;
+            current = table(target)?;
+            check_column(current, target_column, name)?;
         }
+        check_column(current, privacy_unit_column, name)?;
     }
+    Ok(())
 }
 
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
-pub struct RewriteAsPrivacyUnitPreservingWithDot {
-    dataset: Dataset,
-    query: String,
-    synthetic_data: Vec<(String, String)>,
-    privacy_unit: Vec<(String, Vec<(String, String, String)>, String)>,
-    epsilon: f64,
-    delta: f64,
-    dark_mode: bool,
+/// Check the columns joined by the privacy unit links have compatible types in the dataset
+fn check_privacy_unit_link_types(privacy_unit: &PrivacyUnitSpec, dataset: &Dataset) -> Result<()> {
+    let data_type = |table: &str, column: &str| dataset.privacy_unit_table(table)
+        .and_then(|table| table.schema.fields.iter().find(|field| field.name == column))
+        .map(|field| &field.data_type);
+    for (table, links, _) in privacy_unit {
+        let mut source_table = table;
+        for (source_column, target_table, target_column) in links {
+            if let (Some(source_type), Some(target_type)) = (data_type(source_table, source_column), data_type(target_table, target_column)) {
+                if !source_type.is_joinable_with(target_type) {
+                    return Err(Error::invalid_request(format!(
+                        "the privacy unit link from `{source_table}.{source_column}` ({source_type:?}) to `{target_table}.{target_column}` ({target_type:?}) joins incompatible types"
+                    )));
+                }
+            }
+            source_table = target_table;
+        }
+    }
+    Ok(())
 }
 
-impl RewriteAsPrivacyUnitPreservingWithDot {
-    pub fn response(self) -> Result<Response> {
-        let query = qrlew::sql::relation::parse(&self.query)?;
-        let relations = self.dataset.into();
-        let relation = Relation::try_from(query.with(&relations)).unwrap();
-        let synthetic_data = (!self.synthetic_data.is_empty()).then(|| SyntheticData::new(self.synthetic_data.into_iter().map(|(table, synthetic_table)| (Identifier::from(table), Identifier::from(synthetic_table))).collect()));
-        let borrowed_privacy_unit: Vec<(&str, Vec<(&str, &str, &str)>, &str)> = self.privacy_unit.iter().map(|(source, links, privacy_unit)| (source.as_str(), links.iter().map(|(source_col, target, target_col)| (source_col.as_str(), target.as_str(), target_col.as_str())).collect(), privacy_unit.as_str())).collect();
-        let privacy_unit = PrivacyUnit::from(borrowed_privacy_unit);
-        let dp_parameters = DpParameters::from_epsilon_delta(self.epsilon, self.delta);
-        let pup_relation = relation.rewrite_as_privacy_unit_preserving(&relations, synthetic_data, privacy_unit, dp_parameters, None)?;
-        let mut dot = Vec::new();
-        pup_relation.relation().dot(&mut dot, if self.dark_mode {&["dark"]} else {&[]})?;
-        Ok(Response::new(serde_json::to_string(&QueryWithDot::new(Query::from(pup_relation.relation()).to_string(), String::from_utf8(dot)?))?))
-    }
+/// Build the PrivacyUnit given inline or registered under a token for a dataset, optionally completed with its foreign keys
+fn resolve_privacy_unit(privacy_unit: &PrivacyUnitSpec, token: Option<&str>, dataset: &Dataset, infer_links: bool) -> Result<PrivacyUnit> {
+    let registered;
+    let privacy_unit = match token {
+        Some(_) if !privacy_unit.is_empty() => return Err(Error::invalid_request("privacy_unit and privacy_unit_token cannot be both set")),
+        Some(token) => {
+            registered = privacy_units().get(token).ok_or_else(|| Error::invalid_request(format!("unknown or expired privacy unit token {token}")))?;
+            &registered
+        }
+        None => privacy_unit,
+    };
+    let inferred;
+    let privacy_unit = if infer_links {
+        inferred = infer_privacy_unit_links(privacy_unit, dataset);
+        &inferred
+    } else {
+        privacy_unit
+    };
+    check_privacy_unit_references(privacy_unit, dataset)?;
+    check_privacy_unit_link_types(privacy_unit, dataset)?;
+    let borrowed_privacy_unit: BorrowedPrivacyUnitSpec = privacy_unit.iter().map(|(source, links, privacy_unit)| (source.as_str(), links.iter().map(|(source_col, target, target_col)| (source_col.as_str(), target.as_str(), target_col.as_str())).collect(), privacy_unit.as_str())).collect();
+    Ok(PrivacyUnit::from(borrowed_privacy_unit))
 }
 
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
-pub struct RewriteWithDifferentialPrivacyWithDot {
-    dataset: Dataset,
-    query: String,
-    synthetic_data: Vec<(String, String)>,
-    privacy_unit: Vec<(String, Vec<(String, String, String)>, String)>,
-    epsilon: f64,
-    delta: f64,
-    dark_mode: bool,
+/// Check a privacy budget is meaningful: a finite positive epsilon and a delta in [0, 1)
+fn validate_budget(epsilon: f64, delta: f64) -> Result<()> {
+    if !(epsilon.is_finite() && epsilon > 0.) {
+        return Err(Error::invalid_request(format!("epsilon should be a positive number, got {epsilon}")));
+    }
+    if !(delta.is_finite() && (0. ..1.).contains(&delta)) {
+        return Err(Error::invalid_request(format!("delta should be a number in [0, 1), got {delta}")));
+    }
+    Ok(())
 }
 
-impl RewriteWithDifferentialPrivacyWithDot {
-    pub fn response(self, auth: &Authenticator) -> Result<Response> {
-        let query = qrlew::sql::relation::parse(&self.query)?;
-        let relations = self.dataset.into();
-        let relation = Relation::try_from(query.with(&relations)).unwrap();
-        let synthetic_data = (!self.synthetic_data.is_empty()).then(|| SyntheticData::new(self.synthetic_data.into_iter().map(|(table, synthetic_table)| (Identifier::from(table), Identifier::from(synthetic_table))).collect()));
-        let borrowed_privacy_unit: Vec<(&str, Vec<(&str, &str, &str)>, &str)> = self.privacy_unit.iter().map(|(source, links, privacy_unit)| (source.as_str(), links.iter().map(|(source_col, target, target_col)| (source_col.as_str(), target.as_str(), target_col.as_str())).collect(), privacy_unit.as_str())).collect();
-        let privacy_unit = PrivacyUnit::from(borrowed_privacy_unit);
-        let dp_parameters = DpParameters::from_epsilon_delta(self.epsilon, self.delta);
-        let dp_relation = relation.rewrite_with_differential_privacy(&relations, synthetic_data, privacy_unit, dp_parameters)?;
-        let mut dot = Vec::new();
-        dp_relation.relation().dot(&mut dot, if self.dark_mode {&["dark"]} else {&[]})?;
-        Ok(Response::signed(serde_json::to_string(&QueryWithDot::new(Query::from(dp_relation.relation()).to_string(), String::from_utf8(dot)?))?, auth))
+/// The DP parameters of a budget, bounding the rows each privacy unit contributes when set (qrlew bounds it to 100 by default)
+fn dp_parameters(epsilon: f64, delta: f64, max_privacy_unit_contribution: Option<u64>) -> Result<DpParameters> {
+    validate_budget(epsilon, delta)?;
+    let dp_parameters = DpParameters::from_epsilon_delta(epsilon, delta);
+    match max_privacy_unit_contribution {
+        None => Ok(dp_parameters),
+        Some(0) => Err(Error::invalid_request("max_privacy_unit_contribution should be at least 1")),
+        Some(max_contribution) => Ok(dp_parameters.with_privacy_unit_max_multiplicity(max_contribution as f64)),
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use serde_json;
+/// The maximum length of a principal
+const MAX_PRINCIPAL_LENGTH: usize = 256;
 
-    #[test]
-    fn test_dot_serialize() {
-        let request = Dot {
-            dataset: Dataset { tables: vec![
-                Table {
-                    name: "table_1".to_string(),
-                    path: vec!["schema".to_string(), "table_1".to_string()],
-                    schema: Schema { fields: vec![
-                        Field { name: "a".to_string(), data_type: DataType::Float, constraint: None, range: None, possible_values: None },
-                        Field { name: "b".to_string(), data_type: DataType::Integer, constraint: Some(Constraint::Unique), range: None, possible_values: None },
-                    ]},
-                    size: 10000 }
-            ]},
-            query: "SELECT * FROM table_1".to_string(),
-            dark_mode: true,
-        };
+/// Check the principal of a request, if any, is a non-empty, reasonably short, printable name
+fn validate_principal(principal: Option<&str>) -> Result<()> {
+    match principal {
+        Some(principal) if principal.trim().is_empty() => Err(Error::invalid_request("the principal is empty")),
+        Some(principal) if principal.chars().count() > MAX_PRINCIPAL_LENGTH => Err(Error::invalid_request(format!("the principal is longer than {MAX_PRINCIPAL_LENGTH} characters"))),
+        Some(principal) if principal.chars().any(char::is_control) => Err(Error::invalid_request("the principal contains control characters")),
+        _ => Ok(()),
+    }
+}
 
-        println!("{}", serde_json::to_string_pretty(&request).unwrap());
-        println!("{}", serde_json::to_string(&request).unwrap());
+/// Log an audit event of a rewriting, with its principal, query hash and budget (for DP rewritings)
+fn audit(route: &str, principal: Option<&str>, query: &str, budget: Option<(f64, f64)>) {
+    let query_hash = response::sha256_hex(query);
+    match budget {
+        Some((epsilon, delta)) => tracing::info!(target: "audit", route, principal, query_hash, epsilon, delta, "rewrite"),
+        None => tracing::info!(target: "audit", route, principal, query_hash, "rewrite"),
     }
+}
 
-    #[test]
-    fn test_dot_deserialize() {
-        let request_str = r#"{"dataset":{"tables":[{"name":"table_1","path":["schema","table_1"],"schema":{"fields":[{"name":"a","data_type":"Float"},{"name":"b","data_type":"Integer"}]},"size":10000}]},"query":"SELECT * FROM table_1","dark_mode":true}"#;
-        let request: Dot = serde_json::from_str(&request_str).unwrap();
-        println!("{:?}", request);
+/// Reject DP rewritings without privacy unit, which would treat all tables as public, unless explicitly allowed
+fn check_privacy_unit_not_empty(privacy_unit: &PrivacyUnitSpec, token: Option<&str>, allow_no_privacy_unit: bool) -> Result<()> {
+    if privacy_unit.is_empty() && token.is_none() && !allow_no_privacy_unit {
+        return Err(Error::invalid_request("the privacy unit is empty, set allow_no_privacy_unit to rewrite all tables as public"));
     }
+    Ok(())
+}
 
-    #[test]
-    fn test_dot() {
-        let request_str = r#"{"dataset":{"tables":[{"name":"table_1","path":["schema","table_1"],"schema":{"fields":[{"name":"a","data_type":"Float"},{"name":"b","data_type":"Integer"}]},"size":10000}]},"query":"SELECT * FROM table_1","dark_mode":false}"#;
-        let request: Dot = serde_json::from_str(&request_str).unwrap();
-        // println!("{:?}", request.response().unwrap());
-        println!("{}", request.response().unwrap().value());
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
+pub struct RegisterPrivacyUnit {
+    #[schema(value_type = Vec<(String, Vec<(String, String, String)>, String)>)]
+    privacy_unit: PrivacyUnitSpec,
+}
+
+impl RegisterPrivacyUnit {
+    pub fn response(self) -> Result<Response> {
+        validate_privacy_unit(&self.privacy_unit)?;
+        Ok(Response::new(privacy_units().insert(self.privacy_unit)))
     }
+}
 
-    #[test]
-    fn test_rewrite_as_pup_serialize() {
-        let request = RewriteAsPrivacyUnitPreserving {
-            dataset: Dataset { tables: vec![
-                Table {
-                    name: "user_table".to_string(),
-                    path: vec!["schema".to_string(), "user_table".to_string()],
-                    schema: Schema { fields: vec![
-                        Field { name: "id".to_string(), data_type: DataType::Integer, constraint: Some(Constraint::Unique), range: None, possible_values: None },
-                        Field { name: "name".to_string(), data_type: DataType::Text, constraint: None, range: None, possible_values: None },
-                        Field { name: "age".to_string(), data_type: DataType::Integer, constraint: None, range: None, possible_values: None },
-                        Field { name: "weight".to_string(), data_type: DataType::Float, constraint: None, range: None, possible_values: None },
-                    ]},
-                    size: 10000,
-                },
-                Table {
-                    name: "action_table".to_string(),
-                    path: vec!["schema".to_string(), "action_table".to_string()],
-                    schema: Schema { fields: vec![
-                        Field { name: "action".to_string(), data_type: DataType::Text, constraint: None, range: None, possible_values: None },
-                        Field { name: "user_id".to_string(), data_type: DataType::Integer, constraint: None, range: None, possible_values: None },
-                        Field { name: "duration".to_string(), data_type: DataType::Float, constraint: None, range: None, possible_values: None },
-                    ]},
-                    size: 10000,
-                },
-            ]},
-            query: "SELECT * FROM action_table".to_string(),
-            synthetic_data: vec![
-                ("user_table".to_string(), "synthetic_user_table".to_string()),
-                ("action_table".to_string(), "synthetic_action_table".to_string()),
-            ],
-            privacy_unit: vec![
-                ("user_table".to_string(), vec![], "id".to_string()),
-                ("action_table".to_string(), vec![("user_id".to_string(), "user_table".to_string(), "id".to_string())], "id".to_string()),
-            ],
-            epsilon: 1.,
-            delta: 1e-5,
-        };
+/// The columns of each table protected by a privacy unit: all the columns of the tables whose links reach the privacy unit column
+fn protected_columns(privacy_unit: &PrivacyUnitSpec, dataset: &Dataset) -> BTreeMap<String, Vec<String>> {
+    let table = |name: &str| dataset.tables.iter().find(|table| table.name == name);
+    let has_column = |table: &Table, column: &str| table.schema.fields.iter().any(|field| field.name == column);
+    privacy_unit.iter().filter_map(|(name, links, privacy_unit_column)| {
+        let source = table(name)?;
+        let mut current = source;
+        for (source_column, target, target_column) in links {
+            let target = table(target)?;
+            if !has_column(current, source_column) || !has_column(target, target_column) {
+                return None;
+            }
+            current = target;
+        }
+        has_column(current, privacy_unit_column).then(|| (source.name.clone(), source.schema.fields.iter().map(|field| field.name.clone()).collect()))
+    }).collect()
+}
 
-        println!("{}", serde_json::to_string_pretty(&request).unwrap());
-        println!("{}", serde_json::to_string(&request).unwrap());
+/// Compare the protection of the columns of a dataset under two privacy units
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
+pub struct PrivacyUnitDiff {
+    dataset: Dataset,
+    #[schema(value_type = Vec<(String, Vec<(String, String, String)>, String)>)]
+    before: PrivacyUnitSpec,
+    #[schema(value_type = Vec<(String, Vec<(String, String, String)>, String)>)]
+    after: PrivacyUnitSpec,
+    /// Derive the links of tables missing from the privacy units from their foreign keys
+    #[serde(default)]
+    infer_privacy_unit_links: bool,
+}
+
+/// The columns gaining or losing protection, by table, and the tables protected through another path
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
+pub struct ProtectionChanges {
+    gained: BTreeMap<String, Vec<String>>,
+    lost: BTreeMap<String, Vec<String>>,
+    changed: Vec<String>,
+}
+
+impl PrivacyUnitDiff {
+    pub fn response(self) -> Result<Response> {
+        validate_privacy_unit(&self.before)?;
+        validate_privacy_unit(&self.after)?;
+        let (before, after) = if self.infer_privacy_unit_links {
+            (infer_privacy_unit_links(&self.before, &self.dataset), infer_privacy_unit_links(&self.after, &self.dataset))
+        } else {
+            (self.before, self.after)
+        };
+        let (protected_before, protected_after) = (protected_columns(&before, &self.dataset), protected_columns(&after, &self.dataset));
+        let difference = |left: &BTreeMap<String, Vec<String>>, right: &BTreeMap<String, Vec<String>>| left.iter()
+            .filter(|(table, _)| !right.contains_key(*table))
+            .map(|(table, columns)| (table.clone(), columns.clone()))
+            .collect();
+        let definition = |privacy_unit: &PrivacyUnitSpec, table: &str| privacy_unit.iter().find(|(name, _, _)| name == table).cloned();
+        let changes = ProtectionChanges {
+            gained: difference(&protected_after, &protected_before),
+            lost: difference(&protected_before, &protected_after),
+            changed: protected_before.keys()
+                .filter(|table| protected_after.contains_key(*table) && definition(&before, table) != definition(&after, table))
+                .cloned()
+                .collect(),
+        };
+        Ok(Response::new(serde_json::to_string(&changes)?))
     }
+}
 
-    #[test]
-    fn test_rewrite_as_pup_deserialize() {
-        let request_str = r#"{"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"name","data_type":"Text"},{"name":"age","data_type":"Integer"},{"name":"weight","data_type":"Float"}]},"size":10000},{"name":"action_table","path":["schema","action_table"],"schema":{"fields":[{"name":"action","data_type":"Text"},{"name":"user_id","data_type":"Integer"},{"name":"duration","data_type":"Float"}]},"size":10000}]},"query":"SELECT * FROM action_table","synthetic_data":[["user_table","synthetic_user_table"],["action_table","synthetic_action_table"]],"privacy_unit":[["user_table",[],"id"],["action_table",[["user_id","user_table","id"]],"id"]],"epsilon":1.0,"delta":0.00001}"#;
-        let request: RewriteAsPrivacyUnitPreserving = serde_json::from_str(&request_str).unwrap();
-        println!("{:?}", request);
+/// Parse a query, only a single read-only SELECT statement is accepted
+fn parse_query(query: &str) -> Result<Query> {
+    let statements = Parser::parse_sql(&GenericDialect, query).map_err(qrlew::sql::Error::from)?;
+    match statements.as_slice() {
+        [Statement::Query(query)] if is_read_only(query) => {
+            let mut query = query.as_ref().clone();
+            let _ = sqlparser::ast::VisitMut::visit(&mut query, &mut CommaJoins);
+            Ok(query)
+        }
+        [statement] => Err(Error::invalid_request(format!("only SELECT queries are accepted: {statement}"))),
+        _ => Err(Error::invalid_request(format!("a single SELECT statement is expected, got {}", statements.len()))),
     }
+}
 
-    #[test]
-    fn test_rewrite_as_pup() {
-        let request_str = r#"{"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"name","data_type":"Text"},{"name":"age","data_type":"Integer"},{"name":"weight","data_type":"Float"}]},"size":10000},{"name":"action_table","path":["schema","action_table"],"schema":{"fields":[{"name":"action","data_type":"Text"},{"name":"user_id","data_type":"Integer"},{"name":"duration","data_type":"Float"}]},"size":10000}]},"query":"SELECT * FROM action_table","synthetic_data":[["user_table","synthetic_user_table"],["action_table","synthetic_action_table"]],"privacy_unit":[["user_table",[],"id"],["action_table",[["user_id","user_table","id"]],"id"]],"epsilon":1.0,"delta":0.00001}"#;
-        let request: RewriteAsPrivacyUnitPreserving = serde_json::from_str(&request_str).unwrap();
-        println!("{:?}", request.response().unwrap());
+/// Rewrite the comma separated items of `FROM` clauses as cross joins, qrlew only reading the first item:
+/// `FROM a, b WHERE a.id = b.id` becomes `FROM a CROSS JOIN b WHERE a.id = b.id`
+struct CommaJoins;
+
+impl CommaJoins {
+    fn cross_join_items(set_expr: &mut SetExpr) {
+        match set_expr {
+            SetExpr::Select(select) if select.from.len() > 1 => {
+                let mut items = select.from.drain(..);
+                let mut from = items.next().unwrap();
+                for item in items {
+                    from.joins.push(ast::Join { relation: item.relation, join_operator: ast::JoinOperator::CrossJoin });
+                    from.joins.extend(item.joins);
+                }
+                select.from = vec![from];
+            }
+            SetExpr::SetOperation { left, right, .. } => {
+                Self::cross_join_items(left);
+                Self::cross_join_items(right);
+            }
+            _ => (),
+        }
     }
+}
 
-    #[test]
+impl sqlparser::ast::VisitorMut for CommaJoins {
+    type Break = ();
+
+    fn pre_visit_query(&mut self, query: &mut Query) -> ControlFlow<()> {
+        Self::cross_join_items(&mut query.body);
+        ControlFlow::Continue(())
+    }
+}
+
+/// Check a query does not contain data modification statements
+fn is_read_only(query: &Query) -> bool {
+    fn set_expr_is_read_only(set_expr: &SetExpr) -> bool {
+        match set_expr {
+            SetExpr::Select(select) => select.into.is_none(),
+            SetExpr::Query(query) => is_read_only(query),
+            SetExpr::SetOperation { left, right, .. } => set_expr_is_read_only(left) && set_expr_is_read_only(right),
+            SetExpr::Values(_) | SetExpr::Table(_) => true,
+            SetExpr::Insert(_) | SetExpr::Update(_) => false,
+        }
+    }
+    query.with.as_ref().is_none_or(|with| with.cte_tables.iter().all(|cte| is_read_only(&cte.query)))
+        && set_expr_is_read_only(&query.body)
+}
+
+/// The names a query may reference as columns: the columns of the dataset and the aliases defined in the query
+struct DeclaredNames<'a> {
+    dataset: &'a Dataset,
+    aliases: BTreeSet<String>,
+    references: Vec<Ident>,
+}
+
+impl DeclaredNames<'_> {
+    fn declare(&mut self, ident: &Ident) {
+        self.aliases.insert(ident.value.to_lowercase());
+    }
+
+    /// Unquoted identifiers are case insensitive
+    fn is_declared(&self, ident: &Ident) -> bool {
+        let matches = |name: &str| if ident.quote_style.is_some() { name == ident.value } else { name.eq_ignore_ascii_case(&ident.value) };
+        self.dataset.tables.iter().flat_map(|table| &table.schema.fields).any(|field| matches(&field.name))
+            || self.aliases.contains(&ident.value.to_lowercase())
+    }
+}
+
+impl sqlparser::ast::Visitor for DeclaredNames<'_> {
+    type Break = ();
+
+    fn pre_visit_query(&mut self, query: &Query) -> ControlFlow<()> {
+        for cte in query.with.iter().flat_map(|with| &with.cte_tables) {
+            self.declare(&cte.alias.name);
+            cte.alias.columns.iter().for_each(|column| self.declare(column));
+        }
+        if let SetExpr::Select(select) = query.body.as_ref() {
+            for item in &select.projection {
+                if let SelectItem::ExprWithAlias { alias, .. } = item {
+                    self.declare(alias);
+                }
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_table_factor(&mut self, table_factor: &TableFactor) -> ControlFlow<()> {
+        let alias = match table_factor {
+            TableFactor::Table { name, alias, .. } => {
+                name.0.iter().for_each(|part| self.declare(part));
+                alias
+            }
+            TableFactor::Derived { alias, .. } | TableFactor::NestedJoin { alias, .. } => alias,
+            _ => &None,
+        };
+        if let Some(alias) = alias {
+            self.declare(&alias.name);
+            alias.columns.iter().for_each(|column| self.declare(column));
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_expr(&mut self, expr: &ast::Expr) -> ControlFlow<()> {
+        match expr {
+            ast::Expr::Identifier(ident) => self.references.push(ident.clone()),
+            ast::Expr::CompoundIdentifier(idents) => self.references.extend(idents.last().cloned()),
+            _ => (),
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+/// Reject queries referencing columns declared neither in the dataset nor as aliases in the query
+fn check_declared_columns(query: &Query, dataset: &Dataset) -> Result<()> {
+    let mut names = DeclaredNames { dataset, aliases: BTreeSet::new(), references: vec![] };
+    let _ = sqlparser::ast::Visit::visit(query, &mut names);
+    let undeclared: BTreeSet<String> = names.references.iter().filter(|ident| !names.is_declared(ident)).map(|ident| ident.value.clone()).collect();
+    if undeclared.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::invalid_request(format!("undeclared columns: {}", undeclared.into_iter().collect::<Vec<_>>().join(", "))))
+    }
+}
+
+/// List the aggregations qrlew cannot compile into DP (mirrors qrlew's rewriting rules)
+fn unsupported_aggregations(relation: &Relation) -> Vec<String> {
+    let mut aggregations: Vec<String> = vec![];
+    for relation in relation.iter() {
+        if let Relation::Reduce(reduce) = relation {
+            for aggregate in reduce.aggregate() {
+                let supported = match aggregate.aggregate() {
+                    Aggregate::Mean | Aggregate::MeanDistinct | Aggregate::Count | Aggregate::CountDistinct
+                    | Aggregate::Sum | Aggregate::SumDistinct | Aggregate::Std | Aggregate::StdDistinct
+                    | Aggregate::Var | Aggregate::VarDistinct => true,
+                    Aggregate::Min | Aggregate::Max | Aggregate::Median | Aggregate::First | Aggregate::Last
+                    | Aggregate::Quantile(_) | Aggregate::Quantiles(_) => reduce.group_by().contains(aggregate.column()),
+                    _ => false,
+                };
+                let name = aggregate.aggregate().to_string().to_uppercase();
+                if !supported && !aggregations.contains(&name) {
+                    aggregations.push(name);
+                }
+            }
+        }
+    }
+    aggregations
+}
+
+/// Maps the path of a real table to the path of its synthetic table, if it has one
+pub type SyntheticTableResolver = dyn Fn(&[String]) -> Option<Vec<String>> + Send + Sync;
+
+/// The synthetic table resolver registered at startup
+static SYNTHETIC_TABLE_RESOLVER: OnceLock<Box<SyntheticTableResolver>> = OnceLock::new();
+
+/// Register the resolver of the synthetic tables of requests without `synthetic_data`, once at startup
+pub fn set_synthetic_table_resolver(resolver: impl Fn(&[String]) -> Option<Vec<String>> + Send + Sync + 'static) -> Result<()> {
+    SYNTHETIC_TABLE_RESOLVER.set(Box::new(resolver)).map_err(|_| Error::other("a synthetic table resolver is already registered"))
+}
+
+fn synthetic_table_resolver() -> Option<&'static SyntheticTableResolver> {
+    SYNTHETIC_TABLE_RESOLVER.get().map(Box::as_ref)
+}
+
+/// Check the real tables of a `synthetic_data` mapping are tables of the dataset, designated by the last element or by all the elements of their path
+fn check_synthetic_data(synthetic_data: &[(String, String)], dataset: &Dataset) -> Result<()> {
+    for (table, _) in synthetic_data {
+        if !dataset.tables.iter().any(|candidate| candidate.path.last() == Some(table) || candidate.path.join(".") == *table) {
+            return Err(Error::invalid_request(format!("the synthetic_data mapping references the unknown table `{table}`")));
+        }
+    }
+    Ok(())
+}
+
+/// The synthetic data of a request: its `synthetic_data` mapping, or the synthetic tables of the resolver when the mapping is empty
+fn synthetic_data(mapping: Vec<(String, String)>, relations: &Hierarchy<Arc<Relation>>, resolver: Option<&SyntheticTableResolver>) -> Option<SyntheticData> {
+    if !mapping.is_empty() {
+        return Some(SyntheticData::new(mapping.into_iter().map(|(table, synthetic_table)| (Identifier::from(table), Identifier::from(synthetic_table))).collect()));
+    }
+    let resolved: Hierarchy<Identifier> = relations.keys().filter_map(|path| Some((path.clone(), Identifier::from(resolver?(path)?)))).collect();
+    (!resolved.is_empty()).then(|| SyntheticData::new(resolved))
+}
+
+/// The relation of a query reading only the synthetic tables
+fn synthetic_relation(query: &Query, relations: &Hierarchy<Arc<Relation>>, synthetic_data: Option<&SyntheticData>) -> Result<Relation> {
+    let synthetic_data = synthetic_data.ok_or_else(|| Error::invalid_request("a synthetic_data mapping is required in synthetic data only mode"))?;
+    // Tables without synthetic equivalent are removed so that real tables are never read
+    let synthetic_relations = relations.filter_map(|relation| match relation.as_ref() {
+        Relation::Table(table) => synthetic_data.table(table).ok().map(|sd_relation| Arc::new(Relation::from(sd_relation))),
+        _ => None,
+    });
+    Ok(Relation::try_from(query.with(&synthetic_relations))?)
+}
+
+/// In synthetic data only mode (`QRLEW_SYNTHETIC_DATA_ONLY`), the relation of a query on synthetic data
+fn synthetic_data_only_relation(query: &Query, relations: &Hierarchy<Arc<Relation>>, synthetic_data: Option<&SyntheticData>) -> Result<Option<Relation>> {
+    env_flag("QRLEW_SYNTHETIC_DATA_ONLY").then(|| synthetic_relation(query, relations, synthetic_data)).transpose()
+}
+
+/// Whether some rows of the protected tables (named as in the privacy unit) reach the output without being aggregated
+fn has_row_level_output(relation: &Relation, protected_tables: &[&str]) -> bool {
+    match relation {
+        Relation::Table(table) => protected_tables.iter().any(|name| table.name() == *name || table.path().last().is_ok_and(|last| last == *name)),
+        Relation::Reduce(_) | Relation::Values(_) => false,
+        _ => relation.inputs().into_iter().any(|input| has_row_level_output(input, protected_tables)),
+    }
+}
+
+/// The positive delta pure epsilon-DP budgets are rewritten with, qrlew failing on a null delta when adding noise
+const PURE_DP_REWRITING_DELTA: f64 = 1e-9;
+
+/// The delta given to qrlew: rewritings of pure epsilon-DP budgets are only kept when adding no noise, hence independent of delta (see `check_pure_dp`)
+fn rewriting_delta(delta: f64) -> f64 {
+    if delta == 0. { PURE_DP_REWRITING_DELTA } else { delta }
+}
+
+/// Reject pure epsilon-DP (delta = 0) for rewritings adding noise: qrlew only adds Gaussian noise, unbounded without delta
+fn check_pure_dp(delta: f64, dp_event: &DpEvent) -> Result<()> {
+    if delta == 0. && !dp_event.is_no_op() {
+        return Err(Error::invalid_request("pure epsilon-DP (delta = 0) is not supported for queries needing noise, qrlew only implements the Gaussian mechanism: set a positive delta"));
+    }
+    Ok(())
+}
+
+/// In strict DP mode (`QRLEW_STRICT_DP`), reject queries outputting rows of the tables covered by the privacy unit, public tables being left as is
+fn check_strict_dp(strict_dp: bool, relation: &Relation, privacy_unit: &PrivacyUnit) -> Result<()> {
+    let protected_tables: Vec<&str> = privacy_unit.iter().map(|(name, _)| name.as_str()).collect();
+    if strict_dp && has_row_level_output(relation, &protected_tables) {
+        return Err(Error::unsupported_aggregations("strict DP mode requires the rows of every table covered by the privacy unit to be aggregated", vec![]));
+    }
+    Ok(())
+}
+
+/// The input index, counted from the left, each field of a tree of joins comes from,
+/// along with the ranges of inputs on the left and on the right of each cross join of the tree
+fn join_inputs<'a>(relation: &'a Relation, next_input: &mut usize, cross_joins: &mut Vec<(&'a qrlew::relation::Join, Range<usize>, Range<usize>)>) -> Vec<usize> {
+    match relation {
+        Relation::Join(join) => {
+            let start = *next_input;
+            let mut inputs = join_inputs(join.left(), next_input, cross_joins);
+            let middle = *next_input;
+            inputs.extend(join_inputs(join.right(), next_input, cross_joins));
+            if matches!(join.operator(), qrlew::relation::JoinOperator::Cross) {
+                cross_joins.push((join, start..middle, middle..*next_input));
+            }
+            inputs
+        }
+        _ => {
+            *next_input += 1;
+            vec![*next_input - 1; relation.schema().len()]
+        }
+    }
+}
+
+/// The terms of a conjunction
+fn conjuncts(expr: &Expr) -> Vec<Expr> {
+    match expr {
+        Expr::Function(function) if function.function() == Function::And => function.arguments().iter().flat_map(conjuncts).collect(),
+        expr => vec![expr.clone()],
+    }
+}
+
+/// The cross joins of a relation linked by a condition of the `WHERE` clause reading them, e.g. `FROM a CROSS JOIN b WHERE a.id = b.id`:
+/// some term of the filter reading the joins references a column of each side
+fn linked_cross_joins(relation: &Relation) -> Vec<&qrlew::relation::Join> {
+    relation.iter().flat_map(|node| {
+        let Relation::Map(map) = node else { return vec![] };
+        let (Some(filter), join @ Relation::Join(_)) = (map.filter(), map.input()) else { return vec![] };
+        let mut cross_joins = vec![];
+        let inputs = join_inputs(join, &mut 0, &mut cross_joins);
+        let fields: Vec<&str> = join.schema().iter().map(|field| field.name()).collect();
+        let terms: Vec<Vec<usize>> = conjuncts(filter).iter()
+            .map(|term| term.columns().into_iter().filter_map(|column| fields.iter().position(|field| column.last().is_ok_and(|name| name == *field))).map(|index| inputs[index]).collect())
+            .collect();
+        cross_joins.into_iter()
+            .filter(|(_, left, right)| terms.iter().any(|term| term.iter().any(|input| left.contains(input)) && term.iter().any(|input| right.contains(input))))
+            .map(|(join, _, _)| join)
+            .collect()
+    }).collect()
+}
+
+/// Reject joins without a join condition, in their `ON` or `WHERE` clause, unless `QRLEW_ALLOW_CROSS_JOINS` is set
+fn check_cross_joins(relation: &Relation) -> Result<()> {
+    let linked = linked_cross_joins(relation);
+    let has_cross_join = relation.iter().any(|node| matches!(node, Relation::Join(join) if matches!(join.operator(), qrlew::relation::JoinOperator::Cross) && !linked.iter().any(|linked| std::ptr::eq(*linked, join))));
+    if has_cross_join && !env_flag("QRLEW_ALLOW_CROSS_JOINS") {
+        return Err(Error::invalid_request("joins without a join condition (cartesian products) are not accepted"));
+    }
+    Ok(())
+}
+
+/// Rename the output columns of a query body, a wildcard projection on `columns` is first made explicit
+fn rename_output_columns(body: &mut SetExpr, columns: &[&str], output_aliases: &HashMap<String, String>, dialect: Option<Dialect>) {
+    match body {
+        SetExpr::Select(select) => {
+            if matches!(select.projection.as_slice(), [SelectItem::Wildcard(_)]) {
+                select.projection = columns.iter().map(|column| SelectItem::ExprWithAlias {
+                    expr: qrlew::ast::Expr::Identifier(dialect_ident(column, dialect)),
+                    alias: dialect_ident(column, dialect),
+                }).collect();
+            }
+            for item in select.projection.iter_mut() {
+                if let SelectItem::ExprWithAlias { alias, .. } = item {
+                    if let Some(output_alias) = output_aliases.get(&alias.value) {
+                        *alias = dialect_ident(output_alias, dialect);
+                    }
+                }
+            }
+        }
+        SetExpr::Query(query) => rename_output_columns(&mut query.body, columns, output_aliases, dialect),
+        SetExpr::SetOperation { left, right, .. } => {
+            rename_output_columns(left, columns, output_aliases, dialect);
+            rename_output_columns(right, columns, output_aliases, dialect);
+        }
+        _ => (),
+    }
+}
+
+/// Render a relation as a query whose output columns are renamed by `output_aliases`
+fn query_with_output_aliases(relation: &Relation, output_aliases: &HashMap<String, String>, dialect: Option<Dialect>) -> Result<Query> {
+    let mut query = dialect_query(relation, dialect);
+    if output_aliases.is_empty() {
+        return Ok(query);
+    }
+    let columns: Vec<&str> = relation.schema().iter().map(|field| field.name()).collect();
+    if let Some(column) = output_aliases.keys().find(|column| !columns.contains(&column.as_str())) {
+        return Err(Error::invalid_request(format!("unknown output column `{column}` in output_aliases")));
+    }
+    rename_output_columns(&mut query.body, &columns, output_aliases, dialect);
+    Ok(query)
+}
+
+/// A SQL dialect of rendered queries
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, JsonSchema, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum Dialect {
+    Postgres,
+    Bigquery,
+    Mssql,
+}
+
+/// The query of a relation, in a given dialect
+fn dialect_query(relation: &Relation, dialect: Option<Dialect>) -> Query {
+    match dialect {
+        None => Query::from(relation),
+        Some(Dialect::Postgres) => Query::from(RelationWithTranslator(relation, PostgreSqlTranslator)),
+        Some(Dialect::Bigquery) => Query::from(RelationWithTranslator(relation, BigQueryTranslator)),
+        Some(Dialect::Mssql) => Query::from(RelationWithTranslator(relation, MsSqlTranslator)),
+    }
+}
+
+/// A column name quoted as the identifiers of a dialect, e.g. with backticks in BigQuery
+fn dialect_ident(name: &str, dialect: Option<Dialect>) -> Ident {
+    let identifier = Identifier::from_name(name);
+    let idents = match dialect {
+        Some(Dialect::Bigquery) => BigQueryTranslator.identifier(&identifier),
+        Some(Dialect::Mssql) => MsSqlTranslator.identifier(&identifier),
+        None | Some(Dialect::Postgres) => PostgreSqlTranslator.identifier(&identifier),
+    };
+    idents.into_iter().next().unwrap_or_else(|| Ident::with_quote('"', name))
+}
+
+/// A representation of a rewritten relation
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize, JsonSchema, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum OutputFormat {
+    Sql,
+    Dot,
+    Ast,
+}
+
+/// The placeholder of the rewritten query in wrapper templates
+const WRAPPER_TEMPLATE_PLACEHOLDER: &str = "{query}";
+
+/// Embed a query in a template containing exactly one placeholder, e.g. `INSERT INTO results {query}`
+fn wrap_query(query: &Query, wrapper_template: Option<&str>) -> Result<String> {
+    match wrapper_template {
+        None => Ok(query.to_string()),
+        Some(template) if template.matches(WRAPPER_TEMPLATE_PLACEHOLDER).count() == 1 => Ok(template.replace(WRAPPER_TEMPLATE_PLACEHOLDER, &query.to_string())),
+        Some(template) => Err(Error::invalid_request(format!("the wrapper template `{template}` should contain {WRAPPER_TEMPLATE_PLACEHOLDER} exactly once"))),
+    }
+}
+
+/// Render a rewritten relation, as its (wrapped) SQL query alone or as a JSON map of the requested formats
+fn render(relation: &Relation, query: Query, formats: &[OutputFormat], wrapper_template: Option<&str>) -> Result<String> {
+    let sql = wrap_query(&query, wrapper_template)?;
+    if formats.is_empty() {
+        return Ok(sql);
+    }
+    let rendered = formats.iter().map(|format| Ok((*format, match format {
+        OutputFormat::Sql => Value::String(sql.clone()),
+        OutputFormat::Dot => {
+            let mut dot = Vec::new();
+            relation.dot(&mut dot, &[])?;
+            Value::String(String::from_utf8(dot)?)
+        }
+        OutputFormat::Ast => serde_json::to_value(&query)?,
+    }))).collect::<Result<BTreeMap<OutputFormat, Value>>>()?;
+    Ok(serde_json::to_string(&rendered)?)
+}
+
+/// The output columns of a relation qrlew knows to be unique
+fn unique_columns(relation: &Relation) -> Vec<String> {
+    relation.schema().iter()
+        .filter(|field| matches!(field.constraint(), Some(qrlew::relation::Constraint::Unique | qrlew::relation::Constraint::PrimaryKey)))
+        .map(|field| field.name().to_string())
+        .collect()
+}
+
+/// Group the dot nodes depending on a single source table into Graphviz clusters
+fn cluster_by_table(relation: &Relation, dot: &str) -> String {
+    let mut clusters: Vec<(String, Vec<String>)> = vec![];
+    for node in relation.iter() {
+        let mut tables: Vec<&str> = node.iter().filter_map(|r| match r {
+            Relation::Table(table) => Some(table.name()),
+            _ => None,
+        }).collect();
+        tables.dedup();
+        if let [table] = tables.as_slice() {
+            let node_id = namer::name_from_content("graph", node);
+            match clusters.iter_mut().find(|(name, _)| name == table) {
+                Some((_, node_ids)) if !node_ids.contains(&node_id) => node_ids.push(node_id),
+                Some(_) => (),
+                None => clusters.push((table.to_string(), vec![node_id])),
+            }
+        }
+    }
+    let subgraphs: String = clusters.iter().enumerate().map(|(i, (table, node_ids))| format!(
+        "    subgraph cluster_{i} {{\n        label=\"{}\";\n        {};\n    }}\n",
+        table.replace('"', "\\\""),
+        node_ids.join("; "),
+    )).collect();
+    match dot.rfind('}') {
+        Some(end) => format!("{}{}{}", &dot[..end], subgraphs, &dot[end..]),
+        None => dot.to_string(),
+    }
+}
+
+/// A SHA-256 of the query qrlew renders from a relation, independent of the formatting of the SQL it was parsed from.
+/// qrlew derives the names of intermediate relations from their content, so fingerprints are only comparable for a given qrlew version
+fn relation_fingerprint(relation: &Relation) -> String {
+    response::sha256_hex(Query::from(relation).to_string())
+}
+
+/// The dot graph of a relation, rendered once for a given relation and style
+fn rendered_dot(relation: &Relation, dark_mode: bool) -> Result<String> {
+    let mut hasher = std::hash::DefaultHasher::new();
+    (relation, dark_mode).hash(&mut hasher);
+    let key = hasher.finish();
+    if let Some(dot) = dot_cache().get(&key) {
+        return Ok(dot);
+    }
+    let mut dot = Vec::new();
+    relation.dot(&mut dot, if dark_mode {&["dark"]} else {&[]})?;
+    let dot = String::from_utf8(dot)?;
+    dot_cache().insert(key, dot.clone());
+    Ok(dot)
+}
+
+/// Rewrite a dot graph in the subset of the language understood by the layout engine:
+/// HTML-like labels (`label=<...>`) become plain text, one line per `<br/>`, and chained attribute lists are merged
+fn simplify_dot(dot: &str) -> String {
+    const HTML_LABEL: &str = "label=<";
+    let mut plain = String::with_capacity(dot.len());
+    let mut rest = dot;
+    while let Some(start) = rest.find(HTML_LABEL) {
+        plain.push_str(&rest[..start]);
+        rest = &rest[start + HTML_LABEL.len()..];
+        // The label ends at the matching `>`
+        let mut depth = 1;
+        let end = rest.char_indices().find_map(|(i, c)| {
+            depth += match c { '<' => 1, '>' => -1, _ => 0 };
+            (depth == 0).then_some(i)
+        }).unwrap_or(rest.len());
+        let mut text = String::new();
+        let mut in_tag = false;
+        for c in rest[..end].replace("<br/>", "\\n").chars() {
+            match c {
+                '<' => in_tag = true,
+                '>' => in_tag = false,
+                '"' if !in_tag => text.push_str("\\\""),
+                _ if !in_tag => text.push(c),
+                _ => (),
+            }
+        }
+        let text = text.replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&");
+        plain.push_str(&format!("label=\"{text}\""));
+        rest = &rest[(end + 1).min(rest.len())..];
+    }
+    plain.push_str(rest);
+    plain.replace("\"][", "\", ")
+}
+
+/// Lay out a dot graph as an SVG image
+fn dot_to_svg(dot: &str) -> Result<String> {
+    let graph = DotParser::new(&simplify_dot(dot)).process().map_err(|err| Error::other(format!("cannot render the dot graph: {err}")))?;
+    // The layout engine panics on some unsupported graphs
+    std::panic::catch_unwind(|| {
+        let mut builder = GraphBuilder::new();
+        builder.visit_graph(&graph);
+        let mut svg = SVGWriter::new();
+        builder.get().do_it(false, false, false, &mut svg);
+        svg.finalize()
+    }).map_err(|_| Error::other("cannot lay out the dot graph"))
+}
+
+/// Opaque tokens for the table (`t1`, `t2`...) and column (`c1`, `c2`...) names of a dataset, in order of appearance
+fn anonymization_tokens(dataset: &Dataset) -> (HashMap<String, String>, HashMap<String, String>) {
+    let mut tables = HashMap::new();
+    let mut columns = HashMap::new();
+    for table in &dataset.tables {
+        let table_token = format!("t{}", tables.len() + 1);
+        // Table names are upper cased in node titles
+        tables.entry(table.name.to_uppercase()).or_insert(table_token);
+        for field in &table.schema.fields {
+            let column_token = format!("c{}", columns.len() + 1);
+            columns.entry(field.name.clone()).or_insert(column_token);
+        }
+    }
+    (tables, columns)
+}
+
+/// Replace the words (runs of alphanumeric characters and underscores) of a text having a token, HTML entities are kept as is
+fn replace_words(text: &str, token: impl Fn(&str) -> Option<String>) -> String {
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+    let mut replaced = String::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let end = if c == '&' {
+            rest.find(';').map_or(rest.len(), |end| end + 1)
+        } else if is_word_char(c) {
+            rest.find(|c: char| !is_word_char(c)).unwrap_or(rest.len())
+        } else {
+            c.len_utf8()
+        };
+        let word = &rest[..end];
+        match is_word_char(c).then(|| token(word)).flatten() {
+            Some(token) => replaced.push_str(&token),
+            None => replaced.push_str(word),
+        }
+        rest = &rest[end..];
+    }
+    replaced
+}
+
+/// Replace table and column names with their tokens in the labels of a dot graph, table names in node titles and cluster labels, column names elsewhere
+fn anonymize_dot(dot: &str, tables: &HashMap<String, String>, columns: &HashMap<String, String>) -> String {
+    let table_token = |word: &str| tables.get(&word.to_uppercase()).cloned();
+    let column_token = |word: &str| columns.get(word).cloned();
+    let mut anonymized = String::new();
+    let mut rest = dot;
+    while let Some(start) = rest.find("label=") {
+        let (before, label) = rest.split_at(start + "label=".len());
+        anonymized.push_str(before);
+        let end = if let Some(html) = label.strip_prefix('<') {
+            // Node labels are HTML-like, titles are in bold
+            let end = html.find(">]").unwrap_or(html.len());
+            anonymized.push('<');
+            let mut bold = false;
+            let mut text = &html[..end];
+            while !text.is_empty() {
+                let text_end = if text.starts_with('<') { text.find('>').map_or(text.len(), |end| end + 1) } else { text.find('<').unwrap_or(text.len()) };
+                let segment = &text[..text_end];
+                match segment {
+                    "<b>" => bold = true,
+                    "</b>" => bold = false,
+                    _ => (),
+                }
+                if segment.starts_with('<') {
+                    anonymized.push_str(segment);
+                } else if bold {
+                    anonymized.push_str(&replace_words(segment, table_token));
+                } else {
+                    anonymized.push_str(&replace_words(segment, column_token));
+                }
+                text = &text[text_end..];
+            }
+            end + 1
+        } else if let Some(quoted) = label.strip_prefix('"') {
+            // Cluster labels are table names
+            let end = quoted.find('"').unwrap_or(quoted.len());
+            anonymized.push('"');
+            anonymized.push_str(&replace_words(&quoted[..end], table_token));
+            end + 1
+        } else {
+            0
+        };
+        rest = &label[end..];
+    }
+    anonymized.push_str(rest);
+    anonymized
+}
+
+/// The mechanisms of a DP event, flattening compositions and samplings
+fn mechanisms(dp_event: &DpEvent) -> Vec<String> {
+    match dp_event {
+        DpEvent::NoOp => vec![],
+        DpEvent::Gaussian { noise_multiplier } => vec![format!("Gaussian noise (noise multiplier {noise_multiplier:.3})")],
+        DpEvent::Laplace { noise_multiplier } => vec![format!("Laplace noise (noise multiplier {noise_multiplier:.3})")],
+        DpEvent::EpsilonDelta { epsilon, delta } => vec![format!("a thresholding of rare groups (epsilon {epsilon}, delta {delta})")],
+        DpEvent::Composed { events } => events.iter().flat_map(mechanisms).collect(),
+        DpEvent::PoissonSampled { event, .. }
+        | DpEvent::SampledWithReplacement { event, .. }
+        | DpEvent::SampledWithoutReplacement { event, .. } => mechanisms(event),
+    }
+}
+
+/// The highest count of privacy units under which groups are suppressed, if the rewriting thresholds groups
+fn suppression_threshold(dp_event: &DpEvent) -> Option<f64> {
+    match dp_event {
+        DpEvent::EpsilonDelta { epsilon, delta } => Some(qrlew::differential_privacy::dp_event::gaussian_tau(*epsilon, *delta, 1.0)),
+        DpEvent::Composed { events } => events.iter().filter_map(suppression_threshold).reduce(f64::max),
+        DpEvent::PoissonSampled { event, .. }
+        | DpEvent::SampledWithReplacement { event, .. }
+        | DpEvent::SampledWithoutReplacement { event, .. } => suppression_threshold(event),
+        _ => None,
+    }
+}
+
+/// The standard deviation of the Gaussian noise added by an expression (`sigma * sqrt(-2 ln(u)) * cos(2 pi v)`), if any
+fn gaussian_noise_scale(expr: &Expr) -> Option<f64> {
+    let is_function = |expr: &Expr, expected: Function| matches!(expr, Expr::Function(function) if function.function() == expected);
+    expr.iter().find_map(|node| match node {
+        Expr::Function(function) if function.function() == Function::Multiply => match function.arguments().as_slice() {
+            [Expr::Value(sigma), Expr::Function(noise)] if noise.function() == Function::Multiply => match noise.arguments().as_slice() {
+                [sqrt, cos] if is_function(sqrt, Function::Sqrt) && is_function(cos, Function::Cos) => f64::try_from(sigma.clone()).ok(),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+/// The events of a DP event, flattening compositions and samplings
+fn dp_events(dp_event: &DpEvent) -> Vec<&DpEvent> {
+    match dp_event {
+        DpEvent::Composed { events } => events.iter().flat_map(dp_events).collect(),
+        DpEvent::PoissonSampled { event, .. }
+        | DpEvent::SampledWithReplacement { event, .. }
+        | DpEvent::SampledWithoutReplacement { event, .. } => dp_events(event),
+        event => vec![event],
+    }
+}
+
+/// The (epsilon, delta) spent by the DP rewriting of a reduce under basic composition, from the events it added:
+/// the tau-thresholding of its groups (`EpsilonDelta`) spends its own budget, and the `Gaussian` noises of its aggregations
+/// share what the thresholding left of its parameters, `1 - tau_thresholding_share` of them or all of them,
+/// however many `DISTINCT` splits qrlew spreads them over
+fn reduce_consumed_budget(events: &[&DpEvent], dp_parameters: &DpParameters) -> (f64, f64) {
+    let (thresholding_epsilon, thresholding_delta) = events.iter().fold((0., 0.), |(epsilon, delta), event| match event {
+        DpEvent::EpsilonDelta { epsilon: event_epsilon, delta: event_delta } => (epsilon + event_epsilon, delta + event_delta),
+        _ => (epsilon, delta),
+    });
+    let thresholded = events.iter().any(|event| matches!(event, DpEvent::EpsilonDelta { .. }));
+    let share = match (events.iter().any(|event| matches!(event, DpEvent::Gaussian { .. })), thresholded) {
+        (false, _) => 0.,
+        (true, true) => 1. - dp_parameters.tau_thresholding_share,
+        (true, false) => 1.,
+    };
+    (thresholding_epsilon + share * dp_parameters.epsilon, thresholding_delta + share * dp_parameters.delta)
+}
+
+/// The qrlew `Rewriter`, adding up the budget spent by each reduce it rewrites with differential privacy
+struct BudgetRewriter<'a, 'b> {
+    rewriter: Rewriter<'a>,
+    consumed_budget: &'b Cell<(f64, f64)>,
+}
+
+impl<'a> RewriteVisitor<'a> for BudgetRewriter<'a, '_> {
+    fn table(&self, table: &'a qrlew::relation::Table, rewriting_rule: &'a RewritingRule) -> RelationWithDpEvent {
+        self.rewriter.table(table, rewriting_rule)
+    }
+
+    fn map(&self, map: &'a qrlew::relation::Map, rewriting_rule: &'a RewritingRule, rewritten_input: RelationWithDpEvent) -> RelationWithDpEvent {
+        self.rewriter.map(map, rewriting_rule, rewritten_input)
+    }
+
+    fn reduce(&self, reduce: &'a qrlew::relation::Reduce, rewriting_rule: &'a RewritingRule, rewritten_input: RelationWithDpEvent) -> RelationWithDpEvent {
+        // The events of the input come first in the composed event of the rewritten reduce
+        let input_events = if rewritten_input.dp_event().is_no_op() { 0 } else { dp_events(rewritten_input.dp_event()).len() };
+        let rewritten = self.rewriter.reduce(reduce, rewriting_rule, rewritten_input);
+        if let (Property::DifferentiallyPrivate, Parameters::DifferentialPrivacy(dp_parameters)) = (rewriting_rule.output(), rewriting_rule.parameters()) {
+            let events = dp_events(rewritten.dp_event());
+            let (epsilon, delta) = reduce_consumed_budget(events.get(input_events..).unwrap_or_default(), dp_parameters);
+            let (consumed_epsilon, consumed_delta) = self.consumed_budget.get();
+            self.consumed_budget.set((consumed_epsilon + epsilon, consumed_delta + delta));
+        }
+        rewritten
+    }
+
+    fn join(&self, join: &'a qrlew::relation::Join, rewriting_rule: &'a RewritingRule, rewritten_left: RelationWithDpEvent, rewritten_right: RelationWithDpEvent) -> RelationWithDpEvent {
+        self.rewriter.join(join, rewriting_rule, rewritten_left, rewritten_right)
+    }
+
+    fn set(&self, set: &'a qrlew::relation::Set, rewriting_rule: &'a RewritingRule, rewritten_left: RelationWithDpEvent, rewritten_right: RelationWithDpEvent) -> RelationWithDpEvent {
+        self.rewriter.set(set, rewriting_rule, rewritten_left, rewritten_right)
+    }
+
+    fn values(&self, values: &'a qrlew::relation::Values, rewriting_rule: &'a RewritingRule) -> RelationWithDpEvent {
+        self.rewriter.values(values, rewriting_rule)
+    }
+}
+
+/// Rewrite a relation with differential privacy as `Relation::rewrite_with_differential_privacy` does,
+/// along with the (epsilon, delta) the rewriting spends under basic composition, reduce by reduce (see `reduce_consumed_budget`)
+fn rewrite_with_differential_privacy(
+    relation: &Relation,
+    relations: &Hierarchy<Arc<Relation>>,
+    synthetic_data: Option<SyntheticData>,
+    privacy_unit: PrivacyUnit,
+    dp_parameters: DpParameters,
+) -> result::Result<(RelationWithDpEvent, (f64, f64)), qrlew::rewriting::Error> {
+    let relation_with_rules = relation.set_rewriting_rules(RewritingRulesSetter::new(relations, synthetic_data, privacy_unit, dp_parameters, Strategy::Hard));
+    let relation_with_rules = relation_with_rules.map_rewriting_rules(RewritingRulesEliminator);
+    relation_with_rules
+        .select_rewriting_rules(RewritingRulesSelector)
+        .into_iter()
+        .filter(|rwrr| matches!(rwrr.attributes().output(), Property::Public | Property::Published | Property::DifferentiallyPrivate | Property::SyntheticData))
+        .map(|rwrr| {
+            let consumed_budget = Cell::new((0., 0.));
+            let relation_with_dp_event = rwrr.rewrite(BudgetRewriter { rewriter: Rewriter::new(relations), consumed_budget: &consumed_budget });
+            ((relation_with_dp_event, consumed_budget.get()), rwrr.accept(Score))
+        })
+        .max_by(|(_, x), (_, y)| x.total_cmp(y))
+        .map(|(rewriting, _)| rewriting)
+        .ok_or_else(|| qrlew::rewriting::Error::unreachable_property("differential_privacy"))
+}
+
+/// Charge the budget consumed by a DP response to the tables it protects (see `Dataset::budget_keys`), when budget accounting is enabled
+fn spend_budget(accountant: Option<&BudgetAccountant>, budget_keys: &[String], response: &Response) -> Result<()> {
+    match (accountant, response.consumed_budget()) {
+        (Some(accountant), Some(consumed)) => check_budget_available(accountant, budget_keys, consumed),
+        _ => Ok(()),
+    }
+}
+
+/// Spend a budget for protected tables, rejecting the request with a 429 when the total budget of any of them would be exceeded
+fn check_budget_available(accountant: &BudgetAccountant, budget_keys: &[String], consumed: (f64, f64)) -> Result<()> {
+    if accountant.try_spend(budget_keys, consumed.0, consumed.1) {
+        return Ok(());
+    }
+    Err(budget_exceeded(accountant, budget_keys, consumed))
+}
+
+/// The (epsilon, delta) left to spend for all the protected tables
+fn remaining_budget(accountant: &BudgetAccountant, budget_keys: &[String]) -> (f64, f64) {
+    budget_keys.iter()
+        .map(|key| accountant.remaining(key))
+        .fold((f64::INFINITY, f64::INFINITY), |(epsilon, delta), (remaining_epsilon, remaining_delta)| (epsilon.min(remaining_epsilon), delta.min(remaining_delta)))
+}
+
+fn budget_exceeded(accountant: &BudgetAccountant, budget_keys: &[String], (epsilon, delta): (f64, f64)) -> Error {
+    let (remaining_epsilon, remaining_delta) = remaining_budget(accountant, budget_keys);
+    Error::too_many_requests(format!("the query would spend epsilon={epsilon} and delta={delta}, but only epsilon={remaining_epsilon} and delta={remaining_delta} are left in the privacy budget of the tables `{}`", budget_keys.join("`, `")))
+}
+
+/// The scale of the noise of the columns of a relation holding a noisy value as is
+fn noise_scales(relation: &Relation) -> BTreeMap<String, f64> {
+    match relation {
+        Relation::Map(map) => {
+            let input_scales = noise_scales(map.input());
+            let input_scale = |column: &Identifier| column.last().ok().and_then(|column| input_scales.get(column)).copied();
+            map.named_exprs().into_iter().filter_map(|(name, expr)| {
+                let scale = match expr {
+                    Expr::Column(column) => input_scale(column),
+                    // Casting a noisy value keeps its noise
+                    Expr::Function(function) if matches!(function.function(), Function::CastAsFloat | Function::CastAsInteger) => match function.arguments().as_slice() {
+                        [Expr::Column(column)] => input_scale(column),
+                        _ => None,
+                    },
+                    expr => gaussian_noise_scale(expr),
+                };
+                scale.map(|scale| (name.to_string(), scale))
+            }).collect()
+        }
+        Relation::Join(join) => {
+            let (left_scales, right_scales) = (noise_scales(join.left()), noise_scales(join.right()));
+            join.field_inputs().filter_map(|(name, input)| {
+                let (side, column) = (input.head().ok()?, input.last().ok()?);
+                let scales = if side == qrlew::relation::LEFT_INPUT_NAME { &left_scales } else { &right_scales };
+                scales.get(column).map(|scale| (name, *scale))
+            }).collect()
+        }
+        _ => BTreeMap::new(),
+    }
+}
+
+/// The noise scales of the output columns of a DP relation, under their output aliases
+fn output_noise_scales(relation: &Relation, aliases: &HashMap<String, String>) -> BTreeMap<String, f64> {
+    noise_scales(relation).into_iter().map(|(name, scale)| (aliases.get(&name).cloned().unwrap_or(name), scale)).collect()
+}
+
+/// A best-effort plain language summary of a query and of the noise added by its DP rewriting
+fn explain(relation: &Relation, dp_event: &DpEvent, epsilon: f64, delta: f64) -> String {
+    let mut tables: Vec<&str> = vec![];
+    let mut aggregates: Vec<String> = vec![];
+    let mut group_by: Vec<String> = vec![];
+    let mut filtered = false;
+    for node in relation.iter() {
+        match node {
+            Relation::Table(table) if !tables.contains(&table.name()) => tables.push(table.name()),
+            Relation::Map(map) => filtered |= map.filter().is_some(),
+            Relation::Reduce(reduce) => {
+                // Name the input columns by the expressions computing them
+                let input_expr = |column: &Identifier| -> String {
+                    let name = column.last().unwrap_or_default();
+                    match reduce.input() {
+                        Relation::Map(map) => map.named_exprs().into_iter().find(|(field, _)| *field == name).map(|(_, expr)| expr.to_string()),
+                        _ => None,
+                    }.unwrap_or_else(|| name.to_string())
+                };
+                aggregates.extend(reduce.aggregate().iter()
+                    .filter(|aggregate| !matches!(aggregate.aggregate(), Aggregate::First | Aggregate::Last))
+                    .map(|aggregate| format!("{} of {}", aggregate.aggregate(), input_expr(aggregate.column()))));
+                group_by.extend(reduce.group_by().iter().map(input_expr));
+            }
+            _ => (),
+        }
+    }
+    let mut explanation = format!("Reads {}.", tables.join(", "));
+    if !aggregates.is_empty() {
+        explanation.push_str(&format!(" Computes the {}", aggregates.join(", the ")));
+        if !group_by.is_empty() {
+            explanation.push_str(&format!(" per {}", group_by.join(", ")));
+        }
+        if filtered {
+            explanation.push_str(" over the filtered rows");
+        }
+        explanation.push('.');
+    }
+    let mechanisms = mechanisms(dp_event);
+    if mechanisms.is_empty() {
+        explanation.push_str(" No noise is added.");
+    } else {
+        explanation.push_str(&format!(" Protects the privacy units with {}.", mechanisms.join(", ")));
+    }
+    explanation.push_str(&format!(" The privacy budget is epsilon {epsilon}, delta {delta}."));
+    explanation
+}
+
+/// Collect, per source table, the columns needed to compute the `used` fields of a relation
+fn collect_query_columns(relation: &Relation, used: &BTreeSet<String>, columns: &mut BTreeMap<String, BTreeSet<String>>) {
+    let column_names = |exprs: Vec<&qrlew::expr::Expr>| -> BTreeSet<String> {
+        exprs.into_iter().flat_map(|expr| expr.columns()).filter_map(|column| column.last().ok()).map(str::to_string).collect()
+    };
+    match relation {
+        Relation::Table(table) => columns.entry(table.name().to_string()).or_default().extend(used.iter().cloned()),
+        Relation::Map(map) => {
+            let mut exprs: Vec<&qrlew::expr::Expr> = map.named_exprs().into_iter().filter(|(name, _)| used.contains(*name)).map(|(_, expr)| expr).collect();
+            exprs.extend(map.filter());
+            exprs.extend(map.order_by().iter().map(|order_by| &order_by.expr));
+            collect_query_columns(map.input(), &column_names(exprs), columns);
+        }
+        Relation::Reduce(reduce) => {
+            let input_used = reduce.named_aggregates().into_iter()
+                .filter(|(name, _)| used.contains(*name))
+                .map(|(_, aggregate)| aggregate.column())
+                .chain(reduce.group_by())
+                .filter_map(|column| column.last().ok())
+                .map(str::to_string)
+                .collect();
+            collect_query_columns(reduce.input(), &input_used, columns);
+        }
+        Relation::Join(join) => {
+            let mut input_columns: Vec<Identifier> = join.field_inputs().filter(|(name, _)| used.contains(name)).map(|(_, input)| input).collect();
+            match join.operator() {
+                qrlew::relation::JoinOperator::Inner(expr)
+                | qrlew::relation::JoinOperator::LeftOuter(expr)
+                | qrlew::relation::JoinOperator::RightOuter(expr)
+                | qrlew::relation::JoinOperator::FullOuter(expr) => input_columns.extend(expr.columns().into_iter().cloned()),
+                qrlew::relation::JoinOperator::Cross => (),
+            }
+            let side_used = |side: &str| -> BTreeSet<String> {
+                input_columns.iter().filter_map(|column| match column.as_slice() {
+                    [input, name] if input == side => Some(name.clone()),
+                    _ => None,
+                }).collect()
+            };
+            collect_query_columns(join.left(), &side_used(qrlew::relation::Join::left_name()), columns);
+            collect_query_columns(join.right(), &side_used(qrlew::relation::Join::right_name()), columns);
+        }
+        Relation::Set(set) => {
+            let positions: Vec<usize> = set.schema().iter().enumerate().filter(|(_, field)| used.contains(field.name())).map(|(i, _)| i).collect();
+            for input in [set.left(), set.right()] {
+                let input_used = positions.iter().map(|&i| input.schema()[i].name().to_string()).collect();
+                collect_query_columns(input, &input_used, columns);
+            }
+        }
+        Relation::Values(_) => (),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
+pub struct Dot {
+    dataset: Dataset,
+    query: String,
+    dark_mode: bool,
+    #[serde(default)]
+    cluster_by_table: bool,
+    #[serde(default)]
+    with_unique_columns: bool,
+    /// Replace table and column names with opaque tokens (`t1`, `c1`...) numbered in dataset order
+    #[serde(default)]
+    anonymize: bool,
+}
+
+impl Dot {
+    pub fn response(self) -> Result<Response> {
+        let query = parse_query(&self.query)?;
+        check_declared_columns(&query, &self.dataset)?;
+        let tokens = self.anonymize.then(|| anonymization_tokens(&self.dataset));
+        let (relations, warnings) = self.dataset.into_relations()?;
+        let relation = Relation::try_from(query.with(&relations))?;
+        let fingerprint = relation_fingerprint(&relation);
+        let mut dot = rendered_dot(&relation, self.dark_mode)?;
+        if self.cluster_by_table {
+            dot = cluster_by_table(&relation, &dot);
+        }
+        if let Some((tables, columns)) = tokens {
+            dot = anonymize_dot(&dot, &tables, &columns);
+        }
+        Ok(Response::new(dot)
+            .with_unique_columns(self.with_unique_columns.then(|| unique_columns(&relation)))
+            .with_relation_fingerprint(Some(fingerprint))
+            .with_warnings(warnings))
+    }
+}
+
+impl Dot {
+    /// The graph rendered as SVG
+    pub fn response_svg(self) -> Result<String> {
+        dot_to_svg(self.response()?.value())
+    }
+}
+
+/// List the columns of each table read by a query
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
+pub struct QueryColumns {
+    dataset: Dataset,
+    query: String,
+}
+
+impl QueryColumns {
+    pub fn response(self) -> Result<Response> {
+        let query = parse_query(&self.query)?;
+        check_declared_columns(&query, &self.dataset)?;
+        let (relations, warnings) = self.dataset.into_relations()?;
+        let relation = Relation::try_from(query.with(&relations))?;
+        let used = relation.schema().iter().map(|field| field.name().to_string()).collect();
+        let mut columns = BTreeMap::new();
+        collect_query_columns(&relation, &used, &mut columns);
+        Ok(Response::new(serde_json::to_string(&columns)?).with_warnings(warnings))
+    }
+}
+
+/// A column of the relation of a query, with the data type inferred by qrlew
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+struct RelationColumn {
+    name: String,
+    /// The qrlew data type, with its bounds or possible values, e.g. `int[0 100]`
+    data_type: String,
+}
+
+/// The schema of the relation of a query, without rewriting it
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
+pub struct Parse {
+    dataset: Dataset,
+    query: String,
+}
+
+impl Parse {
+    pub fn response(self) -> Result<Response> {
+        let query = parse_query(&self.query)?;
+        check_declared_columns(&query, &self.dataset)?;
+        let (relations, warnings) = self.dataset.into_relations()?;
+        let relation = Relation::try_from(query.with(&relations))?;
+        let columns: Vec<RelationColumn> = relation.schema().iter()
+            .map(|field| RelationColumn { name: field.name().to_string(), data_type: field.data_type().to_string() })
+            .collect();
+        Ok(Response::new(serde_json::to_string(&columns)?).with_warnings(warnings))
+    }
+}
+
+/// An output column of the union schema
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+struct OutputColumn {
+    name: String,
+    data_type: DataType,
+    /// Whether the column may be null, or is missing from the output of some query
+    nullable: bool,
+}
+
+/// Compute the schema holding the outputs of all queries: columns are matched by name, in order of first appearance
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
+pub struct UnionSchema {
+    dataset: Dataset,
+    queries: Vec<String>,
+}
+
+impl UnionSchema {
+    pub fn response(self) -> Result<Response> {
+        if self.queries.is_empty() {
+            return Err(Error::invalid_request("no queries to unite"));
+        }
+        let queries = self.queries.iter().map(|query| {
+            let query = parse_query(query)?;
+            check_declared_columns(&query, &self.dataset)?;
+            Ok(query)
+        }).collect::<Result<Vec<_>>>()?;
+        let (relations, warnings) = self.dataset.into_relations()?;
+        let mut columns: Vec<OutputColumn> = vec![];
+        for (index, query) in queries.iter().enumerate() {
+            let relation = Relation::try_from(query.with(&relations))?;
+            let mut seen = BTreeSet::new();
+            for field in relation.schema().iter() {
+                let (data_type, nullable) = DataType::simplify(&field.data_type())
+                    .ok_or_else(|| Error::invalid_request(format!("unsupported type {} for column {} of query {index}", field.data_type(), field.name())))?;
+                seen.insert(field.name().to_string());
+                match columns.iter_mut().find(|column| column.name == field.name()) {
+                    Some(column) => {
+                        column.data_type = column.data_type.clone().widen(data_type.clone())
+                            .ok_or_else(|| Error::invalid_request(format!("incompatible types for column {}: {:?} and {data_type:?} in query {index}", field.name(), column.data_type)))?;
+                        column.nullable |= nullable;
+                    }
+                    // Columns absent from the previous queries are null in their rows
+                    None => columns.push(OutputColumn { name: field.name().to_string(), data_type, nullable: nullable || index > 0 }),
+                }
+            }
+            for column in columns.iter_mut().filter(|column| !seen.contains(&column.name)) {
+                column.nullable = true;
+            }
+        }
+        Ok(Response::new(serde_json::to_string(&columns)?).with_warnings(warnings))
+    }
+}
+
+/// The most distinct text values of a column kept as its inferred possible values
+const MAX_INFERRED_POSSIBLE_VALUES: usize = 16;
+
+/// The simplified type of a sample value, strings in the default formats are dates, date times or times, NULL has no type
+fn infer_data_type(value: &Value) -> Option<DataType> {
+    Some(match value {
+        Value::Null => return None,
+        Value::Bool(_) => DataType::Boolean,
+        Value::Number(number) if number.is_i64() => DataType::Integer,
+        Value::Number(_) => DataType::Float,
+        Value::String(_) if date_from_value(value, None).is_some() => DataType::Date,
+        Value::String(_) if date_time_from_value(value, None).is_some() => DataType::DateTime,
+        Value::String(_) if time_from_value(value, None).is_some() => DataType::Time,
+        _ => DataType::Text,
+    })
+}
+
+/// The smallest and the largest values, as given
+fn sample_range<T: PartialOrd>(values: &[&Value], parse: impl Fn(&Value) -> Option<T>) -> Option<(Value, Value)> {
+    let parsed: Vec<(T, &Value)> = values.iter().filter_map(|value| Some((parse(value)?, *value))).collect();
+    let order = |left: &&(T, &Value), right: &&(T, &Value)| left.0.partial_cmp(&right.0).unwrap_or(std::cmp::Ordering::Equal);
+    Some((parsed.iter().min_by(order)?.1.clone(), parsed.iter().max_by(order)?.1.clone()))
+}
+
+/// The distinct values, in order of first appearance
+fn sample_values(values: &[&Value]) -> Vec<Value> {
+    let mut distinct: Vec<Value> = vec![];
+    for value in values {
+        if !distinct.contains(*value) {
+            distinct.push((*value).clone());
+        }
+    }
+    distinct
+}
+
+/// Infer a field from the values of a column: mixed types widen to Text, as do columns without values, NULLs make the field optional
+fn infer_field(name: String, values: &[&Value]) -> Field {
+    let (nulls, values): (Vec<&Value>, Vec<&Value>) = values.iter().copied().partition(|value| value.is_null());
+    let data_type = values.iter()
+        .filter_map(|value| infer_data_type(value))
+        .reduce(|left, right| left.widen(right).unwrap_or(DataType::Text))
+        .unwrap_or(DataType::Text);
+    let range = match data_type {
+        DataType::Integer => sample_range(&values, integer_from_value),
+        DataType::Float => sample_range(&values, Value::as_f64),
+        DataType::Date => sample_range(&values, |value| date_from_value(value, None)),
+        DataType::Time => sample_range(&values, |value| time_from_value(value, None)),
+        DataType::DateTime => sample_range(&values, |value| date_time_from_value(value, None)),
+        _ => None,
+    };
+    let possible_values = match data_type {
+        DataType::Boolean => Some(sample_values(&values)),
+        // Only a few distinct strings are taken as an enumeration
+        DataType::Text if !values.is_empty() && values.iter().all(|value| value.is_string()) => Some(sample_values(&values))
+            .filter(|distinct| distinct.len() <= MAX_INFERRED_POSSIBLE_VALUES),
+        _ => None,
+    };
+    Field { name, data_type, range, possible_values, constraint: None, description: None, optional: (!nulls.is_empty()).then_some(true), format: None }
+}
+
+/// Infer the schema of sample rows, the values of each row being in the order of `column_names`
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
+pub struct InferSchema {
+    rows: Vec<Vec<Value>>,
+    column_names: Vec<String>,
+}
+
+impl InferSchema {
+    pub fn response(self) -> Result<Response> {
+        if self.column_names.is_empty() {
+            return Err(Error::invalid_request("no column_names"));
+        }
+        for (index, name) in self.column_names.iter().enumerate() {
+            if self.column_names[..index].contains(name) {
+                return Err(Error::invalid_request(format!("the column name `{name}` is given more than once")));
+            }
+        }
+        if let Some((index, row)) = self.rows.iter().enumerate().find(|(_, row)| row.len() != self.column_names.len()) {
+            return Err(Error::invalid_request(format!("row {index} has {} values, expected one per column name ({})", row.len(), self.column_names.len())));
+        }
+        let fields = self.column_names.into_iter().enumerate()
+            .map(|(index, name)| infer_field(name, &self.rows.iter().map(|row| &row[index]).collect::<Vec<_>>()))
+            .collect();
+        Ok(Response::new(serde_json::to_string(&Schema { fields })?))
+    }
+}
+
+/// The options shared by rewriting requests: the synthetic data, the privacy unit and the budget
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
+struct RewriteOptions {
+    synthetic_data: Vec<(String, String)>,
+    #[serde(default)]
+    #[schema(value_type = Vec<(String, Vec<(String, String, String)>, String)>)]
+    privacy_unit: PrivacyUnitSpec,
+    privacy_unit_token: Option<String>,
+    /// The privacy unit with tables designated by their paths, exclusive of `privacy_unit`
+    #[serde(default)]
+    #[schema(value_type = Vec<(Vec<String>, Vec<(String, Vec<String>, String)>, String)>)]
+    privacy_unit_paths: QualifiedPrivacyUnitSpec,
+    /// Derive the links of tables missing from the privacy unit from their foreign keys
+    #[serde(default)]
+    infer_privacy_unit_links: bool,
+    epsilon: f64,
+    /// Defaults to 0, pure epsilon-DP, only accepted for rewritings adding no noise
+    #[serde(default)]
+    delta: f64,
+}
+
+impl RewriteOptions {
+    /// The privacy unit checked against the dataset, an empty one is rejected unless `allow_empty`
+    fn privacy_unit(&self, dataset: &Dataset, allow_empty: bool) -> Result<PrivacyUnit> {
+        let privacy_unit = privacy_unit_from_paths(&self.privacy_unit, &self.privacy_unit_paths, dataset)?;
+        check_privacy_unit_not_empty(&privacy_unit, self.privacy_unit_token.as_deref(), allow_empty)?;
+        resolve_privacy_unit(&privacy_unit, self.privacy_unit_token.as_deref(), dataset, self.infer_privacy_unit_links)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
+pub struct RewriteAsPrivacyUnitPreserving {
+    dataset: Dataset,
+    query: String,
+    #[serde(flatten)]
+    options: RewriteOptions,
+    #[serde(default)]
+    with_unique_columns: bool,
+    /// Names given to output columns in the rendered query
+    #[serde(default)]
+    output_aliases: HashMap<String, String>,
+    /// The SQL dialect of the rendered query, qrlew's default one when not set
+    dialect: Option<Dialect>,
+    /// Return a map of these representations instead of the SQL query alone
+    #[serde(default)]
+    formats: Vec<OutputFormat>,
+    /// A statement embedding the rewritten query in place of its `{query}` placeholder, e.g. `INSERT INTO results {query}`
+    wrapper_template: Option<String>,
+    /// The user on whose behalf the rewriting is done, only logged in audit events
+    principal: Option<String>,
+}
+
+impl RewriteAsPrivacyUnitPreserving {
+    pub fn response(self) -> Result<Response> {
+        self.response_with("rewrite_as_privacy_unit_preserving", |response, _| Ok(response))
+    }
+
+    /// The response of a route, its value built by `value` from the response of the rewriting and the privacy unit preserving relation
+    fn response_with(self, route: &str, value: impl FnOnce(Response, &Relation) -> Result<Response>) -> Result<Response> {
+        validate_principal(self.principal.as_deref())?;
+        let query = parse_query(&self.query)?;
+        check_declared_columns(&query, &self.dataset)?;
+        check_synthetic_data(&self.options.synthetic_data, &self.dataset)?;
+        let privacy_unit = self.options.privacy_unit(&self.dataset, true)?;
+        let (relations, warnings) = self.dataset.clone().into_relations()?;
+        let (response, pup_relation) = self.rewrite(&query, &relations, privacy_unit)?;
+        let response = value(response, &pup_relation)?;
+        audit(route, self.principal.as_deref(), &self.query, None);
+        Ok(response.with_warnings(warnings))
+    }
+
+    /// Rewrite a parsed query against the relations of the dataset, along with the privacy unit preserving relation
+    fn rewrite(&self, query: &Query, relations: &Hierarchy<Arc<Relation>>, privacy_unit: PrivacyUnit) -> Result<(Response, Relation)> {
+        let relation = Relation::try_from(query.with(relations))?;
+        let fingerprint = relation_fingerprint(&relation);
+        check_cross_joins(&relation)?;
+        let synthetic_data = synthetic_data(self.options.synthetic_data.clone(), relations, synthetic_table_resolver());
+        let dp_parameters = dp_parameters(self.options.epsilon, self.options.delta, None)?;
+        let pup_relation = match synthetic_data_only_relation(query, relations, synthetic_data.as_ref())? {
+            Some(sd_relation) => sd_relation,
+            None => relation.rewrite_as_privacy_unit_preserving(&with_table_name_aliases(relations), synthetic_data, privacy_unit, dp_parameters, None)?.relation().clone(),
+        };
+        let response = Response::new(render(&pup_relation, query_with_output_aliases(&pup_relation, &self.output_aliases, self.dialect)?, &self.formats, self.wrapper_template.as_deref())?)
+            .with_unique_columns(self.with_unique_columns.then(|| unique_columns(&pup_relation)))
+            .with_relation_fingerprint(Some(fingerprint));
+        Ok((response, pup_relation))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
+pub struct RewriteWithDifferentialPrivacy {
+    dataset: Dataset,
+    query: String,
+    #[serde(flatten)]
+    options: RewriteOptions,
+    /// Accept an empty privacy unit, rewriting all tables as public
+    #[serde(default)]
+    allow_no_privacy_unit: bool,
+    /// The maximum number of rows a privacy unit contributes to the query, bounding the sensitivity of aggregates
+    max_privacy_unit_contribution: Option<u64>,
+    #[serde(default)]
+    with_unique_columns: bool,
+    /// Names given to output columns in the rendered query
+    #[serde(default)]
+    output_aliases: HashMap<String, String>,
+    /// The SQL dialect of the rendered query, qrlew's default one when not set
+    dialect: Option<Dialect>,
+    /// Return a map of these representations instead of the SQL query alone
+    #[serde(default)]
+    formats: Vec<OutputFormat>,
+    /// A statement embedding the rewritten query in place of its `{query}` placeholder, e.g. `INSERT INTO results {query}`
+    wrapper_template: Option<String>,
+    #[serde(default)]
+    with_dataset_hash: bool,
+    /// Sign a SHA-256 digest of the value instead of the value itself
+    #[serde(default)]
+    sign_digest: bool,
+    #[serde(default)]
+    with_explanation: bool,
+    /// The user on whose behalf the rewriting is done, only logged in audit events
+    principal: Option<String>,
+}
+
+impl RewriteWithDifferentialPrivacy {
+    pub fn response(self, auth: &Authenticator) -> Result<Response> {
+        self.response_with("rewrite_with_differential_privacy", auth, |response, _| Ok(response))
+    }
+
+    /// The signed response of a route, its value built by `value` from the response of the rewriting and the DP relation,
+    /// the budget is only spent once the response is built
+    fn response_with(self, route: &str, auth: &Authenticator, value: impl FnOnce(Response, &Relation) -> Result<Response>) -> Result<Response> {
+        validate_principal(self.principal.as_deref())?;
+        let query = parse_query(&self.query)?;
+        check_declared_columns(&query, &self.dataset)?;
+        check_synthetic_data(&self.options.synthetic_data, &self.dataset)?;
+        let privacy_unit = self.privacy_unit()?;
+        let dataset_hash = self.with_dataset_hash.then(|| self.dataset.hash()).transpose()?;
+        let budget_keys = self.dataset.budget_keys(&privacy_unit);
+        let (relations, warnings) = self.dataset.clone().into_relations()?;
+        let (response, dp_relation) = self.rewrite(&query, &relations, privacy_unit)?;
+        let response = value(response, &dp_relation)?;
+        spend_budget(budget_accountant(), &budget_keys, &response)?;
+        audit(route, self.principal.as_deref(), &self.query, Some((self.options.epsilon, self.options.delta)));
+        Ok(response
+            .with_dataset_hash(dataset_hash)
+            .with_digest(self.sign_digest)
+            .sign(auth)
+            .with_warnings(warnings))
+    }
+
+    /// The privacy unit of the request, checked against the dataset
+    fn privacy_unit(&self) -> Result<PrivacyUnit> {
+        self.options.privacy_unit(&self.dataset, self.allow_no_privacy_unit)
+    }
+
+    /// Rewrite a parsed query against the relations of the dataset, along with the DP relation, the response is yet to be signed
+    fn rewrite(&self, query: &Query, relations: &Hierarchy<Arc<Relation>>, privacy_unit: PrivacyUnit) -> Result<(Response, Relation)> {
+        let relation = Relation::try_from(query.with(relations))?;
+        let fingerprint = relation_fingerprint(&relation);
+        check_cross_joins(&relation)?;
+        let synthetic_data = synthetic_data(self.options.synthetic_data.clone(), relations, synthetic_table_resolver());
+        let dp_parameters = dp_parameters(self.options.epsilon, rewriting_delta(self.options.delta), self.max_privacy_unit_contribution)?;
+        let (dp_relation, dp_event, consumed_budget) = match synthetic_data_only_relation(query, relations, synthetic_data.as_ref())? {
+            Some(sd_relation) => (sd_relation, DpEvent::no_op(), (0., 0.)),
+            None => {
+                check_strict_dp(env_flag("QRLEW_STRICT_DP"), &relation, &privacy_unit)?;
+                let (relation_with_dp_event, consumed_budget) = rewrite_with_differential_privacy(&relation, &with_table_name_aliases(relations), synthetic_data, privacy_unit, dp_parameters)
+                    .map_err(|err| Error::unsupported_aggregations(err, unsupported_aggregations(&relation)))?;
+                (relation_with_dp_event.relation().clone(), relation_with_dp_event.dp_event().clone(), consumed_budget)
+            }
+        };
+        check_pure_dp(self.options.delta, &dp_event)?;
+        let response = Response::new(render(&dp_relation, query_with_output_aliases(&dp_relation, &self.output_aliases, self.dialect)?, &self.formats, self.wrapper_template.as_deref())?)
+            .with_unique_columns(self.with_unique_columns.then(|| unique_columns(&dp_relation)))
+            .with_explanation(self.with_explanation.then(|| explain(&relation, &dp_event, self.options.epsilon, self.options.delta)))
+            .with_suppression_threshold(suppression_threshold(&dp_event))
+            .with_consumed_budget(Some(consumed_budget))
+            .with_noise_scales(Some(output_noise_scales(&dp_relation, &self.output_aliases)))
+            .with_relation_fingerprint(Some(fingerprint));
+        Ok((response, dp_relation))
+    }
+}
+
+/// Rewrite several queries against the same dataset with differential privacy, each query spending the whole budget
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
+pub struct RewriteWithDifferentialPrivacyBatch {
+    dataset: Dataset,
+    queries: Vec<String>,
+    #[serde(flatten)]
+    options: RewriteOptions,
+    /// Accept an empty privacy unit, rewriting all tables as public
+    #[serde(default)]
+    allow_no_privacy_unit: bool,
+    /// The maximum number of rows a privacy unit contributes to each query, bounding the sensitivity of aggregates
+    max_privacy_unit_contribution: Option<u64>,
+    /// Names given to output columns in the rendered queries
+    #[serde(default)]
+    output_aliases: HashMap<String, String>,
+    /// The SQL dialect of the rendered queries, qrlew's default one when not set
+    dialect: Option<Dialect>,
+    /// The user on whose behalf the rewriting is done, only logged in audit events
+    principal: Option<String>,
+}
+
+/// The outcome of a query of a batch: its signed response or its error
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
+#[serde(untagged)]
+pub enum BatchItem {
+    Response(Box<Response>),
+    Error { error: String, message: String },
+}
+
+impl From<Result<Response>> for BatchItem {
+    fn from(result: Result<Response>) -> Self {
+        match result {
+            Ok(response) => BatchItem::Response(Box::new(response)),
+            Err(err) => BatchItem::Error { error: err.tag().to_string(), message: err.message() },
+        }
+    }
+}
+
+impl RewriteWithDifferentialPrivacyBatch {
+    /// The responses to the queries in order, the dataset is converted once and a failing query does not fail the batch
+    pub fn response(self, auth: &Authenticator) -> Result<Vec<BatchItem>> {
+        let rewrite = RewriteWithDifferentialPrivacy {
+            dataset: self.dataset,
+            query: String::new(),
+            options: self.options,
+            allow_no_privacy_unit: self.allow_no_privacy_unit,
+            max_privacy_unit_contribution: self.max_privacy_unit_contribution,
+            with_unique_columns: false,
+            output_aliases: self.output_aliases,
+            dialect: self.dialect,
+            formats: vec![],
+            wrapper_template: None,
+            with_dataset_hash: false,
+            sign_digest: false,
+            with_explanation: false,
+            principal: self.principal,
+        };
+        validate_principal(rewrite.principal.as_deref())?;
+        check_synthetic_data(&rewrite.options.synthetic_data, &rewrite.dataset)?;
+        let privacy_unit = rewrite.privacy_unit()?;
+        let budget_keys = rewrite.dataset.budget_keys(&privacy_unit);
+        let (relations, warnings) = rewrite.dataset.clone().into_relations()?;
+        Ok(self.queries.iter()
+            .map(|query_str| {
+                let response = parse_query(query_str)
+                    .and_then(|query| check_declared_columns(&query, &rewrite.dataset).map(|_| query))
+                    .and_then(|query| rewrite.rewrite(&query, &relations, privacy_unit.clone()))
+                    .and_then(|(response, _)| spend_budget(budget_accountant(), &budget_keys, &response).map(|_| response));
+                if response.is_ok() {
+                    audit("rewrite_with_differential_privacy", rewrite.principal.as_deref(), query_str, Some((rewrite.options.epsilon, rewrite.options.delta)));
+                }
+                response.map(|response| response.sign(auth).with_warnings(warnings.clone())).into()
+            })
+            .collect())
+    }
+}
+
+/// Rewrite a query both as privacy unit preserving and with differential privacy
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
+pub struct Rewrite {
+    dataset: Dataset,
+    query: String,
+    #[serde(flatten)]
+    options: RewriteOptions,
+    /// Accept an empty privacy unit, rewriting all tables as public
+    #[serde(default)]
+    allow_no_privacy_unit: bool,
+    /// The maximum number of rows a privacy unit contributes to the query, bounding the sensitivity of aggregates
+    max_privacy_unit_contribution: Option<u64>,
+    /// Names given to output columns in the rendered queries
+    #[serde(default)]
+    output_aliases: HashMap<String, String>,
+    /// The SQL dialect of the rendered queries, qrlew's default one when not set
+    dialect: Option<Dialect>,
+    /// The user on whose behalf the rewriting is done, only logged in audit events
+    principal: Option<String>,
+}
+
+
+/// The privacy unit preserving and the differentially private rewritings of a query, only the latter is signed
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
+pub struct Rewrites {
+    pup_query: Response,
+    dp_query: Response,
+}
+
+impl Rewrite {
+    /// Both rewritings, from a single parsing of the query and conversion of the dataset
+    pub fn response(self, auth: &Authenticator) -> Result<Rewrites> {
+        let dp = RewriteWithDifferentialPrivacy {
+            dataset: self.dataset,
+            query: self.query,
+            options: self.options,
+            allow_no_privacy_unit: self.allow_no_privacy_unit,
+            max_privacy_unit_contribution: self.max_privacy_unit_contribution,
+            with_unique_columns: false,
+            output_aliases: self.output_aliases,
+            dialect: self.dialect,
+            formats: vec![],
+            wrapper_template: None,
+            with_dataset_hash: false,
+            sign_digest: false,
+            with_explanation: false,
+            principal: self.principal,
+        };
+        validate_principal(dp.principal.as_deref())?;
+        let query = parse_query(&dp.query)?;
+        check_declared_columns(&query, &dp.dataset)?;
+        check_synthetic_data(&dp.options.synthetic_data, &dp.dataset)?;
+        let privacy_unit = dp.privacy_unit()?;
+        let budget_keys = dp.dataset.budget_keys(&privacy_unit);
+        let (relations, warnings) = dp.dataset.clone().into_relations()?;
+        let pup = RewriteAsPrivacyUnitPreserving {
+            dataset: dp.dataset.clone(),
+            query: dp.query.clone(),
+            options: dp.options.clone(),
+            with_unique_columns: false,
+            output_aliases: dp.output_aliases.clone(),
+            dialect: dp.dialect,
+            formats: vec![],
+            wrapper_template: None,
+            principal: dp.principal.clone(),
+        };
+        let (pup_query, _) = pup.rewrite(&query, &relations, privacy_unit.clone())?;
+        let pup_query = pup_query.with_warnings(warnings.clone());
+        let (dp_query, _) = dp.rewrite(&query, &relations, privacy_unit)?;
+        spend_budget(budget_accountant(), &budget_keys, &dp_query)?;
+        let dp_query = dp_query.sign(auth).with_warnings(warnings);
+        audit("rewrite", dp.principal.as_deref(), &dp.query, Some((dp.options.epsilon, dp.options.delta)));
+        Ok(Rewrites { pup_query, dp_query })
+    }
+}
+
+/// Check a query can be rewritten with differential privacy within a budget, without returning the query
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
+pub struct CheckBudget {
+    dataset: Dataset,
+    query: String,
+    #[serde(flatten)]
+    options: RewriteOptions,
+    /// Accept an empty privacy unit, rewriting all tables as public
+    #[serde(default)]
+    allow_no_privacy_unit: bool,
+    /// The maximum number of rows a privacy unit contributes to the query, bounding the sensitivity of aggregates
+    max_privacy_unit_contribution: Option<u64>,
+}
+
+/// Whether a query can be rewritten within a budget, qrlew does not estimate the minimum budget of a query
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
+pub struct BudgetCheck {
+    feasible: bool,
+    /// Why the rewriting is not feasible
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    /// The mechanisms the rewriting would use
+    mechanisms: Vec<String>,
+    /// The epsilon left in the privacy budget of the protected tables, when budget accounting is enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remaining_epsilon: Option<f64>,
+    /// The delta left in the privacy budget of the protected tables, when budget accounting is enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remaining_delta: Option<f64>,
+}
+
+impl CheckBudget {
+    pub fn response(self) -> Result<Response> {
+        self.response_with(budget_accountant())
+    }
+
+    /// Check the budget, within what is left of the budget of the protected tables when `accountant` is set
+    fn response_with(self, accountant: Option<&BudgetAccountant>) -> Result<Response> {
+        let query = parse_query(&self.query)?;
+        check_declared_columns(&query, &self.dataset)?;
+        check_synthetic_data(&self.options.synthetic_data, &self.dataset)?;
+        let privacy_unit = self.options.privacy_unit(&self.dataset, self.allow_no_privacy_unit)?;
+        let budget_keys = self.dataset.budget_keys(&privacy_unit);
+        let (relations, warnings) = self.dataset.into_relations()?;
+        let relation = Relation::try_from(query.with(&relations))?;
+        let (remaining_epsilon, remaining_delta) = accountant.map(|accountant| remaining_budget(accountant, &budget_keys)).unzip();
+        let infeasible = |err: Error| BudgetCheck { feasible: false, reason: Some(err.to_string().trim_end().to_string()), mechanisms: vec![], remaining_epsilon, remaining_delta };
+        let check = if let Err(err) = validate_budget(self.options.epsilon, self.options.delta) {
+            infeasible(err)
+        } else {
+            let synthetic_data = synthetic_data(self.options.synthetic_data, &relations, synthetic_table_resolver());
+            let dp_parameters = dp_parameters(self.options.epsilon, rewriting_delta(self.options.delta), self.max_privacy_unit_contribution)?;
+            let rewriting = check_cross_joins(&relation)
+                .and_then(|_| check_strict_dp(env_flag("QRLEW_STRICT_DP"), &relation, &privacy_unit))
+                .and_then(|_| Ok(rewrite_with_differential_privacy(&relation, &with_table_name_aliases(&relations), synthetic_data, privacy_unit, dp_parameters)?))
+                .and_then(|(relation_with_dp_event, consumed)| check_pure_dp(self.options.delta, relation_with_dp_event.dp_event()).map(|_| (relation_with_dp_event, consumed)))
+                .and_then(|(relation_with_dp_event, consumed)| match accountant {
+                    Some(accountant) if !accountant.can_spend(&budget_keys, consumed.0, consumed.1) => Err(budget_exceeded(accountant, &budget_keys, consumed)),
+                    _ => Ok(relation_with_dp_event),
+                });
+            match rewriting {
+                Ok(relation_with_dp_event) => BudgetCheck { feasible: true, reason: None, mechanisms: mechanisms(relation_with_dp_event.dp_event()), remaining_epsilon, remaining_delta },
+                Err(err) => infeasible(err),
+            }
+        };
+        Ok(Response::new(serde_json::to_string(&check)?).with_warnings(warnings))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
+pub struct QueryWithDot {
+    query: String,
+    dot: String,
+}
+
+impl QueryWithDot {
+    pub fn new(query: String, dot: String) -> QueryWithDot {
+        QueryWithDot {
+            query,
+            dot,
+        }
+    }
+
+    /// A multipart/mixed response with the query and the dot as separate parts
+    pub fn multipart(&self) -> axum::response::Response {
+        let boundary = format!("qrlew-{}", general_purpose::URL_SAFE_NO_PAD.encode(rand::random::<[u8; 16]>()));
+        let body = format!(
+            "--{boundary}\r\nContent-Type: application/sql\r\n\r\n{}\r\n--{boundary}\r\nContent-Type: text/vnd.graphviz\r\n\r\n{}\r\n--{boundary}--\r\n",
+            self.query, self.dot,
+        );
+        ([(header::CONTENT_TYPE, format!("multipart/mixed; boundary={boundary}"))], body).into_response()
+    }
+}
+
+/// A response with the rendered query of a response and the dot of its relation as value
+fn with_dot(response: Response, relation: &Relation, dark_mode: bool) -> Result<Response> {
+    let mut dot = Vec::new();
+    relation.dot(&mut dot, if dark_mode {&["dark"]} else {&[]})?;
+    let value = serde_json::to_string(&QueryWithDot::new(response.value().to_string(), String::from_utf8(dot)?))?;
+    Ok(response.with_value(value))
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
+pub struct RewriteAsPrivacyUnitPreservingWithDot {
+    #[serde(flatten)]
+    rewrite: RewriteAsPrivacyUnitPreserving,
+    dark_mode: bool,
+}
+
+impl RewriteAsPrivacyUnitPreservingWithDot {
+    pub fn response(self) -> Result<Response> {
+        let dark_mode = self.dark_mode;
+        self.rewrite.response_with("rewrite_as_privacy_unit_preserving_with_dot", |response, pup_relation| with_dot(response, pup_relation, dark_mode))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema, ToSchema)]
+pub struct RewriteWithDifferentialPrivacyWithDot {
+    #[serde(flatten)]
+    rewrite: RewriteWithDifferentialPrivacy,
+    dark_mode: bool,
+}
+
+impl RewriteWithDifferentialPrivacyWithDot {
+    pub fn response(self, auth: &Authenticator) -> Result<Response> {
+        let dark_mode = self.dark_mode;
+        self.rewrite.response_with("rewrite_with_differential_privacy_with_dot", auth, |response, dp_relation| with_dot(response, dp_relation, dark_mode))
+    }
+}
+
+/// The JSON Schema of the request body of a route
+pub fn json_schema(route: &str) -> Result<Value> {
+    let schema = match route {
+        "verify" => schema_for!(Response),
+        "dot" => schema_for!(Dot),
+        "query_columns" => schema_for!(QueryColumns),
+        "parse" => schema_for!(Parse),
+        "union_schema" => schema_for!(UnionSchema),
+        "infer_schema" => schema_for!(InferSchema),
+        "register_privacy_unit" => schema_for!(RegisterPrivacyUnit),
+        "rewrite_as_privacy_unit_preserving" => schema_for!(RewriteAsPrivacyUnitPreserving),
+        "rewrite_with_differential_privacy" => schema_for!(RewriteWithDifferentialPrivacy),
+        "rewrite_with_differential_privacy_batch" => schema_for!(RewriteWithDifferentialPrivacyBatch),
+        "rewrite" => schema_for!(Rewrite),
+        "rewrite_as_privacy_unit_preserving_with_dot" => schema_for!(RewriteAsPrivacyUnitPreservingWithDot),
+        "rewrite_with_differential_privacy_with_dot" => schema_for!(RewriteWithDifferentialPrivacyWithDot),
+        "check_budget" => schema_for!(CheckBudget),
+        "privacy_unit_diff" => schema_for!(PrivacyUnitDiff),
+        _ => return Err(Error::invalid_request(format!("no JSON Schema for route `{route}`"))),
+    };
+    Ok(serde_json::to_value(schema)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn test_field_names() {
+        assert_eq!(field_names::<Dot>(), ["dataset", "query", "dark_mode", "cluster_by_table", "with_unique_columns", "anonymize"]);
+        // The fields of flattened options are listed along with the others
+        let fields = field_names::<RewriteWithDifferentialPrivacyWithDot>();
+        println!("{fields:?}");
+        for field in ["dataset", "query", "privacy_unit", "epsilon", "delta", "max_privacy_unit_contribution", "dark_mode"] {
+            assert!(fields.iter().any(|name| name == field));
+        }
+    }
+
+    #[test]
+    fn test_duration_from_value() {
+        assert_eq!(duration_from_value(&serde_json::json!(90)), Some(Duration::seconds(90)));
+        assert_eq!(duration_from_value(&serde_json::json!("90s")), Some(Duration::seconds(90)));
+        assert_eq!(duration_from_value(&serde_json::json!("1h30m")), Some(Duration::minutes(90)));
+        assert_eq!(duration_from_value(&serde_json::json!("2d 500ms")), None);
+        assert_eq!(duration_from_value(&serde_json::json!("2d500ms")), Some(Duration::days(2) + Duration::milliseconds(500)));
+        assert_eq!(duration_from_value(&serde_json::json!("")), None);
+        assert_eq!(duration_from_value(&serde_json::json!("1x")), None);
+        assert_eq!(duration_from_value(&serde_json::json!("h")), None);
+        let field: Field = serde_json::from_str(r#"{"name":"d","data_type":"Duration","range":["90s","1h30m"]}"#).unwrap();
+        assert_eq!(qrlew::DataType::try_from(field).unwrap(), qrlew::DataType::duration_interval(Duration::seconds(90), Duration::minutes(90)));
+        let field: Field = serde_json::from_str(r#"{"name":"d","data_type":"Duration","possible_values":[60,"2m"]}"#).unwrap();
+        assert_eq!(qrlew::DataType::try_from(field).unwrap(), qrlew::DataType::duration_values([Duration::seconds(60), Duration::minutes(2)]));
+    }
+
+    #[test]
+    fn test_field_error() {
+        for (field, reason) in [
+            (r#"{"name":"dob","data_type":"Date","range":["2020-13-01","2021-01-01"]}"#, r#"field `dob`: could not parse range min "2020-13-01" as date"#),
+            (r#"{"name":"dob","data_type":"Date","range":["2020-01-01",3]}"#, "field `dob`: could not parse range max 3 as date"),
+            (r#"{"name":"at","data_type":"Time","possible_values":["12:00:00","noon"]}"#, r#"field `at`: could not parse possible value "noon" as time"#),
+            (r#"{"name":"ok","data_type":"Boolean","range":[false,"true"]}"#, r#"field `ok`: could not parse range max "true" as boolean"#),
+            (r#"{"name":"x","data_type":"Text","range":["a","z"],"possible_values":["b"]}"#, "field `x`: range and possible_values cannot both be set for Text fields"),
+            (r#"{"name":"x","data_type":"Float","range":[0,1],"possible_values":[2,3]}"#, "field `x`: no possible value lies within the range"),
+        ] {
+            let field: Field = serde_json::from_str(field).unwrap();
+            let err = qrlew::DataType::try_from(field).unwrap_err();
+            println!("{err}");
+            assert!(matches!(err, Error::InvalidRequest(_)));
+            assert_eq!(err.message(), format!("Invalid request: {reason}"));
+        }
+    }
+
+    #[test]
+    fn test_bytes_field() {
+        for field in [
+            r#"{"name":"key","data_type":"Bytes","range":[0,32]}"#,
+            r#"{"name":"key","data_type":"Bytes","possible_values":["AAEC","AwQF"]}"#,
+        ] {
+            let field: Field = serde_json::from_str(field).unwrap();
+            assert_eq!(qrlew::DataType::try_from(field).unwrap(), qrlew::DataType::bytes());
+        }
+        for field in [
+            r#"{"name":"key","data_type":"Bytes","range":[32,0]}"#,
+            r#"{"name":"key","data_type":"Bytes","range":[-1,32]}"#,
+            r#"{"name":"key","data_type":"Bytes","possible_values":["AAEC",3]}"#,
+        ] {
+            let field: Field = serde_json::from_str(field).unwrap();
+            let err = qrlew::DataType::try_from(field).unwrap_err();
+            println!("{err}");
+            assert!(matches!(err, Error::InvalidRequest(_)));
+            assert!(err.to_string().contains("field `key`: "));
+        }
+    }
+
+    #[test]
+    fn test_field_format() {
+        let field: Field = serde_json::from_str(r#"{"name":"at","data_type":"DateTime","range":["2020-01-01T00:00:00+02:00","2021-01-01T12:30:00Z"],"format":"%Y-%m-%dT%H:%M:%S%#z"}"#).unwrap();
+        let data_type = qrlew::DataType::try_from(field).unwrap();
+        println!("{data_type}");
+        // Offsets are converted to UTC
+        let min = NaiveDate::from_ymd_opt(2019, 12, 31).unwrap().and_hms_opt(22, 0, 0).unwrap();
+        let max = NaiveDate::from_ymd_opt(2021, 1, 1).unwrap().and_hms_opt(12, 30, 0).unwrap();
+        assert_eq!(data_type, qrlew::DataType::date_time_interval(min, max));
+        // Formats without offset
+        let field: Field = serde_json::from_str(r#"{"name":"at","data_type":"DateTime","possible_values":["2020-01-01T08:00:00"],"format":"%Y-%m-%dT%H:%M:%S"}"#).unwrap();
+        assert_eq!(qrlew::DataType::try_from(field).unwrap(), qrlew::DataType::date_time_values([NaiveDate::from_ymd_opt(2020, 1, 1).unwrap().and_hms_opt(8, 0, 0).unwrap()]));
+        let field: Field = serde_json::from_str(r#"{"name":"dob","data_type":"Date","range":["01/02/2000","31/12/2000"],"format":"%d/%m/%Y"}"#).unwrap();
+        assert_eq!(qrlew::DataType::try_from(field).unwrap(), qrlew::DataType::date_interval(NaiveDate::from_ymd_opt(2000, 2, 1).unwrap(), NaiveDate::from_ymd_opt(2000, 12, 31).unwrap()));
+        // The default format is used without format
+        let field: Field = serde_json::from_str(r#"{"name":"at","data_type":"DateTime","range":["2020-01-01T00:00:00Z","2021-01-01T00:00:00Z"]}"#).unwrap();
+        let err = qrlew::DataType::try_from(field).unwrap_err();
+        println!("{err}");
+        let field: Field = serde_json::from_str(r#"{"name":"at","data_type":"Time","range":["8h00","18h30"],"format":"%Hh%M"}"#).unwrap();
+        assert_eq!(qrlew::DataType::try_from(field).unwrap(), qrlew::DataType::time_interval(NaiveTime::from_hms_opt(8, 0, 0).unwrap(), NaiveTime::from_hms_opt(18, 30, 0).unwrap()));
+        let field: Field = serde_json::from_str(r#"{"name":"at","data_type":"Time","range":["8:00","18h30"],"format":"%Hh%M"}"#).unwrap();
+        assert!(qrlew::DataType::try_from(field).unwrap_err().to_string().contains(r#"could not parse range min "8:00" as time in format %Hh%M"#));
+    }
+
+    #[test]
+    fn test_range_and_possible_values() {
+        let field: Field = serde_json::from_str(r#"{"name":"age","data_type":"Integer","range":[18,65],"possible_values":[10,20,30,70]}"#).unwrap();
+        assert_eq!(field.range, Some((Value::from(18), Value::from(65))));
+        assert_eq!(field.possible_values.as_ref().map(Vec::len), Some(4));
+        let data_type = qrlew::DataType::try_from(field).unwrap();
+        println!("{data_type}");
+        assert_eq!(data_type, qrlew::DataType::integer_values([20, 30]));
+        let field: Field = serde_json::from_str(r#"{"name":"score","data_type":"Float","range":[0,1],"possible_values":[0.5,1.0,1.5]}"#).unwrap();
+        assert_eq!(qrlew::DataType::try_from(field).unwrap(), qrlew::DataType::float_values([0.5, 1.0]));
+        let field: Field = serde_json::from_str(r#"{"name":"day","data_type":"Date","range":["2020-01-01","2020-12-31"],"possible_values":["2019-06-01","2020-06-01"]}"#).unwrap();
+        assert_eq!(qrlew::DataType::try_from(field).unwrap(), qrlew::DataType::date_values([NaiveDate::from_ymd_opt(2020, 6, 1).unwrap()]));
+        let field: Field = serde_json::from_str(r#"{"name":"day","data_type":"DateTime","range":["2020-01-01T00:00:00Z","2020-12-31T00:00:00Z"],"possible_values":["2020-06-01T02:00:00+02:00"],"format":"%Y-%m-%dT%H:%M:%S%#z"}"#).unwrap();
+        assert_eq!(qrlew::DataType::try_from(field).unwrap(), qrlew::DataType::date_time_values([NaiveDate::from_ymd_opt(2020, 6, 1).unwrap().and_hms_opt(0, 0, 0).unwrap()]));
+        // The field still round trips
+        let field: Field = serde_json::from_str(r#"{"name":"age","data_type":"Integer","range":[18,65],"possible_values":[20]}"#).unwrap();
+        assert_eq!(serde_json::from_str::<Field>(&serde_json::to_string(&field).unwrap()).unwrap(), field);
+    }
+
+    #[test]
+    fn test_list_field() {
+        let field: Field = serde_json::from_str(r#"{"name":"tags","data_type":{"List":"Text"}}"#).unwrap();
+        assert_eq!(field.data_type, DataType::List(Box::new(DataType::Text)));
+        assert_eq!(serde_json::from_str::<Field>(&serde_json::to_string(&field).unwrap()).unwrap(), field);
+        let data_type = qrlew::DataType::try_from(field).unwrap();
+        println!("{data_type}");
+        assert_eq!(data_type, qrlew::DataType::List(qrlew::data_type::List::from_data_type(qrlew::DataType::text())));
+        // The possible values apply to the items
+        let field: Field = serde_json::from_str(r#"{"name":"tags","data_type":{"List":"Text"},"possible_values":["a","b"]}"#).unwrap();
+        assert_eq!(qrlew::DataType::try_from(field).unwrap(), qrlew::DataType::List(qrlew::data_type::List::from_data_type(qrlew::DataType::text_values(["a".to_string(), "b".to_string()]))));
+        // Nested lists
+        let field: Field = serde_json::from_str(r#"{"name":"matrix","data_type":{"List":{"List":"Integer"}},"range":[0,9]}"#).unwrap();
+        let data_type = qrlew::DataType::try_from(field).unwrap();
+        println!("{data_type}");
+        let row = qrlew::DataType::List(qrlew::data_type::List::from_data_type(qrlew::DataType::integer_interval(0, 9)));
+        assert_eq!(data_type, qrlew::DataType::List(qrlew::data_type::List::from_data_type(row)));
+        assert_eq!(DataType::simplify(&data_type), Some((DataType::List(Box::new(DataType::List(Box::new(DataType::Integer)))), false)));
+        let err = qrlew::DataType::try_from(serde_json::from_str::<Field>(r#"{"name":"tags","data_type":{"List":"Text"},"range":["a",1]}"#).unwrap()).unwrap_err();
+        assert_eq!(err.message(), "Invalid request: field `tags`: could not parse range max 1 as text");
+    }
+
+    #[test]
+    fn test_struct_field() {
+        let field: Field = serde_json::from_str(r#"{"name":"address","data_type":{"Struct":[
+            {"name":"city","data_type":"Text","possible_values":["Paris","Lyon"]},
+            {"name":"location","data_type":{"Struct":[{"name":"lat","data_type":"Float","range":[-90,90]},{"name":"lon","data_type":"Float","optional":true}]}}
+        ]}}"#).unwrap();
+        let DataType::Struct(fields) = &field.data_type else { panic!("expected a struct") };
+        assert_eq!(fields.len(), 2);
+        assert!(matches!(&fields[1].data_type, DataType::Struct(location) if location[1].optional == Some(true)));
+        assert_eq!(serde_json::from_str::<Field>(&serde_json::to_string(&field).unwrap()).unwrap(), field);
+        let data_type = qrlew::DataType::try_from(field).unwrap();
+        println!("{data_type}");
+        let location = qrlew::DataType::Struct(qrlew::data_type::Struct::new(vec![
+            ("lat".to_string(), Arc::new(qrlew::DataType::float_interval(-90., 90.))),
+            ("lon".to_string(), Arc::new(qrlew::DataType::optional(qrlew::DataType::float()))),
+        ]));
+        let address = qrlew::DataType::Struct(qrlew::data_type::Struct::new(vec![
+            ("city".to_string(), Arc::new(qrlew::DataType::text_values(["Paris".to_string(), "Lyon".to_string()]))),
+            ("location".to_string(), Arc::new(location)),
+        ]));
+        assert_eq!(data_type, address);
+        assert!(matches!(DataType::simplify(&data_type), Some((DataType::Struct(fields), false)) if fields.len() == 2));
+        // Nested fields are named in errors
+        let field: Field = serde_json::from_str(r#"{"name":"address","data_type":{"Struct":[{"name":"location","data_type":{"Struct":[{"name":"lat","data_type":"Float","range":["south",90]}]}}]}}"#).unwrap();
+        let err = qrlew::DataType::try_from(field).unwrap_err();
+        println!("{err}");
+        assert_eq!(err.message(), r#"Invalid request: field `address`: field `location`: field `lat`: could not parse range min "south" as float"#);
+        let field: Field = serde_json::from_str(r#"{"name":"address","data_type":{"Struct":[{"name":"city","data_type":"Text"}]},"possible_values":[1]}"#).unwrap();
+        assert!(qrlew::DataType::try_from(field).unwrap_err().to_string().contains("cannot be set for Struct fields"));
+    }
+
+    #[test]
+    fn test_optional_field() {
+        let field: Field = serde_json::from_str(r#"{"name":"age","data_type":"Integer","range":[0,100],"optional":true}"#).unwrap();
+        let data_type = qrlew::DataType::try_from(field.clone()).unwrap();
+        println!("{data_type}");
+        assert_eq!(data_type, qrlew::DataType::optional(qrlew::DataType::integer_interval(0, 100)));
+        let field = Field { optional: Some(false), ..field };
+        assert_eq!(qrlew::DataType::try_from(field).unwrap(), qrlew::DataType::integer_interval(0, 100));
+        let field: Field = serde_json::from_str(r#"{"name":"city","data_type":"Text","possible_values":["Paris","Lyon"],"optional":true}"#).unwrap();
+        assert_eq!(qrlew::DataType::try_from(field).unwrap(), qrlew::DataType::optional(qrlew::DataType::text_values(["Paris".to_string(), "Lyon".to_string()])));
+        // Fields are not optional by default
+        let field: Field = serde_json::from_str(r#"{"name":"age","data_type":"Integer"}"#).unwrap();
+        assert_eq!(field.optional, None);
+        assert_eq!(qrlew::DataType::try_from(field).unwrap(), qrlew::DataType::integer());
+    }
+
+    #[test]
+    fn test_string_encoded_integers() {
+        let field: Field = serde_json::from_str(r#"{"name":"id","data_type":"Integer","range":[0,"9007199254740993"]}"#).unwrap();
+        assert_eq!(qrlew::DataType::try_from(field).unwrap(), qrlew::DataType::integer_interval(0, 9007199254740993));
+        let field: Field = serde_json::from_str(r#"{"name":"id","data_type":"Integer","possible_values":[1,"9223372036854775807"]}"#).unwrap();
+        assert_eq!(qrlew::DataType::try_from(field).unwrap(), qrlew::DataType::integer_values([1, i64::MAX]));
+        let field: Field = serde_json::from_str(r#"{"name":"id","data_type":"Integer","range":[0,"9223372036854775808"]}"#).unwrap();
+        let err = qrlew::DataType::try_from(field).unwrap_err();
+        println!("{err}");
+        assert!(err.to_string().contains("field `id`: could not parse range max \"9223372036854775808\" as 64 bit integer"));
+        // Requests with such fields are rejected
+        let request: Dot = serde_json::from_str(r#"{"dataset":{"tables":[{"name":"t","path":["t"],"schema":{"fields":[{"name":"id","data_type":"Integer","range":[0,"9223372036854775808"]}]},"size":10}]},"query":"SELECT id FROM t","dark_mode":false}"#).unwrap();
+        assert!(request.response().is_err());
+    }
+
+    #[test]
+    fn test_synthetic_data_resolver() {
+        let dataset: Dataset = serde_json::from_str(r#"{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"}]},"size":100},{"name":"action_table","path":["schema","action_table"],"schema":{"fields":[{"name":"user_id","data_type":"Integer"}]},"size":100}]}"#).unwrap();
+        let relations: Hierarchy<Arc<Relation>> = dataset.try_into().unwrap();
+        let resolver = |path: &[String]| (path.last()? == "user_table").then(|| vec!["schema".to_string(), "sd_user_table".to_string()]);
+        let synthetic = synthetic_data(vec![], &relations, Some(&resolver)).unwrap();
+        println!("{:?}", synthetic);
+        let synthetic_path = |synthetic: &SyntheticData, table: &str| match relations[["schema", table]].as_ref() {
+            Relation::Table(table) => synthetic.table(table).ok().and_then(|sd_relation| match Relation::from(sd_relation) {
+                Relation::Table(sd_table) => Some(sd_table.path().last().ok()?.to_string()),
+                _ => None,
+            }),
+            _ => None,
+        };
+        assert_eq!(synthetic_path(&synthetic, "user_table"), Some("sd_user_table".to_string()));
+        assert_eq!(synthetic_path(&synthetic, "action_table"), None);
+        // Inline synthetic data overrides the resolver
+        let synthetic = synthetic_data(vec![("user_table".to_string(), "other_sd".to_string())], &relations, Some(&resolver)).unwrap();
+        assert_eq!(synthetic_path(&synthetic, "user_table"), Some("other_sd".to_string()));
+        assert!(synthetic_data(vec![], &relations, None).is_none());
+    }
+
+    #[test]
+    fn test_check_budget() {
+        let request_str = r#"
+        {"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"name","data_type":"Text"},{"name":"age","data_type":"Integer","range":[0,100]},{"name":"weight","data_type":"Float"}]},"size":10000}]},"query":"SELECT name, sum(age) FROM user_table GROUP BY name","synthetic_data":[],"privacy_unit":[["user_table",[],"id"]],"epsilon":1.0,"delta":0.00001}
+"#;
+        let request: CheckBudget = serde_json::from_str(request_str).unwrap();
+        let response = request.clone().response().unwrap();
+        println!("{}", response.value());
+        // Nothing is signed
+        assert!(response.signature().is_none());
+        let check: BudgetCheck = serde_json::from_str(response.value()).unwrap();
+        assert!(check.feasible);
+        assert!(!check.mechanisms.is_empty());
+        let check: BudgetCheck = serde_json::from_str(CheckBudget { options: RewriteOptions { delta: 0., ..request.options.clone() }, ..request.clone() }.response().unwrap().value()).unwrap();
+        println!("{:?}", check.reason);
+        assert!(!check.feasible);
+        let check: BudgetCheck = serde_json::from_str(CheckBudget { query: "SELECT name FROM user_table".to_string(), ..request.clone() }.response().unwrap().value()).unwrap();
+        println!("{:?}", check.reason);
+        assert!(!check.feasible);
+        // Invalid budgets are reported as validate_budget rejects them
+        let check: BudgetCheck = serde_json::from_str(CheckBudget { options: RewriteOptions { epsilon: -1., ..request.options.clone() }, ..request.clone() }.response().unwrap().value()).unwrap();
+        println!("{:?}", check.reason);
+        assert!(!check.feasible);
+        assert!(check.reason.unwrap().contains(&validate_budget(-1., 1e-5).unwrap_err().message()));
+        // An empty privacy unit is rejected as by the DP rewriting, unless allowed
+        let request_without_privacy_unit = CheckBudget { options: RewriteOptions { privacy_unit: vec![], ..request.options.clone() }, ..request.clone() };
+        assert!(matches!(request_without_privacy_unit.clone().response(), Err(Error::InvalidRequest(_))));
+        let check: BudgetCheck = serde_json::from_str(CheckBudget { allow_no_privacy_unit: true, ..request_without_privacy_unit }.response().unwrap().value()).unwrap();
+        assert!(check.feasible);
+        // The budget left to the protected tables is reported and checked when accounting is enabled
+        let accountant = BudgetAccountant::new(1.5, 1e-4);
+        let check: BudgetCheck = serde_json::from_str(request.clone().response_with(Some(&accountant)).unwrap().value()).unwrap();
+        assert!(check.feasible);
+        assert_eq!((check.remaining_epsilon, check.remaining_delta), (Some(1.5), Some(1e-4)));
+        assert!(accountant.try_spend(&["schema.user_table"], 1., 1e-5));
+        let check: BudgetCheck = serde_json::from_str(request.clone().response_with(Some(&accountant)).unwrap().value()).unwrap();
+        println!("{:?}", check.reason);
+        assert!(!check.feasible);
+        assert!(check.reason.unwrap().contains("schema.user_table"));
+        assert_eq!(check.remaining_epsilon, Some(0.5));
+        // Nothing is spent by checking
+        assert_eq!(accountant.remaining("schema.user_table"), (0.5, 9e-5));
+    }
+
+    #[test]
+    fn test_anonymized_dot() {
+        let request_str = r#"{"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"age","data_type":"Integer"}]},"size":10000},{"name":"b","path":["schema","b"],"schema":{"fields":[{"name":"user_id","data_type":"Integer"},{"name":"duration","data_type":"Float"}]},"size":10000}]},"query":"SELECT age, sum(duration) AS total FROM user_table JOIN b ON id = user_id WHERE duration > 1 GROUP BY age","dark_mode":false,"cluster_by_table":true,"anonymize":true}"#;
+        let request: Dot = serde_json::from_str(request_str).unwrap();
+        let plain = Dot { anonymize: false, ..request.clone() }.response().unwrap();
+        let dot = request.response().unwrap();
+        println!("{}", dot.value());
+        for name in ["user_table", "USER_TABLE", "B ", "user_id", "age", "duration"] {
+            assert!(!dot.value().contains(name), "{name} is not anonymized");
+        }
+        assert!(dot.value().contains("<b>t1 size"));
+        assert!(dot.value().contains("label=\"t2\""));
+        assert!(dot.value().contains("INNER ON (_LEFT_.c1 = _RIGHT_.c3)"));
+        // The structure is preserved
+        assert_eq!(dot.value().lines().count(), plain.value().lines().count());
+    }
+
+    #[test]
+    fn test_wrapper_template() {
+        let auth = Authenticator::get("secret_key.pem").unwrap();
+        let request_str = r#"
+        {"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"age","data_type":"Integer","range":[0,100]}]},"size":10000}]},"query":"SELECT sum(age) FROM user_table","synthetic_data":[],"privacy_unit":[["user_table",[],"id"]],"epsilon":1.0,"delta":0.00001,"wrapper_template":"INSERT INTO results {query}"}
+"#;
+        let request: RewriteWithDifferentialPrivacy = serde_json::from_str(request_str).unwrap();
+        let response = request.clone().response(&auth).unwrap();
+        println!("{}", response.value());
+        assert!(response.value().starts_with("INSERT INTO results WITH"));
+        auth.verify(&response.signed_payload(), response.signature().unwrap()).expect("OK");
+        for template in ["INSERT INTO results SELECT 1", "{query} UNION {query}"] {
+            let err = RewriteWithDifferentialPrivacy { wrapper_template: Some(template.to_string()), ..request.clone() }.response(&auth).unwrap_err();
+            println!("{err}");
+            assert!(matches!(err, Error::InvalidRequest(_)));
+        }
+    }
+
+    #[test]
+    fn test_privacy_unit_diff() {
+        let request_str = r#"
+        {"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"name","data_type":"Text"}]},"size":10000},{"name":"action_table","path":["schema","action_table"],"schema":{"fields":[{"name":"action","data_type":"Text"},{"name":"user_id","data_type":"Integer"}]},"size":10000},{"name":"item_table","path":["schema","item_table"],"schema":{"fields":[{"name":"item","data_type":"Text"},{"name":"owner","data_type":"Text"}]},"size":10000}]},
+        "before":[["user_table",[],"id"],["item_table",[],"owner"]],
+        "after":[["user_table",[],"name"],["action_table",[["user_id","user_table","id"]],"id"],["item_table",[],"unknown"]]}
+"#;
+        let request: PrivacyUnitDiff = serde_json::from_str(request_str).unwrap();
+        let response = request.response().unwrap();
+        println!("{}", response.value());
+        let changes: ProtectionChanges = serde_json::from_str(response.value()).unwrap();
+        assert_eq!(changes.gained, BTreeMap::from([("action_table".to_string(), vec!["action".to_string(), "user_id".to_string()])]));
+        // The privacy unit column of item_table does not exist
+        assert_eq!(changes.lost, BTreeMap::from([("item_table".to_string(), vec!["item".to_string(), "owner".to_string()])]));
+        assert_eq!(changes.changed, vec!["user_table".to_string()]);
+    }
+
+    #[test]
+    fn test_empty_privacy_unit() {
+        let auth = Authenticator::get("secret_key.pem").unwrap();
+        let request_str = r#"
+        {"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"age","data_type":"Integer","range":[0,100]}]},"size":10000}]},"query":"SELECT sum(age) FROM user_table","synthetic_data":[],"privacy_unit":[],"epsilon":1.0,"delta":0.00001}
+"#;
+        let request: RewriteWithDifferentialPrivacy = serde_json::from_str(request_str).unwrap();
+        let err = request.clone().response(&auth).unwrap_err();
+        println!("{err}");
+        assert!(err.to_string().contains("the privacy unit is empty"));
+        // Explicitly allowed, all tables are public
+        let response = RewriteWithDifferentialPrivacy { allow_no_privacy_unit: true, ..request }.response(&auth).unwrap();
+        println!("{}", response.value());
+    }
+
+    #[test]
+    fn test_max_privacy_unit_contribution() {
+        let auth = Authenticator::get("secret_key.pem").unwrap();
+        let request_str = r#"
+        {"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"age","data_type":"Integer","range":[0,100]}]},"size":100000}]},"query":"SELECT sum(age) AS total FROM user_table","synthetic_data":[],"privacy_unit":[["user_table",[],"id"]],"epsilon":1.0,"delta":0.00001}
+"#;
+        let request: RewriteWithDifferentialPrivacy = serde_json::from_str(request_str).unwrap();
+        let default_scale = request.clone().response(&auth).unwrap().noise_scales().unwrap()["total"];
+        let bounded_scale = RewriteWithDifferentialPrivacy { max_privacy_unit_contribution: Some(10), ..request.clone() }.response(&auth).unwrap().noise_scales().unwrap()["total"];
+        println!("{default_scale} {bounded_scale}");
+        // Tighter contribution bounds lower the sensitivity, hence the noise
+        assert!(bounded_scale < default_scale);
+        assert!(RewriteWithDifferentialPrivacy { max_privacy_unit_contribution: Some(0), ..request }.response(&auth).is_err());
+    }
+
+    #[test]
+    fn test_dataset_cache() {
+        let dataset: Dataset = serde_json::from_str(r#"{"tables":[{"name":"cached_dataset_table","path":["schema","cached_dataset_table"],"schema":{"fields":[{"name":"a","data_type":"Float","range":[0,1]}]},"size":100}]}"#).unwrap();
+        let (relations, warnings) = dataset.clone().into_relations().unwrap();
+        assert!(warnings.is_empty());
+        // Converted again, the same relations are returned from the cache
+        let (cached, _) = dataset.clone().into_relations().unwrap();
+        let path = ["schema".to_string(), "cached_dataset_table".to_string()];
+        assert!(Arc::ptr_eq(&relations[&path[..]], &cached[&path[..]]));
+        assert_eq!(relations, dataset.clone().try_into().unwrap());
+        // Another dataset is converted anew
+        let mut other = dataset;
+        other.tables[0].size = 200;
+        let (other, _) = other.into_relations().unwrap();
+        assert!(!Arc::ptr_eq(&relations[&path[..]], &other[&path[..]]));
+        assert_eq!(other[&path[..]].size().max(), Some(&200));
+    }
+
+    #[test]
+    fn test_dot_cache() {
+        let request_str = r#"{"dataset":{"tables":[{"name":"cached_table","path":["schema","cached_table"],"schema":{"fields":[{"name":"a","data_type":"Float"}]},"size":10000}]},"query":"SELECT a FROM cached_table","dark_mode":false}"#;
+        let request: Dot = serde_json::from_str(request_str).unwrap();
+        let relation = Relation::try_from(parse_query(&request.query).unwrap().with(&request.dataset.clone().try_into().unwrap())).unwrap();
+        let dot = request.clone().response().unwrap();
+        // Built again, the relation is the same and its graph is cached
+        assert_eq!(rendered_dot(&relation, false).unwrap(), dot.value());
+        let mut hasher = std::hash::DefaultHasher::new();
+        (&relation, false).hash(&mut hasher);
+        assert_eq!(dot_cache().get(&hasher.finish()).as_deref(), Some(dot.value()));
+        let dark = Dot { dark_mode: true, ..request }.response().unwrap();
+        assert_ne!(dark.value(), dot.value());
+    }
+
+    #[test]
+    fn test_validate_principal() {
+        assert!(validate_principal(None).is_ok());
+        assert!(validate_principal(Some("alice@example.com")).is_ok());
+        assert!(validate_principal(Some(" ")).is_err());
+        assert!(validate_principal(Some(&"a".repeat(MAX_PRINCIPAL_LENGTH + 1))).is_err());
+        assert!(validate_principal(Some("alice\nbob")).is_err());
+        let request_str = r#"
+        {"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"age","data_type":"Integer","range":[0,100]}]},"size":10000}]},"query":"SELECT sum(age) FROM user_table","synthetic_data":[],"privacy_unit":[["user_table",[],"id"]],"epsilon":1.0,"delta":0.00001,"principal":""}
+"#;
+        let request: RewriteWithDifferentialPrivacy = serde_json::from_str(request_str).unwrap();
+        assert!(request.response(&Authenticator::get("secret_key.pem").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_relation_fingerprint() {
+        let fingerprint = |query: &str| {
+            let request_str = format!(r#"{{"dataset":{{"tables":[{{"name":"table_1","path":["schema","table_1"],"schema":{{"fields":[{{"name":"a","data_type":"Float"}},{{"name":"b","data_type":"Integer"}}]}},"size":10000}}]}},"query":"{query}","dark_mode":false}}"#);
+            let request: Dot = serde_json::from_str(&request_str).unwrap();
+            request.response().unwrap().relation_fingerprint().unwrap().to_string()
+        };
+        let reference = fingerprint("SELECT sum(a) FROM table_1 WHERE b > 0");
+        println!("{reference}");
+        assert_eq!(fingerprint("select   sum(a)  from table_1 where b>0"), reference);
+        assert_ne!(fingerprint("SELECT sum(a) FROM table_1 WHERE b > 1"), reference);
+    }
+
+    #[test]
+    fn test_check_declared_columns() {
+        let dataset: Dataset = serde_json::from_str(r#"{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"age","data_type":"Integer"}]},"size":100}]}"#).unwrap();
+        let check = |query: &str| check_declared_columns(&parse_query(query).unwrap(), &dataset);
+        for query in [
+            "SELECT id, AGE FROM user_table",
+            "SELECT u.id AS x FROM schema.user_table AS u ORDER BY x",
+            "WITH t (y) AS (SELECT id FROM user_table) SELECT y, count(*) AS n FROM t GROUP BY y",
+            "SELECT s.a FROM (SELECT age AS a FROM user_table) AS s",
+        ] {
+            assert!(check(query).is_ok(), "{query}");
+        }
+        let err = check("SELECT nme, id FROM user_table WHERE agee > 1").unwrap_err();
+        println!("{err}");
+        assert_eq!(err.to_string().trim(), "InvalidRequest: Invalid request: undeclared columns: agee, nme");
+        assert!(check(r#"SELECT "ID" FROM user_table"#).is_err());
+        // Misspelled columns are rejected before reaching qrlew
+        let request_str = r#"{"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"}]},"size":100}]},"query":"SELECT idd FROM user_table","dark_mode":false}"#;
+        let request: Dot = serde_json::from_str(request_str).unwrap();
+        assert!(matches!(request.response(), Err(Error::InvalidRequest(_))));
+    }
+
+    #[test]
+    fn test_limit_possible_values() {
+        let dataset: Dataset = serde_json::from_str(r#"{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"city","data_type":"Text","possible_values":["Paris","Lyon","Lille"]},{"name":"flag","data_type":"Boolean","possible_values":[true,false]}]},"size":100}]}"#).unwrap();
+        assert_eq!(dataset.clone().limit_possible_values(None, false).unwrap(), Vec::<String>::new());
+        let err = dataset.clone().limit_possible_values(Some(2), false).unwrap_err();
+        println!("{err}");
+        assert!(matches!(err, Error::InvalidRequest(_)));
+        let mut degraded = dataset.clone();
+        let warnings = degraded.limit_possible_values(Some(2), true).unwrap();
+        println!("{warnings:?}");
+        assert_eq!(warnings, vec!["the 3 possible values of user_table.city were ignored (more than 2)".to_string()]);
+        let fields = &degraded.tables[0].schema.fields;
+        assert_eq!(fields[0].possible_values, None);
+        assert_eq!(fields[1].possible_values.as_ref().map(Vec::len), Some(2));
+        let relations: Hierarchy<Arc<Relation>> = degraded.try_into().unwrap();
+        println!("{}", relations[["schema", "user_table"]].schema());
+    }
+
+    #[test]
+    fn test_infer_schema() {
+        let request: InferSchema = serde_json::from_str(r#"{
+            "column_names":["id","score","ok","city","dob","mixed","empty","at"],
+            "rows":[
+                [1,0.5,true,"Paris","2000-01-31",1,null,"12:00:00"],
+                [3,2,false,"Lyon","1990-06-01","one",null,"08:30:00"],
+                [2,null,true,"Paris","2010-12-01",2.5,null,"18:00:00"]
+            ]
+        }"#).unwrap();
+        let response = request.response().unwrap();
+        println!("{}", response.value());
+        let schema: Schema = serde_json::from_str(response.value()).unwrap();
+        let field = |name: &str| schema.fields.iter().find(|field| field.name == name).unwrap().clone();
+        assert_eq!(schema.fields.iter().map(|field| field.name.as_str()).collect::<Vec<_>>(), vec!["id", "score", "ok", "city", "dob", "mixed", "empty", "at"]);
+        assert_eq!(field("id").data_type, DataType::Integer);
+        assert_eq!(field("id").range, Some((Value::from(1), Value::from(3))));
+        assert_eq!(field("id").optional, None);
+        // Integers and floats widen to floats
+        assert_eq!(field("score").data_type, DataType::Float);
+        assert_eq!(field("score").range, Some((Value::from(0.5), Value::from(2))));
+        assert_eq!(field("score").optional, Some(true));
+        assert_eq!(field("ok").possible_values, Some(vec![Value::from(true), Value::from(false)]));
+        assert_eq!(field("city").data_type, DataType::Text);
+        assert_eq!(field("city").possible_values, Some(vec![Value::from("Paris"), Value::from("Lyon")]));
+        assert_eq!(field("dob").data_type, DataType::Date);
+        assert_eq!(field("dob").range, Some((Value::from("1990-06-01"), Value::from("2010-12-01"))));
+        assert_eq!(field("at").data_type, DataType::Time);
+        // Mixed types widen to Text, empty columns default to Text
+        assert_eq!(field("mixed").data_type, DataType::Text);
+        assert_eq!(field("mixed").possible_values, None);
+        assert_eq!(field("empty").data_type, DataType::Text);
+        assert_eq!(field("empty").optional, Some(true));
+        // The inferred schema is a valid schema
+        assert!(qrlew::relation::Schema::try_from(schema).is_ok());
+        let schema: Schema = serde_json::from_str(InferSchema { rows: vec![], column_names: vec!["x".to_string()] }.response().unwrap().value()).unwrap();
+        assert_eq!(schema.fields[0].data_type, DataType::Text);
+        // Rows must have a value per column
+        let err = InferSchema { rows: vec![vec![Value::from(1)], vec![]], column_names: vec!["x".to_string()] }.response().unwrap_err();
+        println!("{err}");
+        assert!(matches!(err, Error::InvalidRequest(_)));
+        assert!(err.to_string().contains("row 1 has 0 values"));
+    }
+
+    #[test]
+    fn test_json_schema() {
+        let schema = json_schema("rewrite_with_differential_privacy").unwrap();
+        println!("{}", serde_json::to_string_pretty(&schema).unwrap());
+        let required: Vec<&str> = schema["required"].as_array().unwrap().iter().map(|field| field.as_str().unwrap()).collect();
+        // delta defaults to 0
+        assert_eq!(required, vec!["dataset", "epsilon", "query", "synthetic_data"]);
+        assert!(schema["properties"]["privacy_unit"].is_object());
+        assert!(json_schema("unknown").is_err());
+    }
+
+    #[test]
+    fn test_with_dataset_file() {
+        let dataset_dir = std::env::temp_dir().join(format!("qrlew_server_test_datasets_{}", std::process::id()));
+        std::fs::create_dir_all(&dataset_dir).unwrap();
+        std::fs::write(dataset_dir.join("dataset.json"), r#"{"tables":[{"name":"table_1","path":["schema","table_1"],"schema":{"fields":[{"name":"a","data_type":"Float"}]},"size":10000}]}"#).unwrap();
+        let dataset_dir_str = dataset_dir.to_str().unwrap();
+        let body = with_dataset_file(serde_json::json!({"dataset_file":"dataset.json","query":"SELECT * FROM table_1","dark_mode":false}), dataset_dir_str).unwrap();
+        let request: Dot = serde_json::from_value(body).unwrap();
+        println!("{}", request.response().unwrap().value());
+        // Inline datasets are left as is
+        let body = serde_json::json!({"dataset":{"tables":[]},"query":"SELECT 1","dark_mode":false});
+        assert_eq!(with_dataset_file(body.clone(), dataset_dir_str).unwrap(), body);
+        // Files outside of the directory cannot be read
+        assert!(with_dataset_file(serde_json::json!({"dataset_file":"../../etc/passwd"}), dataset_dir_str).is_err());
+        assert!(with_dataset_file(serde_json::json!({"dataset_file":"dataset.json","dataset":{"tables":[]}}), dataset_dir_str).is_err());
+        std::fs::remove_dir_all(dataset_dir).unwrap();
+    }
+
+    #[test]
+    fn test_dot_serialize() {
+        let request = Dot {
+            dataset: Dataset { tables: vec![
+                Table {
+                    name: "table_1".to_string(),
+                    path: vec!["schema".to_string(), "table_1".to_string()],
+                    schema: Schema { fields: vec![
+                        Field { name: "a".to_string(), data_type: DataType::Float, constraint: None, range: None, possible_values: None, description: None, optional: None, format: None },
+                        Field { name: "b".to_string(), data_type: DataType::Integer, constraint: Some(Constraint::Unique), range: None, possible_values: None, description: None, optional: None, format: None },
+                    ]},
+                    size: 10000 }
+            ]},
+            query: "SELECT * FROM table_1".to_string(),
+            dark_mode: true,
+            cluster_by_table: false,
+            with_unique_columns: false,
+            anonymize: false,
+        };
+
+        println!("{}", serde_json::to_string_pretty(&request).unwrap());
+        println!("{}", serde_json::to_string(&request).unwrap());
+    }
+
+    #[test]
+    fn test_dot_deserialize() {
+        let request_str = r#"{"dataset":{"tables":[{"name":"table_1","path":["schema","table_1"],"schema":{"fields":[{"name":"a","data_type":"Float"},{"name":"b","data_type":"Integer"}]},"size":10000}]},"query":"SELECT * FROM table_1","dark_mode":true}"#;
+        let request: Dot = serde_json::from_str(request_str).unwrap();
+        println!("{:?}", request);
+    }
+
+    #[test]
+    fn test_dot() {
+        let request_str = r#"{"dataset":{"tables":[{"name":"table_1","path":["schema","table_1"],"schema":{"fields":[{"name":"a","data_type":"Float"},{"name":"b","data_type":"Integer"}]},"size":10000}]},"query":"SELECT * FROM table_1","dark_mode":false}"#;
+        let request: Dot = serde_json::from_str(request_str).unwrap();
+        // println!("{:?}", request.response().unwrap());
+        println!("{}", request.response().unwrap().value());
+    }
+
+    #[test]
+    fn test_dot_svg() {
+        let request_str = r#"{"dataset":{"tables":[{"name":"table_1","path":["schema","table_1"],"schema":{"fields":[{"name":"a","data_type":"Float"},{"name":"b","data_type":"Integer"}]},"size":10000}]},"query":"SELECT a, count(b) AS n FROM table_1 GROUP BY a","dark_mode":false}"#;
+        let request: Dot = serde_json::from_str(request_str).unwrap();
+        let svg = request.response_svg().unwrap();
+        println!("{svg}");
+        assert!(svg.contains("<svg"));
+        assert!(svg.contains("TABLE_1"));
+        assert_eq!(simplify_dot(r#"a[label=<<b>T "x"</b><br/>a &lt; b>][color="red"];"#), r#"a[label="T \"x\"\na < b", color="red"];"#);
+        assert!(matches!(dot_to_svg("digraph {"), Err(Error::Other(_))));
+    }
+
+    #[test]
+    fn test_parse_query() {
+        assert!(parse_query("SELECT a, count(*) FROM t GROUP BY a").is_ok());
+        assert!(parse_query("WITH u AS (SELECT * FROM t) SELECT * FROM u UNION SELECT * FROM v").is_ok());
+        for query in [
+            "DELETE FROM t",
+            "UPDATE t SET a = 1",
+            "CREATE TABLE t (a INT)",
+            "INSERT INTO t VALUES (1)",
+            "SELECT * INTO u FROM t",
+            "SELECT 1; DROP TABLE t",
+        ] {
+            let err = parse_query(query).unwrap_err();
+            println!("{err}");
+            assert!(matches!(err, Error::InvalidRequest(_)));
+        }
+    }
+
+    #[test]
+    fn test_dot_unique_columns() {
+        let request_str = r#"{"dataset":{"tables":[{"name":"table_1","path":["schema","table_1"],"schema":{"fields":[{"name":"a","data_type":"Float"},{"name":"b","data_type":"Integer","constraint":"Unique"}]},"size":10000}]},"query":"SELECT a, b FROM table_1","dark_mode":false,"with_unique_columns":true}"#;
+        let request: Dot = serde_json::from_str(request_str).unwrap();
+        let response = request.response().unwrap();
+        assert_eq!(response.unique_columns(), Some(&["b".to_string()][..]));
+        let request: Dot = serde_json::from_str(&request_str.replace(r#""with_unique_columns":true"#, r#""with_unique_columns":false"#)).unwrap();
+        assert_eq!(request.response().unwrap().unique_columns(), None);
+    }
+
+    #[test]
+    fn test_dot_cluster_by_table() {
+        let request_str = r#"{"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"name","data_type":"Text"}]},"size":10000},{"name":"action_table","path":["schema","action_table"],"schema":{"fields":[{"name":"action","data_type":"Text"},{"name":"user_id","data_type":"Integer"}]},"size":10000}]},"query":"SELECT * FROM (SELECT user_id, count(action) AS n FROM action_table GROUP BY user_id) AS a JOIN user_table AS u ON a.user_id = u.id","dark_mode":false,"cluster_by_table":true}"#;
+        let request: Dot = serde_json::from_str(request_str).unwrap();
+        let dot = request.response().unwrap().value().to_string();
+        println!("{dot}");
+        assert!(dot.contains("subgraph cluster_0"));
+        assert!(dot.contains("label=\"user_table\""));
+        assert!(dot.contains("label=\"action_table\""));
+        assert!(dot.trim_end().ends_with('}'));
+    }
+
+    #[test]
+    fn test_dot_with_description() {
+        let request_str = r#"{"dataset":{"tables":[{"name":"table_1","path":["schema","table_1"],"schema":{"fields":[{"name":"a","data_type":"Float"},{"name":"b","data_type":"Integer"}]},"size":10000}]},"query":"SELECT * FROM table_1","dark_mode":false}"#;
+        let request_with_description_str = r#"{"dataset":{"tables":[{"name":"table_1","path":["schema","table_1"],"schema":{"fields":[{"name":"a","data_type":"Float","description":"Some float"},{"name":"b","data_type":"Integer"}]},"size":10000}]},"query":"SELECT * FROM table_1","dark_mode":false}"#;
+        let request: Dot = serde_json::from_str(request_str).unwrap();
+        let request_with_description: Dot = serde_json::from_str(request_with_description_str).unwrap();
+        // Fields without description are serialized without it
+        assert!(!serde_json::to_string(&request.dataset).unwrap().contains("description"));
+        assert!(serde_json::to_string(&request_with_description.dataset).unwrap().contains(r#""description":"Some float""#));
+        assert_eq!(request.response().unwrap().value(), request_with_description.response().unwrap().value());
+    }
+
+    #[test]
+    fn test_query_columns() {
+        let request_str = r#"{"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"name","data_type":"Text"},{"name":"age","data_type":"Integer"}]},"size":10000},{"name":"action_table","path":["schema","action_table"],"schema":{"fields":[{"name":"action","data_type":"Text"},{"name":"user_id","data_type":"Integer"},{"name":"duration","data_type":"Float"}]},"size":10000}]},"query":"SELECT u.name, sum(a.duration) AS total FROM action_table AS a JOIN user_table AS u ON a.user_id = u.id WHERE a.action = 'click' GROUP BY u.name"}"#;
+        let request: QueryColumns = serde_json::from_str(request_str).unwrap();
+        let response = request.response().unwrap();
+        println!("{}", response.value());
+        let columns: BTreeMap<String, Vec<String>> = serde_json::from_str(response.value()).unwrap();
+        assert_eq!(columns["action_table"], vec!["action", "duration", "user_id"]);
+        assert_eq!(columns["user_table"], vec!["id", "name"]);
+    }
+
+    #[test]
+    fn test_parse() {
+        let request_str = r#"{"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"age","data_type":"Integer","range":[0,100]},{"name":"city","data_type":"Text","possible_values":["Paris","Lyon"],"optional":true}]},"size":100}]},"query":"SELECT age + 1 AS next_age, city, count(*) AS count FROM user_table GROUP BY age, city"}"#;
+        let request: Parse = serde_json::from_str(request_str).unwrap();
+        let response = request.response().unwrap();
+        println!("{}", response.value());
+        let columns: Vec<RelationColumn> = serde_json::from_str(response.value()).unwrap();
+        assert_eq!(columns.iter().map(|column| column.name.as_str()).collect::<Vec<_>>(), ["next_age", "city", "count"]);
+        assert_eq!(columns[0].data_type, qrlew::DataType::integer_interval(1, 101).to_string());
+        assert!(columns[1].data_type.contains("Lyon"));
+        // Unknown tables are reported
+        let request: Parse = serde_json::from_str(&request_str.replace("FROM user_table", "FROM unknown_table")).unwrap();
+        assert!(request.response().is_err());
+    }
+
+    #[test]
+    fn test_union_schema() {
+        let dataset = r#"{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer","range":[0,1000]},{"name":"name","data_type":"Text"},{"name":"weight","data_type":"Float","range":[0.0,200.0]}]},"size":100}]}"#;
+        let union_schema = |queries: &[&str]| serde_json::from_str::<UnionSchema>(&format!(r#"{{"dataset":{dataset},"queries":{}}}"#, serde_json::to_string(queries).unwrap())).unwrap().response();
+        let response = union_schema(&["SELECT id AS x, name FROM user_table", "SELECT weight AS x, id FROM user_table"]).unwrap();
+        println!("{}", response.value());
+        let columns: Vec<OutputColumn> = serde_json::from_str(response.value()).unwrap();
+        assert_eq!(columns, vec![
+            OutputColumn { name: "x".to_string(), data_type: DataType::Float, nullable: false },
+            OutputColumn { name: "name".to_string(), data_type: DataType::Text, nullable: true },
+            OutputColumn { name: "id".to_string(), data_type: DataType::Integer, nullable: true },
+        ]);
+        let err = union_schema(&["SELECT name AS x FROM user_table", "SELECT id AS x FROM user_table"]).unwrap_err();
+        println!("{err}");
+        assert!(matches!(err, Error::InvalidRequest(_)));
+        assert!(matches!(union_schema(&[]), Err(Error::InvalidRequest(_))));
+    }
+
+    #[test]
+    fn test_rewrite_as_pup_serialize() {
+        let request = RewriteAsPrivacyUnitPreserving {
+            dataset: Dataset { tables: vec![
+                Table {
+                    name: "user_table".to_string(),
+                    path: vec!["schema".to_string(), "user_table".to_string()],
+                    schema: Schema { fields: vec![
+                        Field { name: "id".to_string(), data_type: DataType::Integer, constraint: Some(Constraint::Unique), range: None, possible_values: None, description: None, optional: None, format: None },
+                        Field { name: "name".to_string(), data_type: DataType::Text, constraint: None, range: None, possible_values: None, description: None, optional: None, format: None },
+                        Field { name: "age".to_string(), data_type: DataType::Integer, constraint: None, range: None, possible_values: None, description: None, optional: None, format: None },
+                        Field { name: "weight".to_string(), data_type: DataType::Float, constraint: None, range: None, possible_values: None, description: None, optional: None, format: None },
+                    ]},
+                    size: 10000,
+                },
+                Table {
+                    name: "action_table".to_string(),
+                    path: vec!["schema".to_string(), "action_table".to_string()],
+                    schema: Schema { fields: vec![
+                        Field { name: "action".to_string(), data_type: DataType::Text, constraint: None, range: None, possible_values: None, description: None, optional: None, format: None },
+                        Field { name: "user_id".to_string(), data_type: DataType::Integer, constraint: None, range: None, possible_values: None, description: None, optional: None, format: None },
+                        Field { name: "duration".to_string(), data_type: DataType::Float, constraint: None, range: None, possible_values: None, description: None, optional: None, format: None },
+                    ]},
+                    size: 10000,
+                },
+            ]},
+            query: "SELECT * FROM action_table".to_string(),
+            options: RewriteOptions {
+                synthetic_data: vec![
+                    ("user_table".to_string(), "synthetic_user_table".to_string()),
+                    ("action_table".to_string(), "synthetic_action_table".to_string()),
+                ],
+                privacy_unit: vec![
+                    ("user_table".to_string(), vec![], "id".to_string()),
+                    ("action_table".to_string(), vec![("user_id".to_string(), "user_table".to_string(), "id".to_string())], "id".to_string()),
+                ],
+                privacy_unit_token: None,
+                privacy_unit_paths: vec![],
+                infer_privacy_unit_links: false,
+                epsilon: 1.,
+                delta: 1e-5,
+            },
+            with_unique_columns: false,
+            output_aliases: HashMap::new(),
+            dialect: None,
+            formats: vec![],
+            wrapper_template: None,
+            principal: None,
+        };
+
+        println!("{}", serde_json::to_string_pretty(&request).unwrap());
+        println!("{}", serde_json::to_string(&request).unwrap());
+    }
+
+    #[test]
+    fn test_rewrite_as_pup_deserialize() {
+        let request_str = r#"{"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"name","data_type":"Text"},{"name":"age","data_type":"Integer"},{"name":"weight","data_type":"Float"}]},"size":10000},{"name":"action_table","path":["schema","action_table"],"schema":{"fields":[{"name":"action","data_type":"Text"},{"name":"user_id","data_type":"Integer"},{"name":"duration","data_type":"Float"}]},"size":10000}]},"query":"SELECT * FROM action_table","synthetic_data":[["user_table","synthetic_user_table"],["action_table","synthetic_action_table"]],"privacy_unit":[["user_table",[],"id"],["action_table",[["user_id","user_table","id"]],"id"]],"epsilon":1.0,"delta":0.00001}"#;
+        let request: RewriteAsPrivacyUnitPreserving = serde_json::from_str(request_str).unwrap();
+        println!("{:?}", request);
+    }
+
+    #[test]
+    fn test_rewrite_as_pup() {
+        let request_str = r#"{"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"name","data_type":"Text"},{"name":"age","data_type":"Integer"},{"name":"weight","data_type":"Float"}]},"size":10000},{"name":"action_table","path":["schema","action_table"],"schema":{"fields":[{"name":"action","data_type":"Text"},{"name":"user_id","data_type":"Integer"},{"name":"duration","data_type":"Float"}]},"size":10000}]},"query":"SELECT * FROM action_table","synthetic_data":[["user_table","synthetic_user_table"],["action_table","synthetic_action_table"]],"privacy_unit":[["user_table",[],"id"],["action_table",[["user_id","user_table","id"]],"id"]],"epsilon":1.0,"delta":0.00001}"#;
+        let request: RewriteAsPrivacyUnitPreserving = serde_json::from_str(request_str).unwrap();
+        println!("{:?}", request.response().unwrap());
+    }
+
+    #[test]
+    fn test_missing_table() {
+        let auth = Authenticator::get("secret_key.pem").unwrap();
+        let request_str = r#"{"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"}]},"size":100}]},"query":"SELECT id FROM missing_table","synthetic_data":[],"privacy_unit":[["user_table",[],"id"]],"epsilon":1.0,"delta":0.00001}"#;
+        let request: RewriteAsPrivacyUnitPreserving = serde_json::from_str(request_str).unwrap();
+        let err = request.response().unwrap_err();
+        println!("{err}");
+        assert!(matches!(err, Error::InvalidSQL(_)));
+        let request: RewriteWithDifferentialPrivacy = serde_json::from_str(request_str).unwrap();
+        assert!(matches!(request.response(&auth), Err(Error::InvalidSQL(_))));
+    }
+
+    #[test]
+    fn test_impossible_rewriting() {
+        let dataset: Dataset = serde_json::from_str(r#"{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"name","data_type":"Text"}]},"size":100}]}"#).unwrap();
+        let privacy_unit: PrivacyUnitSpec = serde_json::from_str(r#"[["user_table",[],"id"]]"#).unwrap();
+        let privacy_unit = resolve_privacy_unit(&privacy_unit, None, &dataset, false).unwrap();
+        let relations: Hierarchy<Arc<Relation>> = dataset.try_into().unwrap();
+        // Rows of the privacy unit cannot be returned without aggregation nor synthetic data
+        let relation = Relation::try_from(parse_query("SELECT name FROM user_table").unwrap().with(&relations)).unwrap();
+        let err = Error::from(relation.rewrite_with_differential_privacy(&relations, None, privacy_unit, dp_parameters(1., 1e-5, None).unwrap()).unwrap_err());
+        println!("{err}");
+        assert!(matches!(err, Error::ImpossibleRewriting(_, _)));
+    }
+
+    #[test]
+    fn test_validate_budget() {
+        assert!(validate_budget(1., 1e-5).is_ok());
+        assert!(validate_budget(0.1, 0.).is_ok());
+        for (epsilon, delta) in [(0., 1e-5), (-1., 1e-5), (f64::NAN, 1e-5), (f64::INFINITY, 1e-5), (1., -1e-5), (1., 1.), (1., 2.), (1., f64::NAN)] {
+            let err = validate_budget(epsilon, delta).unwrap_err();
+            println!("{err}");
+            assert!(matches!(err, Error::InvalidRequest(_)));
+        }
+        // Rewrites are rejected before reaching qrlew
+        let auth = Authenticator::get("secret_key.pem").unwrap();
+        let request_str = r#"{"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"age","data_type":"Integer","range":[0,100]}]},"size":100}]},"query":"SELECT sum(age) FROM user_table","synthetic_data":[],"privacy_unit":[["user_table",[],"id"]],"epsilon":-1.0,"delta":0.00001}"#;
+        let request: RewriteWithDifferentialPrivacy = serde_json::from_str(request_str).unwrap();
+        assert!(matches!(request.response(&auth), Err(Error::InvalidRequest(_))));
+        let request: RewriteAsPrivacyUnitPreserving = serde_json::from_str(request_str).unwrap();
+        assert!(matches!(request.response(), Err(Error::InvalidRequest(_))));
+    }
+
+    #[test]
+    fn test_pure_dp() {
+        let auth = Authenticator::get("secret_key.pem").unwrap();
+        let request_str = r#"{"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"age","data_type":"Integer","range":[0,100]}]},"size":100}]},"query":"SELECT sum(age) AS total FROM user_table","synthetic_data":[],"privacy_unit":[["user_table",[],"id"]],"epsilon":1.0}"#;
+        // Delta defaults to 0
+        let request: RewriteWithDifferentialPrivacy = serde_json::from_str(request_str).unwrap();
+        assert_eq!(request.options.delta, 0.);
+        // Noise cannot be added without delta
+        let err = request.clone().response(&auth).unwrap_err();
+        println!("{err}");
+        assert!(matches!(err, Error::InvalidRequest(_)));
+        assert!(err.to_string().contains("pure epsilon-DP"));
+        let request: CheckBudget = serde_json::from_str(request_str).unwrap();
+        let check: BudgetCheck = serde_json::from_str(request.response().unwrap().value()).unwrap();
+        assert!(!check.feasible);
+        assert!(check.reason.unwrap().contains("pure epsilon-DP"));
+        // Rewritings adding no noise are pure DP
+        let request: RewriteWithDifferentialPrivacy = serde_json::from_str(&request_str.replace(r#""privacy_unit":[["user_table",[],"id"]]"#, r#""allow_no_privacy_unit":true"#)).unwrap();
+        let response = request.response(&auth).unwrap();
+        println!("{}", response.value());
+        auth.verify(&response.signed_payload(), response.signature().unwrap()).expect("OK");
+        // Privacy unit preserving rewritings add no noise
+        let request: RewriteAsPrivacyUnitPreserving = serde_json::from_str(request_str).unwrap();
+        assert!(request.response().is_ok());
+    }
+
+    #[test]
+    fn test_consumed_budget() {
+        let auth = Authenticator::get("secret_key.pem").unwrap();
+        let request_str = r#"{"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"age","data_type":"Integer","range":[0,100]},{"name":"city","data_type":"Text"}]},"size":100}]},"query":"SELECT sum(age) AS total FROM user_table","synthetic_data":[],"privacy_unit":[["user_table",[],"id"]],"epsilon":1.0,"delta":0.00001}"#;
+        let consumed = |query: &str| {
+            let request: RewriteWithDifferentialPrivacy = serde_json::from_str(&request_str.replace("SELECT sum(age) AS total FROM user_table", query)).unwrap();
+            let response = request.response(&auth).unwrap();
+            println!("{query} -> {:?}", response.consumed_budget());
+            // The consumed budget is not signed
+            auth.verify(&response.signed_payload(), response.signature().unwrap()).expect("OK");
+            response.consumed_budget().unwrap()
+        };
+        let close = |(epsilon, delta): (f64, f64), (expected_epsilon, expected_delta): (f64, f64)| (epsilon - expected_epsilon).abs() < 1e-9 && (delta - expected_delta).abs() < 1e-15;
+        // Aggregations spend the whole budget
+        assert!(close(consumed("SELECT sum(age) AS total FROM user_table"), (1., 0.00001)));
+        assert!(close(consumed("SELECT city, sum(age) AS total, count(*) AS count FROM user_table GROUP BY city"), (1., 0.00001)));
+        // Tau-thresholding alone spends its share
+        assert!(close(consumed("SELECT city FROM user_table GROUP BY city"), (0.5, 0.000005)));
+        // DP aggregations combined through a join with a subquery spend the budget each, whether their groups are thresholded or not
+        assert!(close(consumed("WITH m AS (SELECT avg(age) AS avg_age FROM user_table) SELECT avg(age - avg_age) AS centered FROM user_table JOIN m ON age > avg_age"), (2., 0.00002)));
+        assert!(close(consumed("WITH m AS (SELECT avg(age) AS avg_age, count(*) AS n FROM user_table) SELECT city, sum(age - avg_age) AS centered FROM user_table JOIN m ON age > avg_age GROUP BY city"), (2., 0.00002)));
+        assert!(close(consumed("WITH m AS (SELECT city, avg(age) AS avg_age FROM user_table GROUP BY city) SELECT sum(age - avg_age) AS centered FROM user_table AS u JOIN m ON u.city = m.city"), (2., 0.00002)));
+        // Aggregations split by DISTINCT clause share the budget of their reduce
+        for query in ["SELECT count(DISTINCT city) AS c, sum(age) AS s FROM user_table", "SELECT city, count(DISTINCT id) AS c, sum(age) AS s FROM user_table GROUP BY city"] {
+            let (epsilon, delta) = consumed(query);
+            assert!(epsilon <= 1. + 1e-9 && delta <= 0.00001 + 1e-15);
+            assert!(close((epsilon, delta), (1., 0.00001)));
+        }
+        // An aggregation and the subquery aggregating the same column spend the budget each
+        assert!(close(consumed("WITH m AS (SELECT sum(age) AS s FROM user_table) SELECT sum(age) AS total FROM user_table JOIN m ON age <= s"), (2., 0.00002)));
+        // Post-processing an aggregation spends nothing more
+        assert!(close(consumed("SELECT total / 2 AS half FROM (SELECT sum(age) AS total FROM user_table) AS a"), (1., 0.00001)));
+        // Nothing is spent without privacy unit
+        let request: RewriteWithDifferentialPrivacy = serde_json::from_str(&request_str.replace(r#""privacy_unit":[["user_table",[],"id"]]"#, r#""allow_no_privacy_unit":true"#)).unwrap();
+        assert_eq!(request.response(&auth).unwrap().consumed_budget(), Some((0., 0.)));
+    }
+
+    #[test]
+    fn test_spend_budget() {
+        let auth = Authenticator::get("secret_key.pem").unwrap();
+        let request_str = r#"{"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"age","data_type":"Integer","range":[0,100]}]},"size":100}]},"query":"SELECT sum(age) AS total FROM user_table","synthetic_data":[],"privacy_unit":[["user_table",[],"id"]],"epsilon":1.0,"delta":0.00001}"#;
+        let request: RewriteWithDifferentialPrivacy = serde_json::from_str(request_str).unwrap();
+        let response = request.clone().response(&auth).unwrap();
+        let accountant = BudgetAccountant::new(1.5, 1e-4);
+        let budget_keys = request.dataset.budget_keys(&request.privacy_unit().unwrap());
+        assert_eq!(budget_keys, vec!["schema.user_table".to_string()]);
+        // Stateless without accountant
+        assert!(spend_budget(None, &budget_keys, &response).is_ok());
+        assert!(spend_budget(Some(&accountant), &budget_keys, &response).is_ok());
+        let err = spend_budget(Some(&accountant), &budget_keys, &response).unwrap_err();
+        println!("{err}");
+        assert!(matches!(err, Error::TooManyRequests(_)));
+        assert_eq!(err.status(), StatusCode::TOO_MANY_REQUESTS);
+        // Changing the metadata of the dataset does not reset its budget
+        let other: RewriteWithDifferentialPrivacy = serde_json::from_str(&request_str.replace(r#""size":100"#, r#""size":200"#).replace(r#""range":[0,100]"#, r#""range":[0,50]"#)).unwrap();
+        assert_ne!(other.dataset.hash().unwrap(), request.dataset.hash().unwrap());
+        let other_keys = other.dataset.budget_keys(&other.privacy_unit().unwrap());
+        assert_eq!(other_keys, budget_keys);
+        assert!(spend_budget(Some(&accountant), &other_keys, &response).is_err());
+        // Other protected tables have separate budgets
+        let other: RewriteWithDifferentialPrivacy = serde_json::from_str(&request_str.replace(r#""path":["schema","user_table"]"#, r#""path":["other_schema","user_table"]"#)).unwrap();
+        assert!(spend_budget(Some(&accountant), &other.dataset.budget_keys(&other.privacy_unit().unwrap()), &response).is_ok());
+        // Responses without consumed budget spend nothing
+        assert!(spend_budget(Some(&accountant), &budget_keys, &Response::new("SELECT 1".to_string())).is_ok());
+    }
+
+    #[test]
+    fn test_rewrite_with_differential_privacy_batch() {
+        let auth = Authenticator::get("secret_key.pem").unwrap();
+        let request_str = r#"{"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"age","data_type":"Integer","range":[0,100]}]},"size":100}]},"queries":["SELECT sum(age) AS total FROM user_table","SELECT count(*) AS count FROM user_table","SELECT age FROM user_table","SELEC age"],"synthetic_data":[],"privacy_unit":[["user_table",[],"id"]],"epsilon":1.0,"delta":0.00001}"#;
+        let request: RewriteWithDifferentialPrivacyBatch = serde_json::from_str(request_str).unwrap();
+        let items = request.response(&auth).unwrap();
+        println!("{}", serde_json::to_string_pretty(&items).unwrap());
+        assert_eq!(items.len(), 4);
+        // Each query is signed independently
+        for item in &items[..2] {
+            let BatchItem::Response(response) = item else { panic!("{item:?}") };
+            auth.verify(&response.signed_payload(), response.signature().unwrap()).expect("OK");
+        }
+        // Failing queries are reported in place
+        assert!(matches!(&items[2], BatchItem::Error { error, .. } if error == "ImpossibleRewriting"));
+        assert!(matches!(&items[3], BatchItem::Error { error, .. } if error == "InvalidSQL"));
+        // Items deserialize back
+        assert_eq!(serde_json::from_str::<Vec<BatchItem>>(&serde_json::to_string(&items).unwrap()).unwrap(), items);
+        // The same as single queries
+        let single: RewriteWithDifferentialPrivacy = serde_json::from_str(&request_str.replace(r#""queries":["SELECT sum(age) AS total FROM user_table","SELECT count(*) AS count FROM user_table","SELECT age FROM user_table","SELEC age"]"#, r#""query":"SELECT count(*) AS count FROM user_table""#)).unwrap();
+        let BatchItem::Response(response) = &items[1] else { unreachable!() };
+        assert_eq!(single.response(&auth).unwrap().relation_fingerprint(), response.relation_fingerprint());
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let auth = Authenticator::get("secret_key.pem").unwrap();
+        let request_str = r#"{"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"age","data_type":"Integer","range":[0,100]}]},"size":100}]},"query":"SELECT sum(age) AS total FROM user_table","synthetic_data":[],"privacy_unit":[["user_table",[],"id"]],"epsilon":1.0,"delta":0.00001}"#;
+        let request: Rewrite = serde_json::from_str(request_str).unwrap();
+        let rewrites = request.response(&auth).unwrap();
+        println!("{}", serde_json::to_string_pretty(&rewrites).unwrap());
+        // The same rewritings as the separate routes
+        let pup: RewriteAsPrivacyUnitPreserving = serde_json::from_str(request_str).unwrap();
+        assert_eq!(rewrites.pup_query, pup.response().unwrap());
+        assert_eq!(rewrites.pup_query.signature(), None);
+        let dp: RewriteWithDifferentialPrivacy = serde_json::from_str(request_str).unwrap();
+        assert_eq!(rewrites.dp_query.relation_fingerprint(), dp.response(&auth).unwrap().relation_fingerprint());
+        auth.verify(&rewrites.dp_query.signed_payload(), rewrites.dp_query.signature().unwrap()).expect("OK");
+        // Both rewritings fail together
+        let request: Rewrite = serde_json::from_str(&request_str.replace("sum(age) AS total", "age")).unwrap();
+        assert!(matches!(request.response(&auth), Err(Error::ImpossibleRewriting(_, _))));
+    }
+
+    #[test]
+    fn test_dialect() {
+        let auth = Authenticator::get("secret_key.pem").unwrap();
+        let request = |dialect: &str| format!(r#"{{"dataset":{{"tables":[{{"name":"user_table","path":["schema","user_table"],"schema":{{"fields":[{{"name":"id","data_type":"Integer"}},{{"name":"age","data_type":"Integer","range":[0,100]}}]}},"size":100}}]}},"query":"SELECT sum(age) AS total FROM user_table","synthetic_data":[],"privacy_unit":[["user_table",[],"id"]],"epsilon":1.0,"delta":0.00001{dialect}}}"#);
+        let rewrite = |dialect: &str| serde_json::from_str::<RewriteWithDifferentialPrivacy>(&request(dialect)).unwrap().response(&auth).unwrap().value().to_string();
+        let default = rewrite("");
+        let bigquery = rewrite(r#","dialect":"bigquery""#);
+        let mssql = rewrite(r#","dialect":"mssql""#);
+        let postgres = rewrite(r#","dialect":"postgres""#);
+        println!("{default}\n{bigquery}\n{mssql}\n{postgres}");
+        assert!(bigquery.contains('`') && !default.contains('`'));
+        assert_ne!(mssql, default);
+        assert!(postgres.contains("total"));
+        assert!(serde_json::from_str::<RewriteWithDifferentialPrivacy>(&request(r#","dialect":"oracle""#)).is_err());
+        // Output aliases are quoted as the identifiers of the dialect
+        let bigquery = rewrite(r#","dialect":"bigquery","output_aliases":{"total":"total_age"}"#);
+        println!("{bigquery}");
+        assert!(bigquery.contains("AS `total_age` FROM `"));
+        assert!(!bigquery.contains('"'));
+        let postgres = rewrite(r#","dialect":"postgres","output_aliases":{"total":"total_age"}"#);
+        assert!(postgres.contains(r#"AS "total_age" FROM ""#));
+    }
+
+    #[test]
     fn test_rewrite_with_dp_serialize() {
         let request = RewriteWithDifferentialPrivacy {
             dataset: Dataset { tables: vec![
@@ -423,10 +3393,10 @@ mod tests {
                     name: "user_table".to_string(),
                     path: vec!["schema".to_string(), "user_table".to_string()],
                     schema: Schema { fields: vec![
-                        Field { name: "id".to_string(), data_type: DataType::Integer, constraint: Some(Constraint::Unique), range: None, possible_values: None },
-                        Field { name: "name".to_string(), data_type: DataType::Text, constraint: None, range: None, possible_values: None },
-                        Field { name: "age".to_string(), data_type: DataType::Integer, constraint: None, range: None, possible_values: None },
-                        Field { name: "weight".to_string(), data_type: DataType::Float, constraint: None, range: None, possible_values: None },
+                        Field { name: "id".to_string(), data_type: DataType::Integer, constraint: Some(Constraint::Unique), range: None, possible_values: None, description: None, optional: None, format: None },
+                        Field { name: "name".to_string(), data_type: DataType::Text, constraint: None, range: None, possible_values: None, description: None, optional: None, format: None },
+                        Field { name: "age".to_string(), data_type: DataType::Integer, constraint: None, range: None, possible_values: None, description: None, optional: None, format: None },
+                        Field { name: "weight".to_string(), data_type: DataType::Float, constraint: None, range: None, possible_values: None, description: None, optional: None, format: None },
                     ]},
                     size: 10000,
                 },
@@ -434,24 +3404,40 @@ mod tests {
                     name: "action_table".to_string(),
                     path: vec!["schema".to_string(), "action_table".to_string()],
                     schema: Schema { fields: vec![
-                        Field { name: "action".to_string(), data_type: DataType::Text, constraint: None, range: None, possible_values: None },
-                        Field { name: "user_id".to_string(), data_type: DataType::Integer, constraint: None, range: None, possible_values: None },
-                        Field { name: "duration".to_string(), data_type: DataType::Float, constraint: None, range: None, possible_values: None },
+                        Field { name: "action".to_string(), data_type: DataType::Text, constraint: None, range: None, possible_values: None, description: None, optional: None, format: None },
+                        Field { name: "user_id".to_string(), data_type: DataType::Integer, constraint: None, range: None, possible_values: None, description: None, optional: None, format: None },
+                        Field { name: "duration".to_string(), data_type: DataType::Float, constraint: None, range: None, possible_values: None, description: None, optional: None, format: None },
                     ]},
                     size: 10000,
                 },
             ]},
             query: "SELECT sum(duration) FROM action_table WHERE duration > 0 AND duration < 24".to_string(),
-            synthetic_data: vec![
-                ("user_table".to_string(), "synthetic_user_table".to_string()),
-                ("action_table".to_string(), "synthetic_action_table".to_string()),
-            ],
-            privacy_unit: vec![
-                ("user_table".to_string(), vec![], "id".to_string()),
-                ("action_table".to_string(), vec![("user_id".to_string(), "user_table".to_string(), "id".to_string())], "id".to_string()),
-            ],
-            epsilon: 1.,
-            delta: 1e-5,
+            options: RewriteOptions {
+                synthetic_data: vec![
+                    ("user_table".to_string(), "synthetic_user_table".to_string()),
+                    ("action_table".to_string(), "synthetic_action_table".to_string()),
+                ],
+                privacy_unit: vec![
+                    ("user_table".to_string(), vec![], "id".to_string()),
+                    ("action_table".to_string(), vec![("user_id".to_string(), "user_table".to_string(), "id".to_string())], "id".to_string()),
+                ],
+                privacy_unit_token: None,
+                privacy_unit_paths: vec![],
+                infer_privacy_unit_links: false,
+                epsilon: 1.,
+                delta: 1e-5,
+            },
+            allow_no_privacy_unit: false,
+            max_privacy_unit_contribution: None,
+            with_unique_columns: false,
+            output_aliases: HashMap::new(),
+            dialect: None,
+            formats: vec![],
+            wrapper_template: None,
+            principal: None,
+            with_dataset_hash: false,
+            sign_digest: false,
+            with_explanation: false,
         };
 
         println!("{}", serde_json::to_string_pretty(&request).unwrap());
@@ -463,7 +3449,7 @@ mod tests {
         let request_str = r#"
         {"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"name","data_type":"Text"},{"name":"age","data_type":"Integer"},{"name":"weight","data_type":"Float"}]},"size":10000},{"name":"action_table","path":["schema","action_table"],"schema":{"fields":[{"name":"action","data_type":"Text"},{"name":"user_id","data_type":"Integer"},{"name":"duration","data_type":"Float"}]},"size":10000}]},"query":"SELECT sum(duration) FROM action_table WHERE duration > 0 AND duration < 24","synthetic_data":[["user_table","synthetic_user_table"],["action_table","synthetic_action_table"]],"privacy_unit":[["user_table",[],"id"],["action_table",[["user_id","user_table","id"]],"id"]],"epsilon":1.0,"delta":0.00001}
 "#;
-        let request: RewriteWithDifferentialPrivacy = serde_json::from_str(&request_str).unwrap();
+        let request: RewriteWithDifferentialPrivacy = serde_json::from_str(request_str).unwrap();
         println!("{:?}", request);
     }
 
@@ -473,7 +3459,303 @@ mod tests {
         let request_str = r#"
         {"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"name","data_type":"Text"},{"name":"age","data_type":"Integer"},{"name":"weight","data_type":"Float"}]},"size":10000},{"name":"action_table","path":["schema","action_table"],"schema":{"fields":[{"name":"action","data_type":"Text"},{"name":"user_id","data_type":"Integer"},{"name":"duration","data_type":"Float"}]},"size":10000}]},"query":"SELECT sum(duration) FROM action_table WHERE duration > 0 AND duration < 24","synthetic_data":[["user_table","synthetic_user_table"],["action_table","synthetic_action_table"]],"privacy_unit":[["user_table",[],"id"],["action_table",[["user_id","user_table","id"]],"id"]],"epsilon":1.0,"delta":0.00001}
 "#;
+        let request: RewriteWithDifferentialPrivacy = serde_json::from_str(request_str).unwrap();
+        println!("{:?}", request.response(&auth).unwrap());
+    }
+
+    #[test]
+    fn test_rewrite_with_dp_unsupported_aggregations() {
+        let auth = Authenticator::get("secret_key.pem").unwrap();
+        let request_str = r#"
+        {"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"name","data_type":"Text"},{"name":"age","data_type":"Integer"},{"name":"weight","data_type":"Float"}]},"size":10000},{"name":"action_table","path":["schema","action_table"],"schema":{"fields":[{"name":"action","data_type":"Text"},{"name":"user_id","data_type":"Integer"},{"name":"duration","data_type":"Float"}]},"size":10000}]},"query":"SELECT min(duration), max(duration), sum(duration) FROM action_table WHERE duration > 0 AND duration < 24","synthetic_data":[],"privacy_unit":[["user_table",[],"id"],["action_table",[["user_id","user_table","id"]],"id"]],"epsilon":1.0,"delta":0.00001}
+"#;
+        let request: RewriteWithDifferentialPrivacy = serde_json::from_str(request_str).unwrap();
+        let err = request.response(&auth).unwrap_err();
+        println!("{err}");
+        assert_eq!(err.aggregations(), &["MIN".to_string(), "MAX".to_string()]);
+    }
+
+    #[test]
+    fn test_rewrite_with_dp_dataset_hash() {
+        let auth = Authenticator::get("secret_key.pem").unwrap();
+        let request_str = r#"
+        {"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"name","data_type":"Text"},{"name":"age","data_type":"Integer"},{"name":"weight","data_type":"Float"}]},"size":10000},{"name":"action_table","path":["schema","action_table"],"schema":{"fields":[{"name":"action","data_type":"Text"},{"name":"user_id","data_type":"Integer"},{"name":"duration","data_type":"Float"}]},"size":10000}]},"query":"SELECT sum(duration) FROM action_table WHERE duration > 0 AND duration < 24","synthetic_data":[],"privacy_unit":[["user_table",[],"id"],["action_table",[["user_id","user_table","id"]],"id"]],"epsilon":1.0,"delta":0.00001,"with_dataset_hash":true}
+"#;
+        let request: RewriteWithDifferentialPrivacy = serde_json::from_str(request_str).unwrap();
+        let dataset_hash = request.dataset.hash().unwrap();
+        let response = request.response(&auth).unwrap();
+        assert_eq!(response.dataset_hash(), Some(dataset_hash.as_str()));
+        auth.verify(&response.signed_payload(), response.signature().unwrap()).expect("OK");
+    }
+
+    #[test]
+    fn test_rewrite_with_dp_output_aliases() {
+        let auth = Authenticator::get("secret_key.pem").unwrap();
+        let request_str = r#"
+        {"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"name","data_type":"Text"},{"name":"age","data_type":"Integer"},{"name":"weight","data_type":"Float"}]},"size":10000},{"name":"action_table","path":["schema","action_table"],"schema":{"fields":[{"name":"action","data_type":"Text"},{"name":"user_id","data_type":"Integer"},{"name":"duration","data_type":"Float"}]},"size":10000}]},"query":"SELECT action, sum(duration) AS total FROM action_table WHERE duration > 0 AND duration < 24 GROUP BY action","synthetic_data":[],"privacy_unit":[["user_table",[],"id"],["action_table",[["user_id","user_table","id"]],"id"]],"epsilon":1.0,"delta":0.00001,"output_aliases":{"total":"total_duration"}}
+"#;
+        let request: RewriteWithDifferentialPrivacy = serde_json::from_str(request_str).unwrap();
+        let response = request.response(&auth).unwrap();
+        println!("{}", response.value());
+        assert!(response.value().contains(r#"SELECT "action" AS "action", "total" AS "total_duration" FROM"#));
+        auth.verify(&response.signed_payload(), response.signature().unwrap()).expect("OK");
+        let request: RewriteWithDifferentialPrivacy = serde_json::from_str(&request_str.replace(r#"{"total":"#, r#"{"unknown":"#)).unwrap();
+        assert!(matches!(request.response(&auth), Err(Error::InvalidRequest(_))));
+    }
+
+    #[test]
+    fn test_rewrite_with_dp_formats() {
+        let auth = Authenticator::get("secret_key.pem").unwrap();
+        let request_str = r#"
+        {"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"name","data_type":"Text"},{"name":"age","data_type":"Integer"},{"name":"weight","data_type":"Float"}]},"size":10000},{"name":"action_table","path":["schema","action_table"],"schema":{"fields":[{"name":"action","data_type":"Text"},{"name":"user_id","data_type":"Integer"},{"name":"duration","data_type":"Float"}]},"size":10000}]},"query":"SELECT sum(duration) FROM action_table WHERE duration > 0 AND duration < 24","synthetic_data":[],"privacy_unit":[["user_table",[],"id"],["action_table",[["user_id","user_table","id"]],"id"]],"epsilon":1.0,"delta":0.00001,"formats":["sql","dot","ast"]}
+"#;
+        let request: RewriteWithDifferentialPrivacy = serde_json::from_str(request_str).unwrap();
+        let response = request.response(&auth).unwrap();
+        auth.verify(&response.signed_payload(), response.signature().unwrap()).expect("OK");
+        let formats: BTreeMap<String, Value> = serde_json::from_str(response.value()).unwrap();
+        assert_eq!(formats.keys().collect::<Vec<_>>(), vec!["ast", "dot", "sql"]);
+        assert!(formats["sql"].as_str().unwrap().starts_with("WITH"));
+        assert!(formats["dot"].as_str().unwrap().starts_with("digraph"));
+        assert!(formats["ast"]["body"].is_object());
+    }
+
+    #[test]
+    fn test_rewrite_with_dp_with_dot() {
+        let auth = Authenticator::get("secret_key.pem").unwrap();
+        let request_str = r#"
+        {"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"name","data_type":"Text"},{"name":"age","data_type":"Integer"},{"name":"weight","data_type":"Float"}]},"size":10000},{"name":"action_table","path":["schema","action_table"],"schema":{"fields":[{"name":"action","data_type":"Text"},{"name":"user_id","data_type":"Integer"},{"name":"duration","data_type":"Float"}]},"size":10000}]},"query":"SELECT sum(duration) FROM action_table WHERE duration > 0 AND duration < 24","synthetic_data":[],"privacy_unit":[["user_table",[],"id"],["action_table",[["user_id","user_table","id"]],"id"]],"epsilon":1.0,"delta":0.00001,"dark_mode":true}
+"#;
+        let request: RewriteWithDifferentialPrivacyWithDot = serde_json::from_str(request_str).unwrap();
+        let rewrite = request.rewrite.clone().response(&auth).unwrap();
+        let response = request.response(&auth).unwrap();
+        auth.verify(&response.signed_payload(), response.signature().unwrap()).expect("OK");
+        let query_with_dot: QueryWithDot = serde_json::from_str(response.value()).unwrap();
+        println!("{}", query_with_dot.dot);
+        // Noise maps get fresh names, but the metadata matches the plain rewrite
+        assert!(query_with_dot.query.starts_with("WITH"));
+        assert_eq!(response.consumed_budget(), rewrite.consumed_budget());
+        assert_eq!(response.noise_scales(), rewrite.noise_scales());
+        assert!(query_with_dot.dot.starts_with("digraph"));
+    }
+
+    #[test]
+    fn test_rewrite_with_dp_explanation() {
+        let auth = Authenticator::get("secret_key.pem").unwrap();
+        let request_str = r#"
+        {"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"name","data_type":"Text"},{"name":"age","data_type":"Integer"},{"name":"weight","data_type":"Float"}]},"size":10000},{"name":"action_table","path":["schema","action_table"],"schema":{"fields":[{"name":"action","data_type":"Text"},{"name":"user_id","data_type":"Integer"},{"name":"duration","data_type":"Float"}]},"size":10000}]},"query":"SELECT action, sum(duration) FROM action_table WHERE duration > 0 AND duration < 24 GROUP BY action","synthetic_data":[],"privacy_unit":[["user_table",[],"id"],["action_table",[["user_id","user_table","id"]],"id"]],"epsilon":1.0,"delta":0.00001,"with_explanation":true}
+"#;
+        let request: RewriteWithDifferentialPrivacy = serde_json::from_str(request_str).unwrap();
+        let response = request.response(&auth).unwrap();
+        let explanation = response.explanation().unwrap();
+        println!("{explanation}");
+        assert!(explanation.starts_with("Reads action_table."));
+        assert!(explanation.contains("sum of duration per action over the filtered rows"));
+        assert!(explanation.contains("Gaussian noise"));
+        assert!(explanation.ends_with("The privacy budget is epsilon 1, delta 0.00001."));
+        // Groups are thresholded
+        println!("{:?}", response.suppression_threshold());
+        assert!(response.suppression_threshold().unwrap() > 1.);
+    }
+
+    #[test]
+    fn test_rewrite_with_dp_noise_scales() {
+        let auth = Authenticator::get("secret_key.pem").unwrap();
+        let request_str = r#"
+        {"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"name","data_type":"Text"},{"name":"age","data_type":"Integer"},{"name":"weight","data_type":"Float"}]},"size":10000},{"name":"action_table","path":["schema","action_table"],"schema":{"fields":[{"name":"action","data_type":"Text"},{"name":"user_id","data_type":"Integer"},{"name":"duration","data_type":"Float"}]},"size":10000}]},"query":"SELECT action, sum(duration) AS total, count(*) AS actions, avg(duration) AS mean FROM action_table WHERE duration > 0 AND duration < 24 GROUP BY action","synthetic_data":[],"privacy_unit":[["user_table",[],"id"],["action_table",[["user_id","user_table","id"]],"id"]],"epsilon":1.0,"delta":0.00001,"output_aliases":{"total":"total_duration"}}
+"#;
+        let request: RewriteWithDifferentialPrivacy = serde_json::from_str(request_str).unwrap();
+        let response = request.response(&auth).unwrap();
+        println!("{}", response.value());
+        let noise_scales = response.noise_scales().unwrap();
+        println!("{noise_scales:?}");
+        assert!(noise_scales["total_duration"] > 0.);
+        assert!(noise_scales["actions"] > 0.);
+        // Neither the group keys, nor combinations of noisy values have a noise scale
+        assert!(!noise_scales.contains_key("action"));
+        assert!(!noise_scales.contains_key("mean"));
+    }
+
+    #[test]
+    fn test_has_row_level_output() {
+        let dataset: Dataset = serde_json::from_str(r#"{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"name","data_type":"Text"}]},"size":10000}]}"#).unwrap();
+        let relations = dataset.try_into().unwrap();
+        let relation = |query: &str| Relation::try_from(parse_query(query).unwrap().with(&relations)).unwrap();
+        assert!(has_row_level_output(&relation("SELECT name FROM user_table"), &["user_table"]));
+        assert!(!has_row_level_output(&relation("SELECT count(id) FROM user_table"), &["user_table"]));
+        assert!(!has_row_level_output(&relation("SELECT name, count(id) FROM user_table GROUP BY name"), &["user_table"]));
+        assert!(has_row_level_output(&relation("SELECT * FROM user_table AS u JOIN (SELECT name, count(id) AS n FROM user_table GROUP BY name) AS c ON u.name = c.name"), &["user_table"]));
+        // Tables outside of the privacy unit are public
+        assert!(!has_row_level_output(&relation("SELECT name FROM user_table"), &[]));
+    }
+
+    #[test]
+    fn test_check_strict_dp() {
+        let dataset: Dataset = serde_json::from_str(r#"{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"name","data_type":"Text"},{"name":"country","data_type":"Text"}]},"size":10000},{"name":"country_table","path":["schema","country_table"],"schema":{"fields":[{"name":"country","data_type":"Text"},{"name":"population","data_type":"Integer"}]},"size":200}]}"#).unwrap();
+        let relations = dataset.try_into().unwrap();
+        let relation = |query: &str| Relation::try_from(parse_query(query).unwrap().with(&relations)).unwrap();
+        let privacy_unit = PrivacyUnit::from(vec![("user_table", vec![], "id")]);
+        // A public table passes in strict mode
+        assert!(check_strict_dp(true, &relation("SELECT country, population FROM country_table"), &privacy_unit).is_ok());
+        assert!(check_strict_dp(true, &relation("SELECT c.country, c.population, n FROM country_table AS c JOIN (SELECT country, count(id) AS n FROM user_table GROUP BY country) AS u ON c.country = u.country"), &privacy_unit).is_ok());
+        // Rows of a protected table do not
+        let err = check_strict_dp(true, &relation("SELECT name FROM user_table"), &privacy_unit).unwrap_err();
+        println!("{err}");
+        assert!(matches!(err, Error::ImpossibleRewriting(..)));
+        assert!(check_strict_dp(false, &relation("SELECT name FROM user_table"), &privacy_unit).is_ok());
+    }
+
+    #[test]
+    fn test_rewrite_cross_join() {
+        let request_str = r#"{"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"name","data_type":"Text"}]},"size":10000},{"name":"action_table","path":["schema","action_table"],"schema":{"fields":[{"name":"action","data_type":"Text"},{"name":"user_id","data_type":"Integer"}]},"size":10000}]},"query":"SELECT * FROM action_table CROSS JOIN user_table","synthetic_data":[],"privacy_unit":[["user_table",[],"id"],["action_table",[["user_id","user_table","id"]],"id"]],"epsilon":1.0,"delta":0.00001}"#;
+        let request: RewriteAsPrivacyUnitPreserving = serde_json::from_str(request_str).unwrap();
+        let err = request.response().unwrap_err();
+        println!("{err}");
+        assert!(matches!(err, Error::InvalidRequest(_)));
+        let request: RewriteAsPrivacyUnitPreserving = serde_json::from_str(&request_str.replace("CROSS JOIN user_table", "JOIN user_table ON action_table.user_id = user_table.id")).unwrap();
+        assert!(request.response().is_ok());
+        // Cross joins filtered by a WHERE condition linking both sides are not cartesian products
+        for query in [
+            "SELECT * FROM action_table, user_table WHERE action_table.user_id = user_table.id",
+            "SELECT action, name FROM action_table, user_table WHERE action <> 'login' AND user_id = id",
+            "SELECT * FROM action_table CROSS JOIN user_table WHERE action_table.user_id = user_table.id",
+        ] {
+            let request: RewriteAsPrivacyUnitPreserving = serde_json::from_str(&request_str.replace("SELECT * FROM action_table CROSS JOIN user_table", query)).unwrap();
+            let response = request.response();
+            println!("{query} -> {response:?}");
+            assert!(response.is_ok());
+        }
+        // Conditions on one side do not link them
+        for query in ["SELECT * FROM action_table, user_table", "SELECT * FROM action_table, user_table WHERE user_id > 3 AND id < 10"] {
+            let request: RewriteAsPrivacyUnitPreserving = serde_json::from_str(&request_str.replace("SELECT * FROM action_table CROSS JOIN user_table", query)).unwrap();
+            assert!(matches!(request.response(), Err(Error::InvalidRequest(_))));
+        }
+    }
+
+    #[test]
+    fn test_synthetic_relation() {
+        let dataset: Dataset = serde_json::from_str(r#"{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"age","data_type":"Integer"}]},"size":10000},{"name":"action_table","path":["schema","action_table"],"schema":{"fields":[{"name":"action","data_type":"Text"},{"name":"user_id","data_type":"Integer"}]},"size":10000}]}"#).unwrap();
+        let relations = dataset.try_into().unwrap();
+        let synthetic_data = SyntheticData::new([(vec!["schema", "user_table"], Identifier::from(vec!["schema".to_string(), "synthetic_user_table".to_string()]))].into());
+        let query = parse_query("SELECT avg(age) FROM user_table").unwrap();
+        let synthetic_query = Query::from(&synthetic_relation(&query, &relations, Some(&synthetic_data)).unwrap()).to_string();
+        println!("{synthetic_query}");
+        assert!(synthetic_query.contains("synthetic_user_table"));
+        // Tables without synthetic data cannot be read
+        let query = parse_query("SELECT count(*) FROM action_table").unwrap();
+        assert!(synthetic_relation(&query, &relations, Some(&synthetic_data)).is_err());
+        assert!(synthetic_relation(&query, &relations, None).is_err());
+    }
+
+    #[test]
+    fn test_register_privacy_unit() {
+        let auth = Authenticator::get("secret_key.pem").unwrap();
+        let register_str = r#"{"privacy_unit":[["user_table",[],"id"],["action_table",[["user_id","user_table","id"]],"id"]]}"#;
+        let register: RegisterPrivacyUnit = serde_json::from_str(register_str).unwrap();
+        let token = register.response().unwrap().value().to_string();
+        let request_str = format!(r#"
+        {{"dataset":{{"tables":[{{"name":"user_table","path":["schema","user_table"],"schema":{{"fields":[{{"name":"id","data_type":"Integer"}},{{"name":"name","data_type":"Text"}},{{"name":"age","data_type":"Integer"}},{{"name":"weight","data_type":"Float"}}]}},"size":10000}},{{"name":"action_table","path":["schema","action_table"],"schema":{{"fields":[{{"name":"action","data_type":"Text"}},{{"name":"user_id","data_type":"Integer"}},{{"name":"duration","data_type":"Float"}}]}},"size":10000}}]}},"query":"SELECT sum(duration) FROM action_table WHERE duration > 0 AND duration < 24","synthetic_data":[],"privacy_unit_token":"{token}","epsilon":1.0,"delta":0.00001}}
+"#);
         let request: RewriteWithDifferentialPrivacy = serde_json::from_str(&request_str).unwrap();
         println!("{:?}", request.response(&auth).unwrap());
+        let request: RewriteWithDifferentialPrivacy = serde_json::from_str(&request_str.replace(&token, "unknown")).unwrap();
+        assert!(request.response(&auth).is_err());
+        // Invalid privacy units are rejected at registration
+        let register: RegisterPrivacyUnit = serde_json::from_str(r#"{"privacy_unit":[["user_table",[],"id"],["user_table",[],"name"]]}"#).unwrap();
+        assert!(register.response().is_err());
+    }
+
+    #[test]
+    fn test_privacy_unit_paths() {
+        let auth = Authenticator::get("secret_key.pem").unwrap();
+        let request_str = r#"
+        {"dataset":{"tables":[{"name":"sales_users","path":["sales","users"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"name","data_type":"Text"}]},"size":10000},{"name":"hr_users","path":["hr","users"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"salary","data_type":"Float"}]},"size":10000},{"name":"sales_orders","path":["sales","orders"],"schema":{"fields":[{"name":"user_id","data_type":"Integer"},{"name":"amount","data_type":"Float"}]},"size":10000}]},"query":"SELECT sum(amount) FROM sales.orders WHERE amount > 0 AND amount < 100","synthetic_data":[],"privacy_unit_paths":[[["sales","users"],[],"id"],[["sales","orders"],[["user_id",["sales","users"],"id"]],"id"]],"epsilon":1.0,"delta":0.00001}
+"#;
+        let request: RewriteWithDifferentialPrivacy = serde_json::from_str(request_str).unwrap();
+        assert_eq!(privacy_unit_from_paths(&request.options.privacy_unit, &request.options.privacy_unit_paths, &request.dataset).unwrap(), vec![
+            ("sales_users".to_string(), vec![], "id".to_string()),
+            ("sales_orders".to_string(), vec![("user_id".to_string(), "sales_users".to_string(), "id".to_string())], "id".to_string()),
+        ]);
+        println!("{}", request.response(&auth).unwrap().value());
+        let request: RewriteWithDifferentialPrivacy = serde_json::from_str(&request_str.replace(r#"["user_id",["sales","users"],"id"]"#, r#"["user_id",["crm","users"],"id"]"#)).unwrap();
+        assert!(matches!(request.response(&auth), Err(Error::InvalidRequest(_))));
+    }
+
+    #[test]
+    fn test_privacy_unit_link_types() {
+        let request_str = r#"{"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"name","data_type":"Text"}]},"size":10000},{"name":"action_table","path":["schema","action_table"],"schema":{"fields":[{"name":"action","data_type":"Text"},{"name":"user_id","data_type":"Integer"}]},"size":10000}]},"query":"SELECT * FROM action_table","synthetic_data":[],"privacy_unit":[["user_table",[],"id"],["action_table",[["user_id","user_table","id"]],"id"]],"epsilon":1.0,"delta":0.00001}"#;
+        let request: RewriteAsPrivacyUnitPreserving = serde_json::from_str(request_str).unwrap();
+        assert!(request.response().is_ok());
+        // An Integer to Text link is rejected
+        let request: RewriteAsPrivacyUnitPreserving = serde_json::from_str(&request_str.replace(r#"["user_id","user_table","id"]"#, r#"["user_id","user_table","name"]"#)).unwrap();
+        let err = request.response().unwrap_err();
+        println!("{err}");
+        assert!(matches!(err, Error::InvalidRequest(_)));
+        assert!(err.to_string().contains("`action_table.user_id` (Integer) to `user_table.name` (Text)"));
+    }
+
+    #[test]
+    fn test_privacy_unit_references() {
+        let auth = Authenticator::get("secret_key.pem").unwrap();
+        let request_str = r#"{"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"name","data_type":"Text"}]},"size":10000},{"name":"action_table","path":["schema","action_table"],"schema":{"fields":[{"name":"action","data_type":"Text"},{"name":"user_id","data_type":"Integer"}]},"size":10000}]},"query":"SELECT count(*) FROM action_table","synthetic_data":[],"privacy_unit":[["user_table",[],"id"],["action_table",[["user_id","user_table","id"]],"id"]],"epsilon":1.0,"delta":0.00001}"#;
+        for (from, to, message) in [
+            (r#"["user_table",[],"id"]"#, r#"["users",[],"id"]"#, "the privacy unit references the unknown table `users`"),
+            (r#"["user_id","user_table","id"]"#, r#"["user_id","users","id"]"#, "the privacy unit references the unknown table `users`"),
+            (r#"["user_id","user_table","id"]"#, r#"["userid","user_table","id"]"#, "the privacy unit of table `action_table` references the unknown column `action_table.userid`"),
+            (r#"["user_id","user_table","id"]"#, r#"["user_id","user_table","uid"]"#, "the privacy unit of table `action_table` references the unknown column `user_table.uid`"),
+            (r#"["user_table",[],"id"]"#, r#"["user_table",[],"user_id"]"#, "the privacy unit of table `user_table` references the unknown column `user_table.user_id`"),
+        ] {
+            let request_str = request_str.replace(from, to);
+            let request: RewriteAsPrivacyUnitPreserving = serde_json::from_str(&request_str).unwrap();
+            let err = request.response().unwrap_err();
+            println!("{err}");
+            assert!(matches!(err, Error::InvalidRequest(_)));
+            assert_eq!(err.message(), format!("Invalid request: {message}"));
+            let request: RewriteWithDifferentialPrivacy = serde_json::from_str(&request_str).unwrap();
+            assert_eq!(request.response(&auth).unwrap_err().message(), format!("Invalid request: {message}"));
+        }
+        let request: RewriteAsPrivacyUnitPreserving = serde_json::from_str(request_str).unwrap();
+        assert!(request.response().is_ok());
+    }
+
+    #[test]
+    fn test_check_synthetic_data() {
+        let auth = Authenticator::get("secret_key.pem").unwrap();
+        let request_str = r#"{"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"age","data_type":"Integer","range":[0,100]}]},"size":10000}]},"query":"SELECT sum(age) FROM user_table","synthetic_data":[["user_table","synthetic_user_table"]],"privacy_unit":[["user_table",[],"id"]],"epsilon":1.0,"delta":0.00001}"#;
+        let request: RewriteAsPrivacyUnitPreserving = serde_json::from_str(request_str).unwrap();
+        assert!(request.response().is_ok());
+        let request: RewriteWithDifferentialPrivacy = serde_json::from_str(request_str).unwrap();
+        assert!(request.response(&auth).is_ok());
+        let dataset: Dataset = serde_json::from_str(r#"{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"}]},"size":100}]}"#).unwrap();
+        assert!(check_synthetic_data(&[("schema.user_table".to_string(), "synthetic_user_table".to_string())], &dataset).is_ok());
+        // A misspelled real table is rejected
+        let request_str = request_str.replace(r#"["user_table","synthetic_user_table"]"#, r#"["users_table","synthetic_user_table"]"#);
+        let request: RewriteAsPrivacyUnitPreserving = serde_json::from_str(&request_str).unwrap();
+        let err = request.response().unwrap_err();
+        println!("{err}");
+        assert!(matches!(err, Error::InvalidRequest(_)));
+        assert_eq!(err.message(), "Invalid request: the synthetic_data mapping references the unknown table `users_table`");
+        let request: RewriteWithDifferentialPrivacy = serde_json::from_str(&request_str).unwrap();
+        assert!(matches!(request.response(&auth), Err(Error::InvalidRequest(_))));
+    }
+
+    #[test]
+    fn test_infer_privacy_unit_links() {
+        let auth = Authenticator::get("secret_key.pem").unwrap();
+        let request_str = r#"
+        {"dataset":{"tables":[{"name":"user_table","path":["schema","user_table"],"schema":{"fields":[{"name":"id","data_type":"Integer","constraint":"PrimaryKey"},{"name":"name","data_type":"Text"}]},"size":10000},{"name":"order_table","path":["schema","order_table"],"schema":{"fields":[{"name":"id","data_type":"Integer"},{"name":"user_id","data_type":"Integer","constraint":{"ForeignKey":{"table":"user_table","column":"id"}}}]},"size":10000},{"name":"item_table","path":["schema","item_table"],"schema":{"fields":[{"name":"order_id","data_type":"Integer","constraint":{"ForeignKey":{"table":"order_table","column":"id"}}},{"name":"price","data_type":"Float"}]},"size":10000}]},"query":"SELECT sum(price) FROM item_table WHERE price > 0 AND price < 100","synthetic_data":[],"privacy_unit":[["user_table",[],"id"]],"infer_privacy_unit_links":true,"epsilon":1.0,"delta":0.00001}
+"#;
+        let request: RewriteWithDifferentialPrivacy = serde_json::from_str(request_str).unwrap();
+        let privacy_unit = infer_privacy_unit_links(&request.options.privacy_unit, &request.dataset);
+        assert_eq!(privacy_unit, vec![
+            ("user_table".to_string(), vec![], "id".to_string()),
+            ("order_table".to_string(), vec![("user_id".to_string(), "user_table".to_string(), "id".to_string())], "id".to_string()),
+            ("item_table".to_string(), vec![("order_id".to_string(), "order_table".to_string(), "id".to_string()), ("user_id".to_string(), "user_table".to_string(), "id".to_string())], "id".to_string()),
+        ]);
+        println!("{}", request.response(&auth).unwrap().value());
+        // Explicit links override the inferred ones
+        let explicit = vec![("user_table".to_string(), vec![], "id".to_string()), ("order_table".to_string(), vec![], "id".to_string())];
+        let privacy_unit = infer_privacy_unit_links(&explicit, &serde_json::from_str::<RewriteWithDifferentialPrivacy>(request_str).unwrap().dataset);
+        assert_eq!(privacy_unit[1], ("order_table".to_string(), vec![], "id".to_string()));
+        assert_eq!(privacy_unit[2].1, vec![("order_id".to_string(), "order_table".to_string(), "id".to_string())]);
     }
 }
\ No newline at end of file
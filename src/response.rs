@@ -1,11 +1,59 @@
+use std::collections::BTreeMap;
 use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use utoipa::ToSchema;
+use rsa::sha2::{Digest, Sha256};
 use crate::{auth, Error};
 
-/// Simplified DataType
-#[derive(Clone, Debug, PartialEq, PartialOrd, Deserialize, Serialize)]
+/// Lowercase hex SHA-256 of some bytes
+pub fn sha256_hex(bytes: impl AsRef<[u8]>) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// A response value, optionally signed
+#[derive(Clone, Debug, PartialEq, PartialOrd, Deserialize, Serialize, JsonSchema, ToSchema)]
 pub struct Response {
     value: String,
     signature: Option<String>,
+    /// The id of the key which signed the response, see `/public_key` (not signed)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_id: Option<String>,
+    /// The output columns known to be unique, when requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    unique_columns: Option<Vec<String>>,
+    /// The hash of the dataset, covered by the signature when present
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dataset_hash: Option<String>,
+    /// The SHA-256 of the value, signed in place of the value when present
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    digest: Option<String>,
+    /// A plain language summary of the query, when requested (not signed)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    explanation: Option<String>,
+    /// The count of privacy units under which groups are filtered out of the DP query, when it thresholds groups (not signed)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    suppression_threshold: Option<f64>,
+    /// The epsilon spent by a DP query under basic composition, which can differ from the requested one (not signed)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    consumed_epsilon: Option<f64>,
+    /// The delta spent by a DP query under basic composition (not signed)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    consumed_delta: Option<f64>,
+    /// The standard deviation of the noise added to each noisy output column of a DP query (not signed)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    noise_scales: Option<BTreeMap<String, f64>>,
+    /// A hash of the structure of the relation of the query, the same for queries differing only in formatting (not signed)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    relation_fingerprint: Option<String>,
+    /// Losses of precision in the handling of the request (not signed)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    warnings: Option<Vec<String>>,
+    /// The id of the request, for client-side correlation (not signed)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    /// The route of the request (not signed)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    route: Option<String>,
 }
 
 impl Response {
@@ -13,13 +61,77 @@ impl Response {
         Response {
             value,
             signature: None,
+            key_id: None,
+            unique_columns: None,
+            dataset_hash: None,
+            digest: None,
+            explanation: None,
+            suppression_threshold: None,
+            consumed_epsilon: None,
+            consumed_delta: None,
+            noise_scales: None,
+            relation_fingerprint: None,
+            warnings: None,
+            request_id: None,
+            route: None,
         }
     }
 
     pub fn signed(value: String, auth: &auth::Authenticator) -> Self {
+        Response::new(value).sign(auth)
+    }
+
+    /// Sign the signed payload of the response
+    pub fn sign(self, auth: &auth::Authenticator) -> Self {
+        crate::prometheus::record_signature();
+        Response {
+            signature: Some(auth.sign(&self.signed_payload())),
+            key_id: auth.key_id().ok(),
+            ..self
+        }
+    }
+
+    /// The signed content: the value (or its digest when set) alone, or the JSON array `[value, dataset_hash]` when a dataset hash is set
+    pub fn signed_payload(&self) -> String {
+        let value = self.digest.as_ref().unwrap_or(&self.value);
+        match &self.dataset_hash {
+            Some(dataset_hash) => serde_json::json!([value, dataset_hash]).to_string(),
+            None => value.clone(),
+        }
+    }
+
+    /// Sign a digest of the value rather than the value itself, for large values
+    pub fn with_digest(self, with_digest: bool) -> Self {
+        Response {
+            digest: with_digest.then(|| sha256_hex(&self.value)),
+            ..self
+        }
+    }
+
+    /// Whether the digest, if any, is the one of the value
+    pub fn has_valid_digest(&self) -> bool {
+        self.digest.as_ref().is_none_or(|digest| *digest == sha256_hex(&self.value))
+    }
+
+    pub fn with_dataset_hash(self, dataset_hash: Option<String>) -> Self {
+        Response {
+            dataset_hash,
+            ..self
+        }
+    }
+
+    pub fn with_unique_columns(self, unique_columns: Option<Vec<String>>) -> Self {
+        Response {
+            unique_columns,
+            ..self
+        }
+    }
+
+    /// Replace the value, before signing it
+    pub fn with_value(self, value: String) -> Self {
         Response {
-            signature: Some(auth.sign(&value)),
             value,
+            ..self
         }
     }
 
@@ -30,25 +142,160 @@ impl Response {
     pub fn signature(&self) -> Option<&str> {
         self.signature.as_deref()
     }
+
+    pub fn key_id(&self) -> Option<&str> {
+        self.key_id.as_deref()
+    }
+
+    pub fn with_explanation(self, explanation: Option<String>) -> Self {
+        Response {
+            explanation,
+            ..self
+        }
+    }
+
+    pub fn with_suppression_threshold(self, suppression_threshold: Option<f64>) -> Self {
+        Response {
+            suppression_threshold,
+            ..self
+        }
+    }
+
+    /// Set the (epsilon, delta) consumed by the query
+    pub fn with_consumed_budget(self, consumed_budget: Option<(f64, f64)>) -> Self {
+        Response {
+            consumed_epsilon: consumed_budget.map(|(epsilon, _)| epsilon),
+            consumed_delta: consumed_budget.map(|(_, delta)| delta),
+            ..self
+        }
+    }
+
+    pub fn with_noise_scales(self, noise_scales: Option<BTreeMap<String, f64>>) -> Self {
+        Response {
+            noise_scales,
+            ..self
+        }
+    }
+
+    pub fn with_relation_fingerprint(self, relation_fingerprint: Option<String>) -> Self {
+        Response {
+            relation_fingerprint,
+            ..self
+        }
+    }
+
+    /// Set the warnings, if there are any
+    pub fn with_warnings(self, warnings: Vec<String>) -> Self {
+        Response {
+            warnings: (!warnings.is_empty()).then_some(warnings),
+            ..self
+        }
+    }
+
+    pub fn with_request_id(self, request_id: Option<String>) -> Self {
+        Response {
+            request_id,
+            ..self
+        }
+    }
+
+    pub fn with_route(self, route: Option<String>) -> Self {
+        Response {
+            route,
+            ..self
+        }
+    }
+
+    pub fn unique_columns(&self) -> Option<&[String]> {
+        self.unique_columns.as_deref()
+    }
+
+    pub fn dataset_hash(&self) -> Option<&str> {
+        self.dataset_hash.as_deref()
+    }
+
+    pub fn digest(&self) -> Option<&str> {
+        self.digest.as_deref()
+    }
+
+    pub fn explanation(&self) -> Option<&str> {
+        self.explanation.as_deref()
+    }
+
+    pub fn suppression_threshold(&self) -> Option<f64> {
+        self.suppression_threshold
+    }
+
+    pub fn consumed_budget(&self) -> Option<(f64, f64)> {
+        self.consumed_epsilon.zip(self.consumed_delta)
+    }
+
+    pub fn noise_scales(&self) -> Option<&BTreeMap<String, f64>> {
+        self.noise_scales.as_ref()
+    }
+
+    pub fn relation_fingerprint(&self) -> Option<&str> {
+        self.relation_fingerprint.as_deref()
+    }
+
+    pub fn warnings(&self) -> &[String] {
+        self.warnings.as_deref().unwrap_or_default()
+    }
+
+    pub fn request_id(&self) -> Option<&str> {
+        self.request_id.as_deref()
+    }
+
+    pub fn route(&self) -> Option<&str> {
+        self.route.as_deref()
+    }
 }
 
 // Errors need to be convertible to responses
 impl axum::response::IntoResponse for Response {
     fn into_response(self) -> axum::response::Response {
-        serde_json::to_string(&self).or_else(|err| Err(Error::from(err))).into_response()
+        let mut response = serde_json::to_string(&self).map_err(Error::from).into_response();
+        // Keep the response around for the middlewares
+        response.extensions_mut().insert(self);
+        response
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use serde_json;
 
     #[test]
     fn test_response() {
         let response = Response::new("Hello\nSarus !".to_string());
         let signed_response = Response::signed("Hello\nSarus !".to_string(), &auth::Authenticator::get("secret_key.pem").unwrap());
+        println!("{:?}", response);
         println!("{:?}", signed_response);
         println!("{}", signed_response.value());
+        assert_eq!(response.key_id(), None);
+        assert_eq!(signed_response.key_id(), Some(auth::Authenticator::get("secret_key.pem").unwrap().key_id().unwrap().as_str()));
+    }
+
+    #[test]
+    fn test_signed_dataset_hash() {
+        let auth = auth::Authenticator::get("secret_key.pem").unwrap();
+        let response = Response::new("SELECT 1".to_string()).with_dataset_hash(Some("abcd".to_string())).sign(&auth);
+        auth.verify(&response.signed_payload(), response.signature().unwrap()).expect("OK");
+        // The value alone is not what was signed
+        assert!(auth.verify(response.value(), response.signature().unwrap()).is_err());
+        let altered = response.clone().with_dataset_hash(Some("abce".to_string()));
+        assert!(auth.verify(&altered.signed_payload(), altered.signature().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_signed_digest() {
+        let auth = auth::Authenticator::get("secret_key.pem").unwrap();
+        let response = Response::new("SELECT 1".to_string()).with_digest(true).sign(&auth);
+        println!("{}", serde_json::to_string(&response).unwrap());
+        assert_eq!(response.signed_payload(), sha256_hex("SELECT 1"));
+        assert!(response.has_valid_digest());
+        auth.verify(&response.signed_payload(), response.signature().unwrap()).expect("OK");
+        let altered = Response { value: "SELECT 2".to_string(), ..response };
+        assert!(!altered.has_valid_digest());
     }
 }
\ No newline at end of file
@@ -0,0 +1,75 @@
+use std::{collections::BTreeMap, sync::{Arc, RwLock, atomic::{AtomicU64, Ordering}}};
+use serde::Serialize;
+
+/// Request counters of a route
+#[derive(Debug, Default)]
+pub struct Counters {
+    requests: AtomicU64,
+    successes: AtomicU64,
+    failures: AtomicU64,
+}
+
+/// A snapshot of the counters of a route
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CountersSnapshot {
+    requests: u64,
+    successes: u64,
+    failures: u64,
+}
+
+impl Counters {
+    pub fn snapshot(&self) -> CountersSnapshot {
+        CountersSnapshot {
+            requests: self.requests.load(Ordering::Relaxed),
+            successes: self.successes.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Request counters per route
+#[derive(Debug, Default)]
+pub struct Stats {
+    routes: RwLock<BTreeMap<String, Arc<Counters>>>,
+}
+
+impl Stats {
+    fn counters(&self, route: &str) -> Arc<Counters> {
+        if let Some(counters) = self.routes.read().unwrap().get(route) {
+            return counters.clone();
+        }
+        self.routes.write().unwrap().entry(route.to_string()).or_default().clone()
+    }
+
+    /// Count a request to a route and whether it succeeded
+    pub fn record(&self, route: &str, success: bool) {
+        let counters = self.counters(route);
+        counters.requests.fetch_add(1, Ordering::Relaxed);
+        if success {
+            counters.successes.fetch_add(1, Ordering::Relaxed);
+        } else {
+            counters.failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, CountersSnapshot> {
+        self.routes.read().unwrap().iter().map(|(route, counters)| (route.clone(), counters.snapshot())).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let stats = Stats::default();
+        stats.record("/dot", true);
+        stats.record("/dot", false);
+        stats.record("/verify", true);
+        let snapshot = stats.snapshot();
+        println!("{}", serde_json::to_string(&snapshot).unwrap());
+        assert_eq!(snapshot["/dot"], CountersSnapshot { requests: 2, successes: 1, failures: 1 });
+        assert_eq!(snapshot["/verify"], CountersSnapshot { requests: 1, successes: 1, failures: 0 });
+    }
+}